@@ -0,0 +1,156 @@
+//! Two plugins that share a single compiler session per crate.
+
+#![feature(rustc_private)]
+
+extern crate rustc_driver;
+extern crate rustc_interface;
+extern crate rustc_middle;
+
+use std::{borrow::Cow, env};
+
+use clap::Parser;
+use rustc_middle::ty::TyCtxt;
+use rustc_plugin::{
+  ComposablePlugin, CrateFilter, PluginSet, RustcPluginError, Utf8Path,
+};
+use serde::{Deserialize, Serialize};
+
+// Both plugins read their args from the same command line.
+#[derive(Parser)]
+struct Cli {
+  #[arg(long)]
+  prefix: Option<String>,
+}
+
+// The set is used by both the CLI and the driver binaries.
+pub fn plugins() -> PluginSet {
+  PluginSet::new(env!("CARGO_PKG_VERSION"), "plugin-set-driver")
+    .with_filter(|| CrateFilter::AllCrates)
+    .with_plugin(CountItems)
+    .with_plugin(ListFunctions)
+}
+
+// Counts the items in each crate.
+pub struct CountItems;
+
+pub struct CountItemsCallbacks {
+  num_items: usize,
+}
+
+impl rustc_driver::Callbacks for CountItemsCallbacks {
+  fn after_analysis(
+    &mut self,
+    _compiler: &rustc_interface::interface::Compiler,
+    tcx: TyCtxt<'_>,
+  ) -> rustc_driver::Compilation {
+    self.num_items = tcx.hir_free_items().count();
+    rustc_driver::Compilation::Continue
+  }
+}
+
+impl ComposablePlugin for CountItems {
+  type Args = ();
+  type Output = usize;
+  type Callbacks = CountItemsCallbacks;
+
+  fn name(&self) -> Cow<'static, str> {
+    "count-items".into()
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> Self::Args {}
+
+  fn callbacks(&self, _args: Self::Args) -> Self::Callbacks {
+    CountItemsCallbacks { num_items: 0 }
+  }
+
+  fn output(&self, callbacks: Self::Callbacks) -> Self::Output {
+    callbacks.num_items
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    let total = outputs.into_iter().sum::<usize>();
+    println!("Found {total} items in total");
+    Ok(())
+  }
+}
+
+// Lists the functions in each crate whose names start with a prefix.
+pub struct ListFunctions;
+
+#[derive(Serialize, Deserialize)]
+pub struct ListFunctionsArgs {
+  prefix: String,
+}
+
+pub struct ListFunctionsCallbacks {
+  args: ListFunctionsArgs,
+  functions: Vec<String>,
+}
+
+impl rustc_driver::Callbacks for ListFunctionsCallbacks {
+  fn after_analysis(
+    &mut self,
+    _compiler: &rustc_interface::interface::Compiler,
+    tcx: TyCtxt<'_>,
+  ) -> rustc_driver::Compilation {
+    for def_id in tcx.hir_body_owners() {
+      if !tcx.def_kind(def_id).is_fn_like() {
+        continue;
+      }
+      // Closures don't have names.
+      let Some(name) = tcx.opt_item_name(def_id.to_def_id()) else {
+        continue;
+      };
+      if name.as_str().starts_with(&self.args.prefix) {
+        self.functions.push(name.to_string());
+      }
+    }
+    rustc_driver::Compilation::Continue
+  }
+}
+
+impl ComposablePlugin for ListFunctions {
+  type Args = ListFunctionsArgs;
+  type Output = Vec<String>;
+  type Callbacks = ListFunctionsCallbacks;
+
+  fn name(&self) -> Cow<'static, str> {
+    "list-functions".into()
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> Self::Args {
+    let cli = Cli::parse_from(env::args().skip(1));
+    ListFunctionsArgs {
+      prefix: cli.prefix.unwrap_or_default(),
+    }
+  }
+
+  fn callbacks(&self, args: Self::Args) -> Self::Callbacks {
+    ListFunctionsCallbacks {
+      args,
+      functions: Vec::new(),
+    }
+  }
+
+  fn output(&self, callbacks: Self::Callbacks) -> Self::Output {
+    callbacks.functions
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    let functions = outputs.concat();
+    println!(
+      "Functions starting with \"{}\": {}",
+      args.prefix,
+      functions.join(", ")
+    );
+    Ok(())
+  }
+}
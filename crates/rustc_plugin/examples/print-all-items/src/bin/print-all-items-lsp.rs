@@ -0,0 +1,6 @@
+#![feature(rustc_private)]
+
+fn main() -> std::process::ExitCode {
+  env_logger::init();
+  rustc_plugin::lsp_main(print_all_items::PrintAllItemsPlugin)
+}
@@ -8,16 +8,23 @@ extern crate rustc_interface;
 extern crate rustc_middle;
 extern crate rustc_session;
 
-use std::{borrow::Cow, env, process::Command};
+use std::{borrow::Cow, env, path::Path, process::Command};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, parser::ValueSource};
 use rustc_hir::{
   Item,
   intravisit::{self, Visitor},
 };
 use rustc_middle::ty::TyCtxt;
-use rustc_plugin::{CrateFilter, RustcPlugin, RustcPluginArgs, Utf8Path};
+use rustc_plugin::{
+  CrateFilter, LspPlugin, RustcPlugin, RustcPluginArgs, RustcPluginError, Utf8Path,
+  lsp_types::{
+    Hover, HoverContents, HoverProviderCapability, MarkedString, ServerCapabilities,
+    request::{HoverRequest, Request},
+  },
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 // This struct is the plugin provided to the rustc_plugin framework,
 // and it must be exported for use by the CLI/driver binaries.
@@ -37,6 +44,9 @@ pub struct PrintAllItemsPluginArgs {
 impl RustcPlugin for PrintAllItemsPlugin {
   type Args = PrintAllItemsPluginArgs;
 
+  // Each driver invocation sends back the number of items it found.
+  type Output = usize;
+
   fn version(&self) -> Cow<'static, str> {
     env!("CARGO_PKG_VERSION").into()
   }
@@ -45,13 +55,35 @@ impl RustcPlugin for PrintAllItemsPlugin {
     "print-all-items-driver".into()
   }
 
+  // Read the plugin's configuration from `print-all-items-driver.toml` and from
+  // `[package.metadata.print-all-items-driver]` in the Cargo.toml of each package.
+  fn config_name(&self) -> Option<Cow<'static, str>> {
+    Some(self.driver_name())
+  }
+
   // In the CLI, we ask Clap to parse arguments and also specify a CrateFilter.
   // If one of the CLI arguments was a specific file to analyze, then you
   // could provide a different filter.
   fn args(&self, _target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
-    let args = PrintAllItemsPluginArgs::parse_from(env::args().skip(1));
+    let matches =
+      PrintAllItemsPluginArgs::command().get_matches_from(env::args().skip(1));
+    let args =
+      PrintAllItemsPluginArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // Only the arguments passed by the user override the plugin's configuration, e.g.
+    // `print-all-items-driver.toml`, rather than Clap's defaults.
+    let explicit_fields = matches
+      .ids()
+      .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+      .map(ToString::to_string)
+      .collect();
+
     let filter = CrateFilter::AllCrates;
-    RustcPluginArgs { args, filter }
+    RustcPluginArgs {
+      args,
+      filter,
+      explicit_fields: Some(explicit_fields),
+    }
   }
 
   // Pass Cargo arguments (like --feature) from the top-level CLI to Cargo.
@@ -65,17 +97,67 @@ impl RustcPlugin for PrintAllItemsPlugin {
     self,
     compiler_args: Vec<String>,
     plugin_args: Self::Args,
-  ) -> rustc_interface::interface::Result<()> {
+  ) -> rustc_interface::interface::Result<Self::Output> {
     let mut callbacks = PrintAllItemsCallbacks {
       args: Some(plugin_args),
+      num_items: 0,
     };
     rustc_driver::run_compiler(&compiler_args, &mut callbacks);
+    Ok(callbacks.num_items)
+  }
+
+  // Back in the CLI, we receive the outputs from every crate.
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    let total = outputs.into_iter().sum::<usize>();
+    println!("Found {total} items in total");
     Ok(())
   }
 }
 
+// The language server answers hover requests with the number of items in the crates
+// containing the hovered file. The items themselves are printed to its stderr.
+impl LspPlugin for PrintAllItemsPlugin {
+  fn capabilities(&self) -> ServerCapabilities {
+    ServerCapabilities {
+      hover_provider: Some(HoverProviderCapability::Simple(true)),
+      ..Default::default()
+    }
+  }
+
+  fn request_args(
+    &self,
+    method: &str,
+    _file: &Path,
+    _params: &Value,
+  ) -> Option<Self::Args> {
+    (method == HoverRequest::METHOD)
+      .then(|| PrintAllItemsPluginArgs::parse_from(["print-all-items"]))
+  }
+
+  fn response(
+    &self,
+    _method: &str,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Value {
+    let total = outputs.into_iter().sum::<usize>();
+    let hover = Hover {
+      contents: HoverContents::Scalar(MarkedString::String(format!(
+        "Found {total} items"
+      ))),
+      range: None,
+    };
+    serde_json::to_value(hover).unwrap()
+  }
+}
+
 struct PrintAllItemsCallbacks {
   args: Option<PrintAllItemsPluginArgs>,
+  num_items: usize,
 }
 
 impl rustc_driver::Callbacks for PrintAllItemsCallbacks {
@@ -88,7 +170,7 @@ impl rustc_driver::Callbacks for PrintAllItemsCallbacks {
     tcx: TyCtxt<'_>,
   ) -> rustc_driver::Compilation {
     // We call our top-level function with access to the type context `tcx` and the CLI arguments.
-    print_all_items(tcx, self.args.take().unwrap());
+    self.num_items = print_all_items(tcx, self.args.take().unwrap());
 
     // Note that you should generally allow compilation to continue. If
     // your plugin is being invoked on a dependency, then you need to ensure
@@ -101,13 +183,20 @@ impl rustc_driver::Callbacks for PrintAllItemsCallbacks {
 // The core of our analysis. Right now it just prints out a description of each item.
 // I recommend reading the Rustc Development Guide to better understand which compiler APIs
 // are relevant to whatever task you have.
-fn print_all_items(tcx: TyCtxt, args: PrintAllItemsPluginArgs) {
-  tcx.hir_visit_all_item_likes_in_crate(&mut PrintVisitor { args, tcx });
+fn print_all_items(tcx: TyCtxt, args: PrintAllItemsPluginArgs) -> usize {
+  let mut visitor = PrintVisitor {
+    args,
+    tcx,
+    num_items: 0,
+  };
+  tcx.hir_visit_all_item_likes_in_crate(&mut visitor);
+  visitor.num_items
 }
 
 struct PrintVisitor<'tcx> {
   args: PrintAllItemsPluginArgs,
   tcx: TyCtxt<'tcx>,
+  num_items: usize,
 }
 
 impl<'tcx> Visitor<'tcx> for PrintVisitor<'tcx> {
@@ -127,6 +216,7 @@ impl<'tcx> Visitor<'tcx> for PrintVisitor<'tcx> {
       msg = msg.to_uppercase();
     }
     println!("{msg}");
+    self.num_items += 1;
 
     intravisit::walk_item(self, item)
   }
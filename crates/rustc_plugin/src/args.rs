@@ -0,0 +1,139 @@
+//! Passing the plugin's args from the CLI to each invocation of the driver.
+//!
+//! Small JSON args are passed directly in the [`PLUGIN_ARGS`] environment variable.
+//! Otherwise, the args are written to a file in the target directory whose path is passed
+//! in [`PLUGIN_ARGS_FILE`], since Linux limits the size of a single environment variable
+//! to 128 KiB. Args are moved to a file well before reaching that limit, as explained
+//! for [`MAX_ENV_ARGS_LEN`].
+//!
+//! Packages with their own configuration (see [`crate::config`]) get their own args, which
+//! are passed alongside the args for every other package.
+
+use std::{collections::BTreeMap, env, fs, path::PathBuf, process::Command};
+
+use cargo_metadata::camino::Utf8Path;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+  ArgsFormat, RustcPluginError,
+  plugin::{PLUGIN_ARGS, PLUGIN_ARGS_FILE},
+};
+
+/// Serialized args larger than this are always passed through a file.
+///
+/// This is well below the 128 KiB limit on a single environment variable, since every
+/// variable and argument of the driver also counts towards the total limit of `ARG_MAX`,
+/// which Cargo's own variables and a large workspace's compiler args can already use
+/// a good part of.
+const MAX_ENV_ARGS_LEN: usize = 32 * 1024;
+
+impl ArgsFormat {
+  fn extension(self) -> &'static str {
+    match self {
+      ArgsFormat::Json => "json",
+      #[cfg(feature = "bincode")]
+      ArgsFormat::Bincode => "bin",
+      #[cfg(feature = "msgpack")]
+      ArgsFormat::MessagePack => "msgpack",
+    }
+  }
+
+  pub(crate) fn serialize<T: Serialize>(self, value: &T) -> Vec<u8> {
+    match self {
+      ArgsFormat::Json => serde_json::to_vec(value).unwrap(),
+      #[cfg(feature = "bincode")]
+      ArgsFormat::Bincode => bincode::serialize(value).unwrap(),
+      #[cfg(feature = "msgpack")]
+      ArgsFormat::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+    }
+  }
+
+  fn deserialize<T: DeserializeOwned>(
+    self,
+    bytes: &[u8],
+  ) -> Result<T, Box<dyn std::error::Error>> {
+    Ok(match self {
+      ArgsFormat::Json => serde_json::from_slice(bytes)?,
+      #[cfg(feature = "bincode")]
+      ArgsFormat::Bincode => bincode::deserialize(bytes)?,
+      #[cfg(feature = "msgpack")]
+      ArgsFormat::MessagePack => rmp_serde::from_slice(bytes)?,
+    })
+  }
+}
+
+/// The args for every package in the workspace.
+#[derive(Serialize, Deserialize)]
+pub struct LayeredArgs<T> {
+  /// The args for packages without their own configuration.
+  pub args: T,
+
+  /// The args for each package with its own configuration, by package name.
+  pub packages: BTreeMap<String, T>,
+}
+
+impl<T> LayeredArgs<T> {
+  fn select(mut self, package: Option<&str>) -> T {
+    package
+      .and_then(|package| self.packages.remove(package))
+      .unwrap_or(self.args)
+  }
+}
+
+/// Passes `args` to the driver through `cmd`.
+///
+/// Returns the path of the file containing the args, if one was needed, so the CLI
+/// can remove it once Cargo has finished.
+pub fn pass_args<T: Serialize>(
+  cmd: &mut Command,
+  args: &LayeredArgs<T>,
+  format: ArgsFormat,
+  target_dir: &Utf8Path,
+) -> Result<Option<PathBuf>, RustcPluginError> {
+  let bytes = format.serialize(args);
+
+  if format == ArgsFormat::Json && bytes.len() <= MAX_ENV_ARGS_LEN {
+    let args_str = String::from_utf8(bytes).unwrap();
+    log::debug!("{PLUGIN_ARGS}={args_str}");
+    cmd.env(PLUGIN_ARGS, args_str);
+    return Ok(None);
+  }
+
+  // Each invocation of the CLI gets its own file, like its output directory.
+  let dir = target_dir.join("plugin-args");
+  let path = dir
+    .join(format!("{}.{}", std::process::id(), format.extension()))
+    .into_std_path_buf();
+  fs::create_dir_all(&dir)
+    .and_then(|()| fs::write(&path, &bytes))
+    .map_err(|e| {
+      RustcPluginError::io(
+        format!("failed to write plugin args to {}", path.display()),
+        e,
+      )
+    })?;
+
+  log::debug!("{PLUGIN_ARGS_FILE}={}", path.display());
+  cmd.env(PLUGIN_ARGS_FILE, &path);
+  Ok(Some(path))
+}
+
+/// Reads the args passed to the driver by [`pass_args`] for the package being compiled.
+///
+/// Also returns the serialized args, which identify the args for the output cache.
+pub fn read_args<T: Serialize + DeserializeOwned>(format: ArgsFormat) -> (T, Vec<u8>) {
+  let bytes = match env::var(PLUGIN_ARGS_FILE) {
+    Ok(path) => fs::read(&path)
+      .unwrap_or_else(|e| panic!("failed to read plugin args from {path}: {e}")),
+    Err(_) => env::var(PLUGIN_ARGS).unwrap().into_bytes(),
+  };
+  let args = format
+    .deserialize::<LayeredArgs<T>>(&bytes)
+    .unwrap_or_else(|e| panic!("failed to deserialize plugin args: {e}"))
+    .select(env::var("CARGO_PKG_NAME").ok().as_deref());
+
+  // Re-serialize the selected args so that changing one package's configuration doesn't
+  // invalidate the cached outputs of every other package.
+  let bytes = format.serialize(&args);
+  (args, bytes)
+}
@@ -0,0 +1,257 @@
+//! Caching of plugin outputs across runs of the CLI.
+//!
+//! Each compiler unit's output is stored in a file keyed on the unit's crate name, root
+//! source file, test flag and features. Entries are kept in a directory per toolchain,
+//! and every hash is computed with [`StableSipHasher128`] so that entries stay valid
+//! across builds of the plugin.
+//!
+//! Outputs are replayed in two ways:
+//! - The CLI gives the driver a [`run_id`] that only changes with the plugin version, its
+//!   args and the crate selection, so Cargo's own freshness check skips crates that
+//!   haven't changed. The CLI
+//!   then replays the stored output of every unit that Cargo reports as fresh, without
+//!   invoking the driver.
+//! - If Cargo does invoke the driver, e.g. because a file's modification time changed,
+//!   then the driver replays the output if the compiler args are unchanged and if every
+//!   source file read by rustc, as well as every upstream crate passed with `--extern`,
+//!   has the same contents. The crate is still compiled so that Cargo gets its artifacts.
+//!
+//! [`StableSipHasher128`]: rustc_stable_hash::StableSipHasher128
+
+use std::{
+  fs,
+  hash::Hasher,
+  path::{Path, PathBuf},
+};
+
+use cargo_metadata::{Artifact, camino::Utf8Path};
+use rustc_stable_hash::{FromStableHash, SipHasher128Hash, StableSipHasher128};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{dep_info, driver::arg_value};
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+  run_id: String,
+  fingerprint: String,
+  files: Vec<(PathBuf, String)>,
+  output: T,
+}
+
+/// A 128-bit hash from [`StableSipHasher128`], formatted as hex.
+struct StableHash(String);
+
+impl FromStableHash for StableHash {
+  type Hash = SipHasher128Hash;
+
+  fn from(SipHasher128Hash([lo, hi]): SipHasher128Hash) -> Self {
+    StableHash(format!("{hi:016x}{lo:016x}"))
+  }
+}
+
+fn stable_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+  let mut hasher = StableSipHasher128::new();
+  for part in parts {
+    // Prefixing each part with its length keeps e.g. ["ab", "c"] and ["a", "bc"] apart.
+    hasher.write_usize(part.len());
+    hasher.write(part);
+  }
+  hasher.finish::<StableHash>().0
+}
+
+/// Returns the directory containing the cached outputs for the current toolchain.
+pub fn cache_dir(target_dir: &Utf8Path) -> PathBuf {
+  target_dir
+    .join("plugin-cache")
+    .join(crate::CHANNEL)
+    .into_std_path_buf()
+}
+
+/// Returns an identifier for the plugin's version and args, and for the crates it runs on,
+/// which Cargo tracks for every crate the plugin runs on.
+///
+/// The crate selection is included so that a crate which was last compiled without the
+/// plugin can't have an older output replayed.
+pub fn run_id(version: &str, plugin_args: &[u8], crate_selection: &[u8]) -> String {
+  stable_hash([
+    crate::CHANNEL.as_bytes(),
+    version.as_bytes(),
+    plugin_args,
+    crate_selection,
+  ])
+}
+
+/// Returns the path of the cached output for a compiler unit.
+fn unit_path(
+  cache_dir: &Path,
+  crate_name: &str,
+  src_path: &Path,
+  test: bool,
+  features: &[String],
+) -> PathBuf {
+  let src_path = src_path.canonicalize().unwrap_or(src_path.to_path_buf());
+  let mut features = features.iter().map(String::as_bytes).collect::<Vec<_>>();
+  features.sort_unstable();
+  let key = stable_hash(
+    [src_path.as_os_str().as_encoded_bytes(), &[u8::from(test)]]
+      .into_iter()
+      .chain(features),
+  );
+  cache_dir.join(format!("{crate_name}-{}.json", &key[.. 16]))
+}
+
+/// Handle to the cached output for one crate being compiled.
+pub struct OutputCache {
+  path: PathBuf,
+  run_id: String,
+  fingerprint: String,
+}
+
+impl OutputCache {
+  /// Creates a handle for the crate being compiled with `compiler_args`.
+  pub fn new(
+    cache_dir: &Path,
+    run_id: String,
+    version: &str,
+    plugin_args: &[u8],
+    compiler_args: &[String],
+  ) -> Self {
+    let fingerprint = stable_hash(
+      [crate::CHANNEL.as_bytes(), version.as_bytes(), plugin_args]
+        .into_iter()
+        .chain(compiler_args.iter().map(String::as_bytes)),
+    );
+
+    let crate_name =
+      arg_value(compiler_args, "--crate-name", |_| true).unwrap_or("unknown");
+    let src_path = compiler_args
+      .iter()
+      .find(|arg| arg.ends_with(".rs"))
+      .map_or(Path::new(""), Path::new);
+    let test = compiler_args.iter().any(|arg| arg == "--test");
+    let features = compiler_args
+      .windows(2)
+      .filter(|args| args[0] == "--cfg")
+      .filter_map(|args| {
+        let feature = args[1].strip_prefix("feature=\"")?.strip_suffix('"')?;
+        Some(feature.to_string())
+      })
+      .collect::<Vec<_>>();
+    let path = unit_path(cache_dir, crate_name, src_path, test, &features);
+
+    OutputCache {
+      path,
+      run_id,
+      fingerprint,
+    }
+  }
+
+  /// Returns the cached output if nothing relevant has changed since it was stored.
+  pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+    let contents = fs::read_to_string(&self.path).ok()?;
+    let entry: CacheEntry<T> = serde_json::from_str(&contents).ok()?;
+    if entry.fingerprint != self.fingerprint {
+      log::debug!(
+        "Cache miss for {}: fingerprint changed",
+        self.path.display()
+      );
+      return None;
+    }
+
+    for (file, hash) in &entry.files {
+      if hash_file(file).as_ref() != Some(hash) {
+        log::debug!(
+          "Cache miss for {}: {} changed",
+          self.path.display(),
+          file.display()
+        );
+        return None;
+      }
+    }
+
+    log::debug!("Cache hit for {}", self.path.display());
+    Some(entry.output)
+  }
+
+  /// Stores `output`, keyed on the source files listed in the crate's dep-info and on
+  /// the crate's dependencies.
+  pub fn store<T: Serialize>(&self, output: &T, compiler_args: &[String]) {
+    let Some(files) = dep_info::dep_info_path(compiler_args)
+      .and_then(|path| dep_info::read_dep_info(&path).ok())
+    else {
+      log::warn!("Not caching plugin output: could not read dep-info");
+      return;
+    };
+
+    // Cargo's dep-info only lists local sources, and the file names of dependencies don't
+    // change with their contents, so each dependency is hashed too.
+    let files = files
+      .into_iter()
+      .chain(extern_paths(compiler_args))
+      .filter_map(|file| {
+        let hash = hash_file(&file)?;
+        Some((file, hash))
+      })
+      .collect();
+    let entry = CacheEntry {
+      run_id: self.run_id.clone(),
+      fingerprint: self.fingerprint.clone(),
+      files,
+      output,
+    };
+
+    let result = fs::create_dir_all(self.path.parent().unwrap())
+      .and_then(|()| fs::write(&self.path, serde_json::to_string(&entry).unwrap()));
+    if let Err(e) = result {
+      log::warn!("Failed to write plugin cache {}: {e}", self.path.display());
+    }
+  }
+}
+
+/// Returns the cached output of a unit that Cargo didn't recompile, if it was stored by
+/// a driver that had the same `run_id`.
+///
+/// The output is returned as JSON, since the CLI only needs to pass it along.
+pub fn load_fresh(cache_dir: &Path, run_id: &str, artifact: &Artifact) -> Option<Value> {
+  let crate_name = artifact.target.name.replace('-', "_");
+  let path = unit_path(
+    cache_dir,
+    &crate_name,
+    artifact.target.src_path.as_std_path(),
+    artifact.profile.test,
+    &artifact.features,
+  );
+  let contents = fs::read_to_string(&path).ok()?;
+  let entry: CacheEntry<Value> = serde_json::from_str(&contents).ok()?;
+  if entry.run_id != run_id {
+    log::debug!("Not replaying {}: run ID changed", path.display());
+    return None;
+  }
+
+  log::debug!("Replaying fresh unit {}", path.display());
+  Some(entry.output)
+}
+
+/// Returns the paths of the crates passed with `--extern name=path`.
+fn extern_paths(args: &[String]) -> Vec<PathBuf> {
+  let mut paths = Vec::new();
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let value = match arg.strip_prefix("--extern") {
+      Some("") => args.next().map(String::as_str),
+      Some(value) => value.strip_prefix('='),
+      None => None,
+    };
+    // Crates from the sysroot, e.g. `--extern proc_macro`, have no path.
+    if let Some((_, path)) = value.and_then(|value| value.split_once('=')) {
+      paths.push(PathBuf::from(path));
+    }
+  }
+  paths
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+  let contents = fs::read(path).ok()?;
+  Some(stable_hash([contents.as_slice()]))
+}
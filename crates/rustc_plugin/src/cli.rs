@@ -1,51 +1,188 @@
 use std::{
+  collections::BTreeMap,
   env, fs,
-  path::PathBuf,
-  process::{Command, ExitCode, Stdio},
+  io::{self, BufReader, Write},
+  path::{Path, PathBuf},
+  process::{Command, ExitCode, ExitStatus, Stdio},
+  time::{SystemTime, UNIX_EPOCH},
 };
 
-use cargo_metadata::camino::Utf8Path;
+use cargo_metadata::{Message, Metadata, Package, camino::Utf8Path};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use super::plugin::{PLUGIN_ARGS, RustcPlugin};
-use crate::CrateFilter;
+use super::plugin::{PLUGIN_OUTPUT_DIR, RustcPlugin};
+use crate::{
+  CargoSubcommand, CrateFilter, RustcPluginArgs, RustcPluginError, TargetKind,
+  args::{self, LayeredArgs},
+  cache, config, discover,
+};
 
 pub const RUN_ON_ALL_CRATES: &str = "RUSTC_PLUGIN_ALL_TARGETS";
-pub const SPECIFIC_CRATE: &str = "SPECIFIC_CRATE";
-pub const SPECIFIC_TARGET: &str = "SPECIFIC_TARGET";
+pub const SPECIFIC_PACKAGES: &str = "SPECIFIC_PACKAGES";
+pub const SPECIFIC_CRATES: &str = "SPECIFIC_CRATES";
 pub const CARGO_VERBOSE: &str = "CARGO_VERBOSE";
+pub const RUN_ID: &str = "RUSTC_PLUGIN_RUN_ID";
+pub const CACHE_DIR: &str = "RUSTC_PLUGIN_CACHE_DIR";
+
+/// A package that the plugin should run on, as listed in [`SPECIFIC_PACKAGES`].
+///
+/// The version is needed since the dependency graph may contain several versions of
+/// a package.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackageSpec {
+  pub name: String,
+  pub version: String,
+}
+
+/// A crate that the plugin should run on, as listed in [`SPECIFIC_CRATES`].
+///
+/// The crate name alone is ambiguous, since e.g. a package's lib and bin usually have
+/// the same name, and both have a unit test harness.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrateSpec {
+  pub crate_name: String,
+
+  /// The canonicalized root source file of the crate.
+  pub src_path: PathBuf,
+
+  /// True if the crate is compiled as a test harness.
+  pub test: bool,
+}
+
+impl CrateSpec {
+  fn new(name: &str, src_path: &Path, test: bool) -> Self {
+    CrateSpec {
+      crate_name: name.replace('-', "_"),
+      src_path: src_path
+        .canonicalize()
+        .unwrap_or_else(|_| src_path.to_path_buf()),
+      test,
+    }
+  }
+}
 
 /// The top-level function that should be called in your user-facing binary.
+///
+/// Errors are printed to stderr, and reported through the exit code as described in
+/// [`RustcPluginError::exit_code`]. Use [`try_cli_main`] to handle errors yourself.
 pub fn cli_main<T: RustcPlugin>(plugin: T) -> ExitCode {
+  match try_cli_main(plugin) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      // Cargo has already printed its own diagnostics.
+      if !matches!(e, RustcPluginError::Cargo { .. }) {
+        eprintln!("error: {e}");
+      }
+      e.exit_code()
+    }
+  }
+}
+
+/// Fallible version of [`cli_main`].
+///
+/// Note that [`RustcPlugin::finalize`] is still called if Cargo fails, in which case
+/// this function returns [`RustcPluginError::Cargo`] afterwards, even if `finalize`
+/// returned an error too.
+pub fn try_cli_main<T: RustcPlugin>(plugin: T) -> Result<(), RustcPluginError> {
   if env::args().any(|arg| arg == "-V") {
     println!("{}", plugin.version());
-    return ExitCode::SUCCESS;
+    return Ok(());
   }
 
-  let metadata = cargo_metadata::MetadataCommand::new()
-    .no_deps()
-    .other_options(["--all-features".to_string(), "--offline".to_string()])
-    .exec()
-    .unwrap();
+  let run = run_cargo(&plugin, |target_dir| plugin.args(target_dir), io::stdout())?;
+  let finalized = plugin.finalize(run.outputs, &run.args);
+
+  if run.status.success() {
+    finalized
+  } else {
+    Err(RustcPluginError::Cargo {
+      code: run.status.code(),
+    })
+  }
+}
+
+/// The result of running Cargo with a plugin.
+pub(crate) struct CargoRun<T: RustcPlugin> {
+  /// The args of the workspace, after applying its configuration.
+  pub args: T::Args,
+  pub outputs: Vec<T::Output>,
+  pub status: ExitStatus,
+}
+
+/// Runs Cargo on the workspace in the current directory with the driver of `plugin`,
+/// and collects the outputs of each crate.
+///
+/// `get_args` is called with the plugin's target directory. Cargo's stdin is closed,
+/// its stdout is forwarded to `stdout`, and its stderr is inherited.
+pub(crate) fn run_cargo<T: RustcPlugin>(
+  plugin: &T,
+  get_args: impl FnOnce(&Utf8Path) -> RustcPluginArgs<T::Args>,
+  mut stdout: impl Write,
+) -> Result<CargoRun<T>, RustcPluginError> {
+  let mut metadata = load_metadata(false)?;
   let plugin_subdir = format!("plugin-{}", crate::CHANNEL);
   let target_dir = metadata.target_directory.join(plugin_subdir);
 
-  let args = plugin.args(&target_dir);
+  let RustcPluginArgs {
+    args,
+    filter,
+    explicit_fields,
+  } = get_args(&target_dir);
+
+  // Filters that may select dependencies need the entire dependency graph, which is
+  // only loaded when necessary since it is much slower to compute.
+  let search_deps = match &filter {
+    CrateFilter::Packages(patterns) => !patterns.iter().all(|pattern| {
+      workspace_members(&metadata)
+        .iter()
+        .any(|pkg| glob_match(pattern.as_bytes(), pkg.name.as_bytes()))
+    }),
+    CrateFilter::Predicate(_) => true,
+    _ => false,
+  };
+  if search_deps {
+    metadata = load_metadata(true)?;
+  }
+  let workspace_members = workspace_members(&metadata);
+
+  let args = match plugin.config_name() {
+    Some(name) => config::apply_config(
+      &name,
+      &metadata,
+      &workspace_members,
+      args,
+      explicit_fields.as_deref(),
+    )?,
+    None => LayeredArgs {
+      args,
+      packages: BTreeMap::new(),
+    },
+  };
 
+  // Nothing that Cargo runs should read the CLI's stdin, which e.g. carries the messages
+  // of the language server.
   let mut cmd = Command::new("cargo");
-  cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+  cmd
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::inherit());
 
   let mut path = env::current_exe()
-    .expect("current executable path invalid")
+    .map_err(|e| RustcPluginError::io("failed to find the current executable", e))?
     .with_file_name(plugin.driver_name().as_ref());
 
   if cfg!(windows) {
     path.set_extension("exe");
   }
 
+  let profile = plugin.cargo_profile(&args.args);
   cmd
-    .env("RUSTC_WORKSPACE_WRAPPER", path)
-    .args(["check", "--target-dir"])
+    .args(plugin.cargo_subcommand(&args.args).args())
+    .arg("--target-dir")
     .arg(&target_dir);
+  if let Some(profile) = &profile {
+    cmd.args(["--profile", profile]);
+  }
 
   if env::var(CARGO_VERBOSE).is_ok() {
     cmd.arg("-vv");
@@ -53,37 +190,108 @@ pub fn cli_main<T: RustcPlugin>(plugin: T) -> ExitCode {
     cmd.arg("-q");
   }
 
-  let workspace_members = metadata
-    .workspace_members
-    .iter()
-    .map(|pkg_id| {
-      metadata
-        .packages
-        .iter()
-        .find(|pkg| &pkg.id == pkg_id)
-        .unwrap()
-    })
-    .collect::<Vec<_>>();
+  // Cargo only uses RUSTC_WORKSPACE_WRAPPER for workspace members, so running the plugin
+  // on a dependency requires wrapping every invocation of rustc.
+  let mut wrap_dependencies = false;
 
-  match args.filter {
+  match filter {
     CrateFilter::CrateContainingFile(file_path) => {
-      only_run_on_file(&mut cmd, file_path, &workspace_members, &target_dir);
+      only_run_on_file(
+        &mut cmd,
+        plugin,
+        &args.args,
+        file_path,
+        &metadata.workspace_root,
+        &workspace_members,
+        &target_dir,
+      )?;
     }
-    CrateFilter::AllCrates | CrateFilter::OnlyWorkspace => {
+    CrateFilter::AllCrates => {
       cmd.arg("--all");
-      match args.filter {
-        CrateFilter::AllCrates => {
-          cmd.env(RUN_ON_ALL_CRATES, "");
-        }
-        CrateFilter::OnlyWorkspace => {}
-        CrateFilter::CrateContainingFile(_) => unreachable!(),
-      }
+      cmd.env(RUN_ON_ALL_CRATES, "");
+    }
+    CrateFilter::OnlyWorkspace => {
+      cmd.arg("--all");
+    }
+    CrateFilter::Packages(patterns) => {
+      let packages = find_packages(&metadata, &workspace_members, search_deps, |pkg| {
+        patterns
+          .iter()
+          .any(|pattern| glob_match(pattern.as_bytes(), pkg.name.as_bytes()))
+      });
+      wrap_dependencies = only_run_on_packages(&mut cmd, &packages, &workspace_members)?;
+    }
+    CrateFilter::Predicate(predicate) => {
+      let packages = find_packages(&metadata, &workspace_members, true, predicate);
+      wrap_dependencies = only_run_on_packages(&mut cmd, &packages, &workspace_members)?;
+    }
+    CrateFilter::TargetKinds(kinds) => {
+      only_run_on_target_kinds(
+        &mut cmd,
+        &kinds,
+        plugin.cargo_subcommand(&args.args),
+        &workspace_members,
+      );
     }
   }
 
-  let args_str = serde_json::to_string(&args.args).unwrap();
-  log::debug!("{PLUGIN_ARGS}={args_str}");
-  cmd.env(PLUGIN_ARGS, args_str);
+  let wrapper = if wrap_dependencies {
+    "RUSTC_WRAPPER"
+  } else {
+    "RUSTC_WORKSPACE_WRAPPER"
+  };
+  cmd.env(wrapper, path);
+
+  let args_file = args::pass_args(&mut cmd, &args, plugin.args_format(), &target_dir)?;
+
+  // Each invocation of the CLI gets its own output directory so that concurrent
+  // runs on the same workspace don't read each other's outputs.
+  let output_dir = target_dir
+    .join("plugin-output")
+    .join(std::process::id().to_string());
+  let _ = fs::remove_dir_all(&output_dir);
+  fs::create_dir_all(&output_dir).map_err(|e| {
+    RustcPluginError::io(format!("failed to create output directory {output_dir}"), e)
+  })?;
+  cmd.env(PLUGIN_OUTPUT_DIR, &output_dir);
+
+  // The driver makes Cargo track this variable for every crate that the plugin runs on.
+  // Without caching, giving it a fresh value ensures that Cargo always invokes the driver
+  // on those crates. With caching, it only changes with the plugin and its args, so Cargo
+  // skips unchanged crates and their outputs are replayed from the cache instead.
+  let cache_dir = plugin
+    .cache_outputs()
+    .then(|| cache::cache_dir(&target_dir));
+  let run_id = if cache_dir.is_some() {
+    let crate_selection = cmd
+      .get_envs()
+      .filter(|(var, _)| {
+        [RUN_ON_ALL_CRATES, SPECIFIC_PACKAGES, SPECIFIC_CRATES]
+          .iter()
+          .any(|selection| var == selection)
+      })
+      .map(|(var, value)| format!("{var:?}={value:?}"))
+      .collect::<Vec<_>>();
+    cache::run_id(
+      &plugin.version(),
+      &plugin.args_format().serialize(&args),
+      crate_selection.join("\n").as_bytes(),
+    )
+  } else {
+    let time = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    format!("{time}-{}", std::process::id())
+  };
+  cmd.env(RUN_ID, &run_id);
+
+  // Cargo only reports which crates were fresh through its JSON messages, while
+  // diagnostics are still rendered to stderr.
+  if let Some(cache_dir) = &cache_dir {
+    cmd.env(CACHE_DIR, cache_dir);
+    cmd.arg("--message-format=json-render-diagnostics");
+  }
 
   // HACK: if running on the rustc codebase, this env var needs to exist
   // for the code to compile
@@ -93,162 +301,279 @@ pub fn cli_main<T: RustcPlugin>(plugin: T) -> ExitCode {
 
   plugin.modify_cargo(&mut cmd, &args.args);
 
-  let exit_status = cmd.status().expect("failed to wait for cargo?");
+  let exit_status = cmd.spawn().and_then(|mut child| {
+    let child_stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut fresh = 0;
+    for message in Message::parse_stream(child_stdout) {
+      match message? {
+        Message::TextLine(line) => writeln!(stdout, "{line}")?,
+        Message::CompilerArtifact(artifact) if artifact.fresh => {
+          let Some(output) = cache_dir
+            .as_deref()
+            .and_then(|cache_dir| cache::load_fresh(cache_dir, &run_id, &artifact))
+          else {
+            continue;
+          };
+          let crate_name = artifact.target.name.replace('-', "_");
+          let path = output_dir.join(format!("{crate_name}-fresh{fresh}.json"));
+          fs::write(path, serde_json::to_string(&output).unwrap())?;
+          fresh += 1;
+        }
+        _ => {}
+      }
+    }
+    child.wait()
+  });
 
-  match exit_status.code() {
-    Some(code) => ExitCode::from(u8::try_from(code).unwrap()),
-    None => ExitCode::FAILURE,
+  let outputs = read_outputs::<T::Output>(&output_dir);
+  let _ = fs::remove_dir_all(&output_dir);
+  if let Some(args_file) = args_file {
+    let _ = fs::remove_file(args_file);
   }
+  let status = exit_status.map_err(|e| RustcPluginError::io("failed to run cargo", e))?;
+
+  Ok(CargoRun {
+    args: args.args,
+    outputs: outputs?,
+    status,
+  })
 }
 
-fn only_run_on_file(
-  cmd: &mut Command,
-  file_path: PathBuf,
-  workspace_members: &[&cargo_metadata::Package],
-  target_dir: &Utf8Path,
-) {
-  // We compare this against canonicalized paths, so it must be canonicalized too
-  let file_path = file_path.canonicalize().unwrap();
+/// Reads the outputs written by each driver invocation into `output_dir`, sorted by crate name.
+fn read_outputs<T: DeserializeOwned>(
+  output_dir: &Utf8Path,
+) -> Result<Vec<T>, RustcPluginError> {
+  let read_error =
+    |e| RustcPluginError::io(format!("failed to read plugin outputs in {output_dir}"), e);
+  let mut paths = fs::read_dir(output_dir)
+    .map_err(read_error)?
+    .map(|entry| Ok(entry?.path()))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(read_error)?;
+  paths.sort();
+
+  paths
+    .into_iter()
+    .map(|path| {
+      let contents = fs::read_to_string(&path).map_err(read_error)?;
+      serde_json::from_str(&contents)
+        .map_err(|source| RustcPluginError::InvalidOutput { path, source })
+    })
+    .collect()
+}
+
+/// Loads the metadata of the workspace, including its dependencies if `deps` is true.
+fn load_metadata(deps: bool) -> Result<Metadata, RustcPluginError> {
+  let mut cmd = cargo_metadata::MetadataCommand::new();
+  if deps {
+    cmd.other_options(["--all-features".to_string()]);
+  } else {
+    cmd
+      .no_deps()
+      .other_options(["--all-features".to_string(), "--offline".to_string()]);
+  }
+  Ok(cmd.exec()?)
+}
 
-  // Find the package and target that corresponds to a given file path
-  let mut matching = workspace_members
+fn workspace_members(metadata: &Metadata) -> Vec<&Package> {
+  metadata
+    .workspace_members
     .iter()
-    .filter_map(|pkg| {
-      let targets = pkg
-        .targets
+    .map(|pkg_id| {
+      metadata
+        .packages
         .iter()
-        .filter(|target| {
-          let src_path = target.src_path.canonicalize().unwrap();
-          log::trace!("Package {} has src path {}", pkg.name, src_path.display());
-          file_path.starts_with(src_path.parent().unwrap())
-        })
-        .collect::<Vec<_>>();
-
-      let target = (match targets.len() {
-        0 => None,
-        1 => Some(targets[0]),
-        _ => {
-          // If there are multiple targets that match a given directory, e.g. `examples/whatever.rs`, then
-          // find the target whose name matches the file stem
-          let stem = file_path.file_stem().unwrap().to_string_lossy();
-          let name_matches_stem = targets
-            .clone()
-            .into_iter()
-            .find(|target| target.name == stem);
-
-          // Otherwise we're in a special case, e.g. "main.rs" corresponds to the bin target.
-          name_matches_stem.or_else(|| {
-            let only_bin = targets
-              .iter()
-              .all(|target| !target.kind.contains(&"lib".into()));
-            // TODO: this is a pile of hacks, and it seems like there is no reliable way to say
-            // which target a file will correspond to given only its filename. For example,
-            // if you have src/foo.rs it could either be imported by src/main.rs, or src/lib.rs, or
-            // even both!
-            if only_bin {
-              targets
-                .into_iter()
-                .find(|target| target.kind.contains(&"bin".into()))
-            } else {
-              let kind = (if stem == "main" { "bin" } else { "lib" }).to_string();
-              targets
-                .into_iter()
-                .find(|target| target.kind.contains(&kind))
-            }
-          })
-        }
-      })?;
+        .find(|pkg| &pkg.id == pkg_id)
+        .unwrap()
+    })
+    .collect()
+}
+
+/// Returns the packages matching `predicate`, either among the workspace members or
+/// in the entire dependency graph if `search_deps` is true, in which case `metadata`
+/// must include dependencies.
+fn find_packages(
+  metadata: &Metadata,
+  workspace_members: &[&Package],
+  search_deps: bool,
+  predicate: impl Fn(&Package) -> bool,
+) -> Vec<Package> {
+  if search_deps {
+    metadata
+      .packages
+      .iter()
+      .filter(|pkg| predicate(pkg))
+      .cloned()
+      .collect()
+  } else {
+    workspace_members
+      .iter()
+      .filter(|pkg| predicate(pkg))
+      .map(|pkg| (*pkg).clone())
+      .collect()
+  }
+}
+
+/// Matches `name` against a `pattern` where `*` matches any sequence of characters
+/// and `?` matches any single character.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+  match (pattern.split_first(), name.split_first()) {
+    (None, None) => true,
+    (Some((b'*', rest)), _) => {
+      glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1 ..]))
+    }
+    (Some((b'?', rest)), Some((_, name_rest))) => glob_match(rest, name_rest),
+    (Some((c, rest)), Some((d, name_rest))) => c == d && glob_match(rest, name_rest),
+    _ => false,
+  }
+}
+
+/// Adds a compile filter for each package, and returns true if any package is not
+/// a member of the workspace.
+fn only_run_on_packages(
+  cmd: &mut Command,
+  packages: &[Package],
+  workspace_members: &[&Package],
+) -> Result<bool, RustcPluginError> {
+  if packages.is_empty() {
+    return Err(RustcPluginError::NoMatchingPackages);
+  }
+
+  for pkg in packages {
+    cmd.arg("-p").arg(format!("{}:{}", pkg.name, pkg.version));
+  }
 
-      Some((pkg, target))
+  let specs = packages
+    .iter()
+    .map(|pkg| PackageSpec {
+      name: pkg.name.to_string(),
+      version: pkg.version.to_string(),
     })
     .collect::<Vec<_>>();
-  let (pkg, target) = match matching.len() {
-    0 => panic!("Could not find target for path: {}", file_path.display()),
-    1 => matching.remove(0),
-    _ => panic!("Too many matching targets: {matching:?}"),
-  };
+  cmd.env(SPECIFIC_PACKAGES, serde_json::to_string(&specs).unwrap());
 
-  // Add compile filter to specify the target corresponding to the given file
-  cmd.arg("-p").arg(format!("{}:{}", pkg.name, pkg.version));
-
-  // See https://doc.rust-lang.org/cargo/commands/cargo-check.html#target-selection for possible compile kinds
-  enum CompileKind {
-    Lib,
-    Bin,
-    Example,
-    Test,
-    Bench,
-    ProcMacro,
-  }
-
-  // kind string should be one of the ones listed here:
-  // https://doc.rust-lang.org/cargo/reference/cargo-targets.html#the-crate-type-field
-  let kind_str = &target.kind[0];
-  let kind = match kind_str.as_str() {
-    "lib" | "rlib" | "dylib" | "staticlib" | "cdylib" => CompileKind::Lib,
-    "bin" => CompileKind::Bin,
-    "proc-macro" => CompileKind::ProcMacro,
-    "example" => CompileKind::Example,
-    "test" => CompileKind::Test,
-    "bench" => CompileKind::Bench,
-    _ => unreachable!("unexpected cargo crate type: {kind_str}"),
-  };
+  log::debug!("Packages: {specs:?}");
 
-  match kind {
-    CompileKind::Lib => {
-      // If the rmeta files were previously generated for the lib (e.g. by running the plugin
-      // on a reverse-dep), then we have to remove them or else Cargo will memoize the plugin.
-      let deps_dir = target_dir.join("debug").join("deps");
-      if let Ok(entries) = fs::read_dir(deps_dir) {
-        let prefix = format!("lib{}", pkg.name.replace('-', "_"));
-        for entry in entries {
-          let path = entry.unwrap().path();
-          if let Some(file_name) = path.file_name()
-            && file_name.to_string_lossy().starts_with(&prefix)
-          {
-            fs::remove_file(path).unwrap();
-          }
-        }
-      }
+  Ok(
+    packages
+      .iter()
+      .any(|pkg| !workspace_members.iter().any(|member| member.id == pkg.id)),
+  )
+}
 
-      cmd.arg("--lib");
-    }
-    CompileKind::Bin => {
-      cmd.args(["--bin", &target.name]);
-    }
-    CompileKind::ProcMacro => {}
-    CompileKind::Example => {
-      cmd.args(["--example", &target.name]);
+fn only_run_on_target_kinds(
+  cmd: &mut Command,
+  kinds: &[TargetKind],
+  subcommand: CargoSubcommand,
+  workspace_members: &[&Package],
+) {
+  cmd.arg("--all");
+  for kind in kinds {
+    let flag = match kind {
+      TargetKind::Lib => "--lib",
+      TargetKind::Bin => "--bins",
+      TargetKind::Example => "--examples",
+      TargetKind::Test => "--tests",
+      TargetKind::Bench => "--benches",
+    };
+    // Cargo rejects repeated flags, including those of the subcommand (e.g. `check --tests`)
+    if !subcommand.args()[1 ..].contains(&flag) {
+      cmd.arg(flag);
     }
-    CompileKind::Test => {
-      cmd.args(["--test", &target.name]);
+  }
+
+  // Other targets may still be compiled as dependencies of the selected targets
+  // (e.g. a package's lib for its bins), so the driver must check each crate.
+  let crates = workspace_members
+    .iter()
+    .flat_map(|pkg| &pkg.targets)
+    .filter_map(|target| {
+      let kind = TargetKind::from_cargo_kind(&target.kind[0])?;
+      let test = matches!(kind, TargetKind::Test | TargetKind::Bench);
+      kinds
+        .contains(&kind)
+        .then(|| CrateSpec::new(&target.name, target.src_path.as_std_path(), test))
+    })
+    .collect::<Vec<_>>();
+  cmd.env(SPECIFIC_CRATES, serde_json::to_string(&crates).unwrap());
+
+  log::debug!("Crates: {crates:?}");
+}
+
+/// Adds a compile filter for each target that includes `file_path`, as chosen
+/// by [`RustcPlugin::select_targets`].
+fn only_run_on_file<T: RustcPlugin>(
+  cmd: &mut Command,
+  plugin: &T,
+  args: &T::Args,
+  file_path: PathBuf,
+  workspace_root: &Utf8Path,
+  workspace_members: &[&Package],
+  target_dir: &Utf8Path,
+) -> Result<(), RustcPluginError> {
+  // Dep-info files contain canonical paths, so the file must be canonicalized too
+  let file_path =
+    file_path
+      .canonicalize()
+      .map_err(|source| RustcPluginError::FileNotFound {
+        path: file_path,
+        source,
+      })?;
+
+  let targets = discover::targets_containing_file(
+    &file_path,
+    workspace_root,
+    workspace_members,
+    target_dir,
+    plugin.cargo_profile(args).as_deref(),
+    |cmd| plugin.modify_cargo(cmd, args),
+  )?;
+  if targets.is_empty() {
+    return Err(RustcPluginError::FileNotInWorkspace(file_path));
+  }
+
+  let targets = plugin.select_targets(&file_path, targets);
+  if targets.is_empty() {
+    return Err(RustcPluginError::NoMatchingPackages);
+  }
+
+  let mut packages: Vec<Package> = Vec::new();
+  let mut selection: Vec<Vec<&str>> = Vec::new();
+  let mut crates = Vec::new();
+  for target in &targets {
+    // See https://doc.rust-lang.org/cargo/commands/cargo-check.html#target-selection
+    let flags = if target.test && matches!(target.kind, TargetKind::Lib | TargetKind::Bin)
+    {
+      // Unit tests can't be selected individually, so this also checks the other test
+      // targets of the package. The driver skips them using SPECIFIC_CRATES.
+      vec!["--tests"]
+    } else {
+      match target.kind {
+        TargetKind::Lib => vec!["--lib"],
+        TargetKind::Bin => vec!["--bin", &target.name],
+        TargetKind::Example => vec!["--example", &target.name],
+        TargetKind::Test => vec!["--test", &target.name],
+        TargetKind::Bench => vec!["--bench", &target.name],
+      }
+    };
+    // Cargo rejects repeated flags, including those of the subcommand (e.g. `check --tests`)
+    let subcommand = plugin.cargo_subcommand(args);
+    if !selection.contains(&flags) && flags != subcommand.args()[1 ..] {
+      selection.push(flags);
     }
-    CompileKind::Bench => {
-      cmd.args(["--bench", &target.name]);
+
+    crates.push(CrateSpec::new(&target.name, &target.src_path, target.test));
+
+    if !packages.iter().any(|pkg| pkg.id == target.package.id) {
+      packages.push(target.package.clone());
     }
   }
 
-  cmd.env(
-    SPECIFIC_CRATE,
-    match kind {
-      CompileKind::Lib => &pkg.name,
-      CompileKind::Bin => &pkg.name,
-      CompileKind::Example => &target.name,
-      CompileKind::Test => &target.name,
-      CompileKind::Bench => &target.name,
-      CompileKind::ProcMacro => &pkg.name,
-    }
-    .replace('-', "_"),
-  );
-  cmd.env(SPECIFIC_TARGET, match kind {
-    CompileKind::Bench | CompileKind::Example => "bin",
-    _ => kind_str,
-  });
+  cmd.args(selection.concat());
+  only_run_on_packages(cmd, &packages, workspace_members)?;
+  cmd.env(SPECIFIC_CRATES, serde_json::to_string(&crates).unwrap());
+
+  log::debug!("Crates: {crates:?}");
 
-  log::debug!(
-    "Package: {}, target kind {}, target name {}",
-    pkg.name,
-    kind_str,
-    target.name
-  );
+  Ok(())
 }
@@ -0,0 +1,377 @@
+//! Running several plugins in a single compiler session.
+
+use std::{any::Any, borrow::Cow, collections::BTreeMap};
+
+use cargo_metadata::camino::Utf8Path;
+use rustc_ast::ast;
+use rustc_driver::Compilation;
+use rustc_interface::interface;
+use rustc_middle::ty::TyCtxt;
+use rustc_session::{EarlyDiagCtxt, config::ErrorOutputType};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{CrateFilter, RustcPlugin, RustcPluginArgs, RustcPluginError};
+
+/// A plugin that can run alongside other plugins in a [`PluginSet`].
+///
+/// Unlike a [`RustcPlugin`], a composable plugin doesn't invoke the compiler itself.
+/// Instead, it provides [`rustc_driver::Callbacks`] that are called in a compiler session
+/// shared with the other plugins in the set.
+pub trait ComposablePlugin {
+  /// Command-line arguments passed by the user.
+  type Args: Serialize + DeserializeOwned;
+
+  /// Results produced by a single invocation of the driver.
+  type Output: Serialize + DeserializeOwned;
+
+  /// The plugin's callbacks into the compiler.
+  type Callbacks: rustc_driver::Callbacks + Send + 'static;
+
+  /// Returns the name of the plugin, which must be unique within a [`PluginSet`].
+  ///
+  /// The name is the key of the plugin's args and outputs within those of the set.
+  fn name(&self) -> Cow<'static, str>;
+
+  /// Parses and returns the CLI arguments for the plugin.
+  fn args(&self, target_dir: &Utf8Path) -> Self::Args;
+
+  /// Returns the callbacks to run in the compiler session of a crate.
+  fn callbacks(&self, args: Self::Args) -> Self::Callbacks;
+
+  /// Extracts the output for a crate once the compiler session has finished.
+  fn output(&self, callbacks: Self::Callbacks) -> Self::Output;
+
+  /// Optionally process the outputs of every crate once Cargo has finished.
+  ///
+  /// See [`RustcPlugin::finalize`].
+  fn finalize(
+    &self,
+    _outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    Ok(())
+  }
+}
+
+/// A [`RustcPlugin`] that runs several [`ComposablePlugin`]s in one compiler session
+/// per crate, so that the workspace is only compiled once for all of them.
+///
+/// The args of the set map each plugin's [name](ComposablePlugin::name) to its args, and
+/// likewise for the outputs. If the set has a configuration (see
+/// [`PluginSet::with_config_name`]), each plugin's settings are therefore in a table with
+/// its name. Since the set doesn't know which args the user set explicitly, each field
+/// of a plugin's serialized args overrides its configuration.
+///
+/// Each compiler hook calls the plugins in the order they were added. Compilation stops
+/// after a hook if any plugin returns [`Compilation::Stop`]. Since
+/// [`interface::Config::override_queries`] is a function pointer, at most one plugin in
+/// a set may override queries. If several of them do, the driver reports a compiler
+/// error, so the CLI fails with [`RustcPluginError::Cargo`].
+///
+/// If the args of a plugin are missing or can't be deserialized, the driver reports a
+/// fatal error without running the compiler.
+///
+/// [`RustcPlugin::finalize`] is called for every plugin, even if an earlier one fails.
+/// The first error is returned.
+pub struct PluginSet {
+  version: Cow<'static, str>,
+  driver_name: Cow<'static, str>,
+  config_name: Option<Cow<'static, str>>,
+  filter: Box<dyn Fn() -> CrateFilter>,
+  plugins: Vec<Box<dyn ErasedPlugin>>,
+}
+
+impl PluginSet {
+  /// Creates an empty set with the given version and driver name, which are used as
+  /// [`RustcPlugin::version`] and [`RustcPlugin::driver_name`].
+  ///
+  /// The set runs on [`CrateFilter::OnlyWorkspace`] unless changed by
+  /// [`PluginSet::with_filter`].
+  pub fn new(
+    version: impl Into<Cow<'static, str>>,
+    driver_name: impl Into<Cow<'static, str>>,
+  ) -> Self {
+    PluginSet {
+      version: version.into(),
+      driver_name: driver_name.into(),
+      config_name: None,
+      filter: Box::new(|| CrateFilter::OnlyWorkspace),
+      plugins: Vec::new(),
+    }
+  }
+
+  /// Sets the crates to run the plugins on.
+  #[must_use]
+  pub fn with_filter(mut self, filter: impl Fn() -> CrateFilter + 'static) -> Self {
+    self.filter = Box::new(filter);
+    self
+  }
+
+  /// Sets the name under which the configuration of the set is found, as described in
+  /// [`RustcPlugin::config_name`].
+  #[must_use]
+  pub fn with_config_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+    self.config_name = Some(name.into());
+    self
+  }
+
+  /// Adds a plugin to the set.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the set already contains a plugin with the same name.
+  #[must_use]
+  pub fn with_plugin<P: ComposablePlugin + 'static>(mut self, plugin: P) -> Self {
+    let name = plugin.name();
+    assert!(
+      self.plugins.iter().all(|other| other.name() != name),
+      "duplicate plugin name: {name}"
+    );
+    self.plugins.push(Box::new(plugin));
+    self
+  }
+}
+
+impl RustcPlugin for PluginSet {
+  type Args = BTreeMap<String, Value>;
+  type Output = BTreeMap<String, Value>;
+
+  fn version(&self) -> Cow<'static, str> {
+    self.version.clone()
+  }
+
+  fn driver_name(&self) -> Cow<'static, str> {
+    self.driver_name.clone()
+  }
+
+  fn config_name(&self) -> Option<Cow<'static, str>> {
+    self.config_name.clone()
+  }
+
+  fn args(&self, target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
+    let args = self
+      .plugins
+      .iter()
+      .map(|plugin| (plugin.name().into_owned(), plugin.args(target_dir)))
+      .collect();
+    RustcPluginArgs {
+      args,
+      filter: (self.filter)(),
+      explicit_fields: None,
+    }
+  }
+
+  fn run(
+    self,
+    compiler_args: Vec<String>,
+    mut plugin_args: Self::Args,
+  ) -> interface::Result<Self::Output> {
+    let callbacks = self
+      .plugins
+      .iter()
+      .map(|plugin| {
+        let name = plugin.name();
+        let args = plugin_args
+          .remove(name.as_ref())
+          .ok_or_else(|| RustcPluginError::MissingPluginArgs(name.to_string()))?;
+        Ok((name, plugin.callbacks(args)?))
+      })
+      .collect::<Result<_, RustcPluginError>>();
+    let callbacks = match callbacks {
+      Ok(callbacks) => callbacks,
+      Err(e) => {
+        let early_dcx = EarlyDiagCtxt::new(ErrorOutputType::default());
+        return Err(early_dcx.early_err(e.to_string()));
+      }
+    };
+    let mut callbacks = SetCallbacks { callbacks };
+    rustc_driver::run_compiler(&compiler_args, &mut callbacks);
+
+    Ok(
+      self
+        .plugins
+        .iter()
+        .zip(callbacks.callbacks)
+        .map(|(plugin, (name, callbacks))| (name.into_owned(), plugin.output(callbacks)))
+        .collect(),
+    )
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    let mut result = Ok(());
+    for plugin in &self.plugins {
+      let name = plugin.name();
+      let finalized = match args.get(name.as_ref()) {
+        Some(args) => {
+          let outputs = outputs
+            .iter()
+            .filter_map(|output| output.get(name.as_ref()).cloned())
+            .collect();
+          plugin.finalize(outputs, args)
+        }
+        None => Err(RustcPluginError::MissingPluginArgs(name.into_owned())),
+      };
+      if result.is_ok() {
+        result = finalized;
+      }
+    }
+    result
+  }
+}
+
+/// A [`ComposablePlugin`] with its types erased, so that plugins of different types can
+/// be stored in one [`PluginSet`].
+trait ErasedPlugin {
+  fn name(&self) -> Cow<'static, str>;
+  fn args(&self, target_dir: &Utf8Path) -> Value;
+  fn callbacks(&self, args: Value) -> Result<Box<dyn ErasedCallbacks>, RustcPluginError>;
+  fn output(&self, callbacks: Box<dyn ErasedCallbacks>) -> Value;
+  fn finalize(&self, outputs: Vec<Value>, args: &Value) -> Result<(), RustcPluginError>;
+}
+
+impl<P: ComposablePlugin> ErasedPlugin for P {
+  fn name(&self) -> Cow<'static, str> {
+    ComposablePlugin::name(self)
+  }
+
+  fn args(&self, target_dir: &Utf8Path) -> Value {
+    serde_json::to_value(ComposablePlugin::args(self, target_dir)).unwrap()
+  }
+
+  fn callbacks(&self, args: Value) -> Result<Box<dyn ErasedCallbacks>, RustcPluginError> {
+    let args = deserialize(&ComposablePlugin::name(self), "args", args)?;
+    Ok(Box::new(ComposablePlugin::callbacks(self, args)))
+  }
+
+  fn output(&self, callbacks: Box<dyn ErasedCallbacks>) -> Value {
+    let callbacks = *callbacks.into_any().downcast::<P::Callbacks>().unwrap();
+    serde_json::to_value(ComposablePlugin::output(self, callbacks)).unwrap()
+  }
+
+  fn finalize(&self, outputs: Vec<Value>, args: &Value) -> Result<(), RustcPluginError> {
+    let name = ComposablePlugin::name(self);
+    let outputs = outputs
+      .into_iter()
+      .map(|output| deserialize(&name, "output", output))
+      .collect::<Result<_, _>>()?;
+    let args = deserialize(&name, "args", args.clone())?;
+    ComposablePlugin::finalize(self, outputs, &args)
+  }
+}
+
+fn deserialize<T: DeserializeOwned>(
+  plugin: &str,
+  what: &'static str,
+  value: Value,
+) -> Result<T, RustcPluginError> {
+  serde_json::from_value(value).map_err(|source| RustcPluginError::InvalidPluginData {
+    plugin: plugin.to_string(),
+    what,
+    source,
+  })
+}
+
+trait ErasedCallbacks: rustc_driver::Callbacks + Send {
+  fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<C: rustc_driver::Callbacks + Send + 'static> ErasedCallbacks for C {
+  fn into_any(self: Box<Self>) -> Box<dyn Any> {
+    self
+  }
+}
+
+/// Dispatches each compiler hook to the callbacks of every plugin in a set.
+struct SetCallbacks {
+  callbacks: Vec<(Cow<'static, str>, Box<dyn ErasedCallbacks>)>,
+}
+
+impl SetCallbacks {
+  fn all(
+    &mut self,
+    mut f: impl FnMut(&mut dyn ErasedCallbacks) -> Compilation,
+  ) -> Compilation {
+    // Every plugin gets to run its hook, even if an earlier one asks to stop.
+    let mut compilation = Compilation::Continue;
+    for (_, callbacks) in &mut self.callbacks {
+      if let Compilation::Stop = f(&mut **callbacks) {
+        compilation = Compilation::Stop;
+      }
+    }
+    compilation
+  }
+}
+
+impl rustc_driver::Callbacks for SetCallbacks {
+  fn config(&mut self, config: &mut interface::Config) {
+    let mut overridden_by: Option<&str> = None;
+    let mut conflict = None;
+    for (name, callbacks) in &mut self.callbacks {
+      let override_queries = config.override_queries.take();
+      let register_lints = config.register_lints.take();
+      let psess_created = config.psess_created.take();
+
+      callbacks.config(config);
+
+      match (overridden_by, config.override_queries.is_some()) {
+        (None, true) => overridden_by = Some(name),
+        (Some(other), true) => {
+          conflict
+            .get_or_insert(format!("plugins {other} and {name} both override queries"));
+          config.override_queries = override_queries;
+        }
+        (_, false) => config.override_queries = override_queries,
+      }
+
+      // Hooks that are closures can be chained, so each plugin can register its own.
+      config.register_lints = match (register_lints, config.register_lints.take()) {
+        (Some(prev), Some(next)) => Some(Box::new(move |sess, store| {
+          prev(sess, store);
+          next(sess, store);
+        })),
+        (prev, next) => next.or(prev),
+      };
+      config.psess_created = match (psess_created, config.psess_created.take()) {
+        (Some(prev), Some(next)) => Some(Box::new(move |psess| {
+          prev(psess);
+          next(psess);
+        })),
+        (prev, next) => next.or(prev),
+      };
+    }
+
+    // The error can only be reported once the session's diagnostics are set up.
+    if let Some(conflict) = conflict {
+      config.psess_created = Some(Box::new(move |psess| psess.dcx().fatal(conflict)));
+    }
+  }
+
+  fn after_crate_root_parsing(
+    &mut self,
+    compiler: &interface::Compiler,
+    krate: &mut ast::Crate,
+  ) -> Compilation {
+    self.all(|callbacks| callbacks.after_crate_root_parsing(compiler, krate))
+  }
+
+  fn after_expansion<'tcx>(
+    &mut self,
+    compiler: &interface::Compiler,
+    tcx: TyCtxt<'tcx>,
+  ) -> Compilation {
+    self.all(|callbacks| callbacks.after_expansion(compiler, tcx))
+  }
+
+  fn after_analysis<'tcx>(
+    &mut self,
+    compiler: &interface::Compiler,
+    tcx: TyCtxt<'tcx>,
+  ) -> Compilation {
+    self.all(|callbacks| callbacks.after_analysis(compiler, tcx))
+  }
+}
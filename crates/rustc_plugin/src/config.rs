@@ -0,0 +1,110 @@
+//! Layering configuration from the workspace onto the plugin's args.
+//!
+//! For a plugin with config name `name`, each layer overrides the ones before it:
+//! 1. The args returned by [`RustcPlugin::args`](crate::RustcPlugin::args)
+//! 2. `[workspace.metadata.<name>]` in the workspace's `Cargo.toml`
+//! 3. `<name>.toml` in the workspace root
+//! 4. `[package.metadata.<name>]` in a package's `Cargo.toml`, only for that package
+//! 5. The fields of the args that the user set explicitly, as given by
+//!    [`RustcPluginArgs::explicit_fields`](crate::RustcPluginArgs::explicit_fields)
+//!
+//! Layers are merged as JSON values, so objects are merged key by key and any other
+//! value is replaced.
+
+use std::{collections::BTreeMap, fs, io};
+
+use cargo_metadata::{Metadata, Package};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::{RustcPluginError, args::LayeredArgs};
+
+/// Merges the configuration of the workspace into `args`, except for its
+/// `explicit_fields`.
+pub fn apply_config<T: Serialize + DeserializeOwned>(
+  name: &str,
+  metadata: &Metadata,
+  workspace_members: &[&Package],
+  args: T,
+  explicit_fields: Option<&[String]>,
+) -> Result<LayeredArgs<T>, RustcPluginError> {
+  let mut layers = Vec::new();
+  if let Some(config) = metadata.workspace_metadata.get(name) {
+    layers.push(config.clone());
+  }
+
+  let path = metadata.workspace_root.join(format!("{name}.toml"));
+  match fs::read_to_string(&path) {
+    Ok(contents) => {
+      let config =
+        toml::from_str::<Value>(&contents).map_err(|e| RustcPluginError::Config {
+          origin: path.to_string(),
+          message: e.to_string(),
+        })?;
+      layers.push(config);
+    }
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(RustcPluginError::io(format!("failed to read {path}"), e)),
+  }
+
+  let package_layers = workspace_members
+    .iter()
+    .filter_map(|pkg| Some((pkg.name.clone(), pkg.metadata.get(name)?.clone())))
+    .collect::<Vec<_>>();
+
+  // Without any configuration, the args don't need to be representable as JSON objects.
+  if layers.is_empty() && package_layers.is_empty() {
+    return Ok(LayeredArgs {
+      args,
+      packages: BTreeMap::new(),
+    });
+  }
+
+  let defaults = serde_json::to_value(&args).unwrap();
+  let cli_layer = match (explicit_fields, &defaults) {
+    (Some(fields), Value::Object(defaults)) => Value::Object(
+      defaults
+        .iter()
+        .filter(|(key, _)| fields.contains(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect(),
+    ),
+    _ => defaults.clone(),
+  };
+  let base = layers.into_iter().fold(defaults, merge);
+  let build = |layers: Vec<Value>, origin: &str| {
+    let value = layers.into_iter().fold(base.clone(), merge);
+    serde_json::from_value::<T>(value).map_err(|e| RustcPluginError::Config {
+      origin: origin.to_string(),
+      message: e.to_string(),
+    })
+  };
+
+  let packages = package_layers
+    .into_iter()
+    .map(|(pkg, layer)| {
+      let args = build(vec![layer, cli_layer.clone()], &format!("package `{pkg}`"))?;
+      Ok((pkg, args))
+    })
+    .collect::<Result<_, RustcPluginError>>()?;
+  let args = build(vec![cli_layer], "workspace")?;
+
+  Ok(LayeredArgs { args, packages })
+}
+
+/// Merges `layer` on top of `base`.
+fn merge(base: Value, layer: Value) -> Value {
+  match (base, layer) {
+    (Value::Object(mut base), Value::Object(layer)) => {
+      for (key, value) in layer {
+        let merged = match base.remove(&key) {
+          Some(base_value) => merge(base_value, value),
+          None => value,
+        };
+        base.insert(key, merged);
+      }
+      Value::Object(base)
+    }
+    (_, layer) => layer,
+  }
+}
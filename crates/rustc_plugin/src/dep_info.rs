@@ -0,0 +1,73 @@
+//! Utilities for reading and extending the Makefile-style dep-info files emitted by rustc.
+
+use std::{
+  fs::{self, OpenOptions},
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+
+use crate::driver::arg_value;
+
+/// Returns the path to the dep-info file that rustc will emit for the given compiler args,
+/// if the args ask for one.
+pub fn dep_info_path(args: &[String]) -> Option<PathBuf> {
+  let emit = arg_value(args, "--emit", |_| true)?;
+  let dep_info = emit.split(',').find(|kind| kind.starts_with("dep-info"))?;
+  if let Some((_, path)) = dep_info.split_once('=') {
+    return Some(PathBuf::from(path));
+  }
+
+  let crate_name = arg_value(args, "--crate-name", |_| true)?;
+  let out_dir = arg_value(args, "--out-dir", |_| true)?;
+  let extra_filename = args
+    .iter()
+    .find_map(|arg| arg.strip_prefix("extra-filename="))
+    .unwrap_or("");
+  Some(Path::new(out_dir).join(format!("{crate_name}{extra_filename}.d")))
+}
+
+/// Returns every source file listed in a dep-info file.
+pub fn read_dep_info(path: &Path) -> io::Result<Vec<PathBuf>> {
+  let contents = fs::read_to_string(path)?;
+
+  // rustc emits a phony target with no dependencies for each input file,
+  // so these are exactly the lines of the form `path:`.
+  let files = contents
+    .lines()
+    .filter(|line| !line.starts_with('#'))
+    .filter_map(|line| line.strip_suffix(':'))
+    .map(|file| PathBuf::from(file.replace("\\ ", " ")))
+    .collect();
+  Ok(files)
+}
+
+/// Records a dependency on the current value of each environment variable in `vars`.
+///
+/// Cargo reads these entries after rustc exits, and will re-run rustc on the crate
+/// whenever one of the variables changes, just like for `env!`.
+pub fn track_env_vars(path: &Path, vars: &[&str]) -> io::Result<()> {
+  let mut file = OpenOptions::new().append(true).open(path)?;
+  writeln!(file)?;
+  for var in vars {
+    write!(file, "# env-dep:{var}")?;
+    if let Ok(value) = std::env::var(var) {
+      write!(file, "={}", escape_env(&value))?;
+    }
+    writeln!(file)?;
+  }
+  Ok(())
+}
+
+/// Escapes a string the same way as rustc's `escape_dep_env`.
+fn escape_env(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\n' => escaped.push_str(r"\n"),
+      '\r' => escaped.push_str(r"\r"),
+      '\\' => escaped.push_str(r"\\"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
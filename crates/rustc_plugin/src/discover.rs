@@ -0,0 +1,273 @@
+//! Finds the targets whose module tree includes a given source file.
+//!
+//! A file can't be mapped to a target by its path alone, since e.g. `src/foo.rs` may be
+//! included by both `src/lib.rs` and `src/main.rs`, or by neither. Instead, we check the
+//! relevant packages without the plugin and read the dep-info file that rustc emits for
+//! each target, which lists every source file in its module tree.
+
+use std::{
+  fs,
+  io::BufReader,
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+  time::SystemTime,
+};
+
+use cargo_metadata::{Message, Package, camino::Utf8Path};
+
+use crate::{FileTarget, RustcPluginError, TargetKind, dep_info, plugin::profile_dir};
+
+/// Returns every target in the workspace that includes `file` in its module tree.
+///
+/// `file` must be canonicalized. The discovery command uses the same `profile` as the plugin
+/// run, and `modify_cargo` is applied to it, so that it compiles the same configuration
+/// (e.g. features). It always uses `cargo check`, since other subcommands don't change
+/// which files are in a target's module tree.
+pub fn targets_containing_file(
+  file: &Path,
+  workspace_root: &Utf8Path,
+  workspace_members: &[&Package],
+  target_dir: &Utf8Path,
+  profile: Option<&str>,
+  modify_cargo: impl FnOnce(&mut Command),
+) -> Result<Vec<FileTarget>, RustcPluginError> {
+  // Only packages whose directory contains the file are considered. If packages are
+  // nested, then the file belongs to the innermost one.
+  let candidates = workspace_members
+    .iter()
+    .filter(|pkg| {
+      pkg
+        .manifest_path
+        .parent()
+        .and_then(|dir| dir.canonicalize().ok())
+        .is_some_and(|dir| file.starts_with(dir))
+    })
+    .collect::<Vec<_>>();
+  let Some(depth) = candidates
+    .iter()
+    .map(|pkg| pkg.manifest_path.components().count())
+    .max()
+  else {
+    return Ok(Vec::new());
+  };
+  let candidates = candidates
+    .into_iter()
+    .filter(|pkg| pkg.manifest_path.components().count() == depth)
+    .collect::<Vec<_>>();
+
+  // Check every target of the candidates, including tests which may contain modules
+  // that are only included under `cfg(test)`. This check is done without the plugin, so
+  // it uses its own target directory. Otherwise, it would share its output files with the
+  // plugin's units while Cargo fingerprints them differently, and each run would
+  // invalidate the other's. This way, it is fresh on subsequent runs unless the sources
+  // change.
+  let discover_dir = target_dir.join("discover");
+  let mut cmd = Command::new("cargo");
+  cmd
+    .args([
+      "check",
+      "--all-targets",
+      "--message-format=json",
+      "--target-dir",
+    ])
+    .arg(&discover_dir)
+    .env_remove("RUSTC_WRAPPER")
+    .env_remove("RUSTC_WORKSPACE_WRAPPER")
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null());
+  if let Some(profile) = profile {
+    cmd.args(["--profile", profile]);
+  }
+  for pkg in &candidates {
+    cmd.arg("-p").arg(format!("{}:{}", pkg.name, pkg.version));
+  }
+  modify_cargo(&mut cmd);
+
+  log::debug!("Discovering targets containing {}: {cmd:?}", file.display());
+
+  let mut child = cmd
+    .spawn()
+    .map_err(|e| RustcPluginError::io("failed to run cargo", e))?;
+  let stdout = BufReader::new(child.stdout.take().unwrap());
+  let messages = Message::parse_stream(stdout)
+    .filter_map(Result::ok)
+    .collect::<Vec<_>>();
+  // The check is allowed to fail, e.g. if the file has a type error, so we only need
+  // to wait for Cargo to finish writing dep-info files.
+  child
+    .wait()
+    .map_err(|e| RustcPluginError::io("failed to wait for cargo", e))?;
+
+  let includes_file = |dep_info: &Path| {
+    dep_info::read_dep_info(dep_info)
+      .unwrap_or_default()
+      .into_iter()
+      .any(|dep| resolve(workspace_root, &dep).is_some_and(|dep| dep == file))
+  };
+
+  let mut targets = Vec::new();
+  let mut compiled = Vec::new();
+  let mut compiled_dep_infos = Vec::new();
+  for message in messages {
+    let Message::CompilerArtifact(artifact) = message else {
+      continue;
+    };
+    let Some(pkg) = candidates.iter().find(|pkg| pkg.id == artifact.package_id) else {
+      continue;
+    };
+    let Some(kind) = TargetKind::from_cargo_kind(&artifact.target.kind[0]) else {
+      continue;
+    };
+    compiled.push((
+      pkg.id.clone(),
+      artifact.target.name.clone(),
+      artifact.profile.test,
+    ));
+
+    let Some(path) = artifact
+      .filenames
+      .first()
+      .and_then(|f| artifact_dep_info(f))
+    else {
+      continue;
+    };
+    if includes_file(&path) {
+      targets.push(FileTarget {
+        package: (**pkg).clone(),
+        name: artifact.target.name.clone(),
+        kind,
+        src_path: artifact.target.src_path.into_std_path_buf(),
+        test: artifact.profile.test,
+      });
+    }
+    compiled_dep_infos.push(path);
+  }
+
+  // Units that failed to compile don't have an artifact, so fall back to the most recent
+  // dep-info files with the target's crate name that don't belong to an artifact, which
+  // rustc emits before type-checking. These don't say whether they belong to a target or to its unit tests, so the units
+  // are only added if every one of those files includes the file.
+  for pkg in &candidates {
+    for target in &pkg.targets {
+      let Some(kind) = TargetKind::from_cargo_kind(&target.kind[0]) else {
+        continue;
+      };
+      let failed = unit_test_modes(kind, target.test)
+        .iter()
+        .copied()
+        .filter(|test| !compiled.contains(&(pkg.id.clone(), target.name.clone(), *test)))
+        .collect::<Vec<_>>();
+      if failed.is_empty() {
+        continue;
+      }
+
+      let crate_name = target.name.replace('-', "_");
+      let dir = discover_dir.join(profile_dir(profile)).join(match kind {
+        TargetKind::Example => "examples",
+        _ => "deps",
+      });
+      let Some(dep_infos) = latest_dep_infos(
+        dir.as_std_path(),
+        &crate_name,
+        &compiled_dep_infos,
+        failed.len(),
+      ) else {
+        log::debug!("Missing dep-info for target {}", target.name);
+        continue;
+      };
+      let included = dep_infos.iter().filter(|path| includes_file(path)).count();
+      if included == 0 {
+        continue;
+      }
+      if included < dep_infos.len() {
+        log::debug!(
+          "Can't tell which unit of target {} includes {}",
+          target.name,
+          file.display()
+        );
+        continue;
+      }
+      for test in failed {
+        targets.push(FileTarget {
+          package: (**pkg).clone(),
+          name: target.name.clone(),
+          kind,
+          src_path: target.src_path.clone().into_std_path_buf(),
+          test,
+        });
+      }
+    }
+  }
+
+  log::debug!("Targets containing {}: {targets:?}", file.display());
+
+  Ok(targets)
+}
+
+/// Returns the dep-info file emitted alongside an artifact, e.g. `deps/foo-1234.d`
+/// for `deps/libfoo-1234.rmeta`.
+fn artifact_dep_info(artifact: &Utf8Path) -> Option<PathBuf> {
+  let stem = artifact.file_stem()?;
+  let stem = stem.strip_prefix("lib").unwrap_or(stem);
+  Some(
+    artifact
+      .with_file_name(format!("{stem}.d"))
+      .into_std_path_buf(),
+  )
+}
+
+/// Returns whether each unit that `cargo check --all-targets` compiles for a target is a
+/// test harness.
+fn unit_test_modes(kind: TargetKind, test: bool) -> &'static [bool] {
+  match kind {
+    TargetKind::Test | TargetKind::Bench => &[true],
+    _ if test => &[false, true],
+    _ => &[false],
+  }
+}
+
+/// Returns the `n` most recently modified dep-info files in `dir` for a crate, other than
+/// those in `exclude`, or `None` if there are fewer.
+fn latest_dep_infos(
+  dir: &Path,
+  crate_name: &str,
+  exclude: &[PathBuf],
+  n: usize,
+) -> Option<Vec<PathBuf>> {
+  let prefix = format!("{crate_name}-");
+  let mut dep_infos = fs::read_dir(dir)
+    .ok()?
+    .filter_map(Result::ok)
+    .filter(|entry| {
+      let name = entry.file_name();
+      let name = name.to_string_lossy();
+      name.starts_with(&prefix) && name.ends_with(".d")
+    })
+    .filter(|entry| !exclude.contains(&entry.path()))
+    .map(|entry| {
+      let modified = entry
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+      (modified, entry.path())
+    })
+    .collect::<Vec<_>>();
+  if dep_infos.len() < n {
+    return None;
+  }
+  dep_infos.sort_by(|(m1, _), (m2, _)| m2.cmp(m1));
+  Some(
+    dep_infos
+      .into_iter()
+      .take(n)
+      .map(|(_, path)| path)
+      .collect(),
+  )
+}
+
+/// Canonicalizes a path from a dep-info file, which is relative to the directory that
+/// Cargo runs rustc in, i.e. the workspace root.
+fn resolve(workspace_root: &Utf8Path, path: &Path) -> Option<PathBuf> {
+  workspace_root.as_std_path().join(path).canonicalize().ok()
+}
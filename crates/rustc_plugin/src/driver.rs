@@ -1,19 +1,28 @@
 use std::{
-  env,
+  env, fs,
   ops::Deref,
   path::{Path, PathBuf},
   process::{Command, ExitCode, exit},
 };
 
+use rustc_errors::FatalError;
 use rustc_session::{EarlyDiagCtxt, config::ErrorOutputType};
 use rustc_tools_util::VersionInfo;
 
-use super::plugin::{PLUGIN_ARGS, RustcPlugin};
-use crate::cli::{RUN_ON_ALL_CRATES, SPECIFIC_CRATE, SPECIFIC_TARGET};
+use super::plugin::{PLUGIN_OUTPUT_DIR, RustcPlugin};
+use crate::{
+  args,
+  cache::OutputCache,
+  cli::{
+    CACHE_DIR, CrateSpec, PackageSpec, RUN_ID, RUN_ON_ALL_CRATES, SPECIFIC_CRATES,
+    SPECIFIC_PACKAGES,
+  },
+  dep_info,
+};
 
 /// If a command-line option matches `find_arg`, then apply the predicate `pred` on its value. If
 /// true, then return it. The parameter is assumed to be either `--arg=value` or `--arg value`.
-fn arg_value<'a, T: Deref<Target = str>>(
+pub(crate) fn arg_value<'a, T: Deref<Target = str>>(
   args: &'a [T],
   find_arg: &str,
   pred: impl Fn(&str) -> bool,
@@ -144,9 +153,51 @@ pub fn driver_main<T: RustcPlugin>(plugin: T) -> ExitCode {
 
     if run_plugin {
       log::debug!("Running plugin...");
-      let plugin_args: T::Args =
-        serde_json::from_str(&env::var(PLUGIN_ARGS).unwrap()).unwrap();
-      plugin.run(args, plugin_args).unwrap();
+      let (plugin_args, plugin_args_bytes) =
+        args::read_args::<T::Args>(plugin.args_format());
+      let crate_name = arg_value(&args, "--crate-name", |_| true)
+        .unwrap_or("unknown")
+        .to_string();
+
+      let cache = match env::var(CACHE_DIR) {
+        Ok(cache_dir) if plugin.cache_outputs() => Some(OutputCache::new(
+          Path::new(&cache_dir),
+          env::var(RUN_ID).unwrap_or_default(),
+          &plugin.version(),
+          &plugin_args_bytes,
+          &args,
+        )),
+        _ => None,
+      };
+
+      let output = match cache.as_ref().and_then(OutputCache::load::<T::Output>) {
+        Some(output) => {
+          // We still need to run rustc so that Cargo gets its compiler artifacts.
+          log::debug!("Replaying cached output for {crate_name}");
+          rustc_driver::run_compiler(&args, &mut DefaultCallbacks);
+          output
+        }
+        None => {
+          let mut compiler_args = args.clone();
+          if let Some(threads) = plugin.compiler_threads(&plugin_args) {
+            compiler_args.push(format!("-Zthreads={threads}"));
+          }
+          // The plugin has already reported the error, so just exit with a failure.
+          let Ok(output) = plugin.run(compiler_args, plugin_args) else {
+            FatalError.raise()
+          };
+          if let Some(cache) = &cache {
+            cache.store(&output, &args);
+          }
+          output
+        }
+      };
+
+      if let Ok(output_dir) = env::var(PLUGIN_OUTPUT_DIR) {
+        write_output(Path::new(&output_dir), &crate_name, &output);
+      }
+
+      track_env_vars(&args, &[RUN_ID]);
     } else {
       log::debug!(
         "Running normal Rust. Relevant variables:\
@@ -156,17 +207,77 @@ primary_package={primary_package}, \
 is_target_crate={is_target_crate}"
       );
       rustc_driver::run_compiler(&args, &mut DefaultCallbacks);
+
+      // If the crate selection changes, then this crate may need to be re-compiled
+      // with the plugin, so Cargo must not consider it fresh.
+      track_env_vars(&args, &[
+        RUN_ON_ALL_CRATES,
+        SPECIFIC_PACKAGES,
+        SPECIFIC_CRATES,
+      ]);
     }
   })
 }
 
+/// Makes Cargo track the given environment variables for the crate being compiled.
+fn track_env_vars(args: &[String], vars: &[&str]) {
+  let Some(path) = dep_info::dep_info_path(args) else {
+    return;
+  };
+  if let Err(e) = dep_info::track_env_vars(&path, vars) {
+    log::warn!("Failed to update dep-info file {}: {e}", path.display());
+  }
+}
+
+/// Writes the output of the current crate where the CLI can find it.
+///
+/// A crate may be compiled multiple times in one Cargo invocation (e.g. once as a lib
+/// and once as a test), so the process ID is used to disambiguate the file names.
+fn write_output<T: serde::Serialize>(output_dir: &Path, crate_name: &str, output: &T) {
+  let path = output_dir.join(format!("{crate_name}-{}.json", std::process::id()));
+  let contents = serde_json::to_string(output).unwrap();
+  fs::write(&path, contents)
+    .unwrap_or_else(|e| panic!("failed to write plugin output {}: {e}", path.display()));
+}
+
 fn is_target_crate(args: &[String]) -> bool {
-  match (env::var(SPECIFIC_CRATE), env::var(SPECIFIC_TARGET)) {
-    (Ok(krate), Ok(target)) => {
-      arg_value(args, "--crate-name", |name| name == krate).is_some()
-        && (arg_value(args, "--crate-type", |_| true).is_none()  // integration test crate
-          || arg_value(args, "--crate-type", |name| name == target).is_some())
+  let matches_package = match env::var(SPECIFIC_PACKAGES) {
+    Ok(packages) => {
+      let packages: Vec<PackageSpec> = serde_json::from_str(&packages).unwrap();
+      let (Ok(name), Ok(version)) =
+        (env::var("CARGO_PKG_NAME"), env::var("CARGO_PKG_VERSION"))
+      else {
+        return false;
+      };
+      packages
+        .iter()
+        .any(|pkg| pkg.name == name && pkg.version == version)
     }
-    _ => true,
-  }
+    Err(_) => true,
+  };
+
+  let matches_crate = match env::var(SPECIFIC_CRATES) {
+    Ok(crates) => {
+      let crates: Vec<CrateSpec> = serde_json::from_str(&crates).unwrap();
+      let is_test = args.iter().any(|arg| arg == "--test");
+      crates.iter().any(|krate| {
+        arg_value(args, "--crate-name", |name| name == krate.crate_name).is_some()
+          && krate.test == is_test
+          && is_src_path(args, &krate.src_path)
+      })
+    }
+    Err(_) => true,
+  };
+
+  matches_package && matches_crate
+}
+
+/// Returns true if the root source file of the crate being compiled is `src_path`.
+fn is_src_path(args: &[String], src_path: &Path) -> bool {
+  args.iter().any(|arg| {
+    arg.ends_with(".rs")
+      && Path::new(arg)
+        .canonicalize()
+        .is_ok_and(|path| path == src_path)
+  })
 }
@@ -0,0 +1,164 @@
+use std::{fmt, io, path::PathBuf, process::ExitCode};
+
+/// Errors that can occur while running a plugin from the CLI.
+///
+/// Each error maps to a distinct exit code via [`RustcPluginError::exit_code`], so that
+/// programs wrapping a plugin can tell the failures apart.
+#[derive(Debug)]
+pub enum RustcPluginError {
+  /// `cargo metadata` failed, e.g. because there is no workspace in the current directory.
+  Metadata(cargo_metadata::Error),
+
+  /// The file given to [`CrateFilter::CrateContainingFile`](crate::CrateFilter::CrateContainingFile)
+  /// could not be read.
+  FileNotFound { path: PathBuf, source: io::Error },
+
+  /// The file given to [`CrateFilter::CrateContainingFile`](crate::CrateFilter::CrateContainingFile)
+  /// is not included by any target in the workspace.
+  FileNotInWorkspace(PathBuf),
+
+  /// No packages or targets matched the [`CrateFilter`](crate::CrateFilter).
+  NoMatchingPackages,
+
+  /// The plugin's configuration could not be parsed, or could not be merged into
+  /// [`RustcPlugin::Args`](crate::RustcPlugin::Args). `origin` is the config file or
+  /// package the configuration came from.
+  Config { origin: String, message: String },
+
+  /// An I/O operation of the framework failed, such as spawning Cargo.
+  Io { context: String, source: io::Error },
+
+  /// The combined args of a [`PluginSet`](crate::PluginSet) have no entry for one of its
+  /// plugins.
+  MissingPluginArgs(String),
+
+  /// The args or outputs of a plugin in a [`PluginSet`](crate::PluginSet) could not be
+  /// deserialized into its types. `what` is either `"args"` or `"output"`.
+  InvalidPluginData {
+    plugin: String,
+    what: &'static str,
+    source: serde_json::Error,
+  },
+
+  /// The output of a driver could not be deserialized.
+  InvalidOutput {
+    path: PathBuf,
+    source: serde_json::Error,
+  },
+
+  /// Cargo failed, e.g. because of a compiler error or a crash in the plugin.
+  ///
+  /// Cargo has already reported the cause of the error by this point.
+  Cargo { code: Option<i32> },
+}
+
+impl RustcPluginError {
+  pub(crate) fn io(context: impl Into<String>, source: io::Error) -> Self {
+    RustcPluginError::Io {
+      context: context.into(),
+      source,
+    }
+  }
+
+  /// Returns the exit code that the CLI should exit with for this error.
+  ///
+  /// | Error                | Code                    |
+  /// |----------------------|-------------------------|
+  /// | `Metadata`           | 64                      |
+  /// | `FileNotFound`       | 65                      |
+  /// | `FileNotInWorkspace` | 66                      |
+  /// | (reserved)           | 67                      |
+  /// | `NoMatchingPackages` | 68                      |
+  /// | `MissingPluginArgs`  | 70                      |
+  /// | `Io`                 | 74                      |
+  /// | `InvalidOutput`      | 75                      |
+  /// | `InvalidPluginData`  | 76                      |
+  /// | `Config`             | 78                      |
+  /// | `Cargo`              | Cargo's exit code, or 1 |
+  ///
+  /// Code 67 was used for ambiguous targets of
+  /// [`CrateFilter::CrateContainingFile`](crate::CrateFilter::CrateContainingFile), which
+  /// can no longer occur since the plugin runs on every target that includes the file. It
+  /// is not reused, so that wrappers checking for it don't misinterpret other errors.
+  pub fn exit_code(&self) -> ExitCode {
+    let code = match self {
+      RustcPluginError::Metadata(_) => 64,
+      RustcPluginError::FileNotFound { .. } => 65,
+      RustcPluginError::FileNotInWorkspace(_) => 66,
+      RustcPluginError::NoMatchingPackages => 68,
+      RustcPluginError::MissingPluginArgs(_) => 70,
+      RustcPluginError::Config { .. } => 78,
+      RustcPluginError::Io { .. } => 74,
+      RustcPluginError::InvalidOutput { .. } => 75,
+      RustcPluginError::InvalidPluginData { .. } => 76,
+      RustcPluginError::Cargo { code } => {
+        code.and_then(|code| u8::try_from(code).ok()).unwrap_or(1)
+      }
+    };
+    ExitCode::from(code)
+  }
+}
+
+impl fmt::Display for RustcPluginError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RustcPluginError::Metadata(e) => write!(f, "failed to load Cargo metadata: {e}"),
+      RustcPluginError::FileNotFound { path, source } => {
+        write!(f, "could not read file {}: {source}", path.display())
+      }
+      RustcPluginError::FileNotInWorkspace(path) => write!(
+        f,
+        "file {} is not included by any target in the workspace",
+        path.display()
+      ),
+      RustcPluginError::NoMatchingPackages => {
+        write!(f, "no packages matched the crate filter")
+      }
+      RustcPluginError::Config { origin, message } => {
+        write!(f, "invalid plugin configuration in {origin}: {message}")
+      }
+      RustcPluginError::MissingPluginArgs(plugin) => {
+        write!(f, "missing args for plugin {plugin}")
+      }
+      RustcPluginError::Io { context, source } => write!(f, "{context}: {source}"),
+      RustcPluginError::InvalidOutput { path, source } => write!(
+        f,
+        "failed to deserialize plugin output {}: {source}",
+        path.display()
+      ),
+      RustcPluginError::InvalidPluginData {
+        plugin,
+        what,
+        source,
+      } => write!(
+        f,
+        "failed to deserialize {what} of plugin {plugin}: {source}"
+      ),
+      RustcPluginError::Cargo { code: Some(code) } => {
+        write!(f, "cargo exited with code {code}")
+      }
+      RustcPluginError::Cargo { code: None } => {
+        write!(f, "cargo was terminated by a signal")
+      }
+    }
+  }
+}
+
+impl std::error::Error for RustcPluginError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      RustcPluginError::Metadata(e) => Some(e),
+      RustcPluginError::FileNotFound { source, .. }
+      | RustcPluginError::Io { source, .. } => Some(source),
+      RustcPluginError::InvalidOutput { source, .. }
+      | RustcPluginError::InvalidPluginData { source, .. } => Some(source),
+      _ => None,
+    }
+  }
+}
+
+impl From<cargo_metadata::Error> for RustcPluginError {
+  fn from(e: cargo_metadata::Error) -> Self {
+    RustcPluginError::Metadata(e)
+  }
+}
@@ -3,25 +3,50 @@
 //! Much of this library is either directly copy/pasted, or otherwise generalized
 //! from the Clippy driver: <https://github.com/rust-lang/rust-clippy/tree/master/src>
 
-#![feature(rustc_private)]
+#![feature(rustc_private, associated_type_defaults)]
 
+extern crate rustc_ast;
 extern crate rustc_driver;
+extern crate rustc_errors;
 extern crate rustc_interface;
+extern crate rustc_middle;
 extern crate rustc_session;
+extern crate rustc_stable_hash;
 
 pub use build::build_main;
+pub use cargo_metadata::Package;
 #[doc(hidden)]
 pub use cargo_metadata::camino::Utf8Path;
-pub use cli::cli_main;
+pub use cli::{cli_main, try_cli_main};
+pub use compose::{ComposablePlugin, PluginSet};
 pub use driver::driver_main;
-pub use plugin::{CrateFilter, RustcPlugin, RustcPluginArgs};
+pub use error::RustcPluginError;
+#[cfg(feature = "lsp")]
+pub use lsp::{LspPlugin, lsp_main, try_lsp_main};
+#[cfg(feature = "lsp")]
+pub use lsp_types;
+pub use plugin::{
+  ArgsFormat, CargoSubcommand, CrateFilter, FileTarget, RustcPlugin, RustcPluginArgs,
+  TargetKind,
+};
 
 /// The toolchain channel that this version of rustc_plugin was built with.
 ///
 /// For example, `nightly-2025-08-20`
 pub const CHANNEL: &str = env!("RUSTC_CHANNEL");
 
+mod args;
 mod build;
+mod cache;
 mod cli;
+mod compose;
+mod config;
+mod dep_info;
+mod discover;
 mod driver;
+mod error;
+#[cfg(feature = "lsp")]
+mod lsp;
 mod plugin;
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -0,0 +1,170 @@
+//! A Language Server Protocol server that runs a plugin on `textDocument` requests,
+//! enabled by the `lsp` feature.
+//!
+//! The server speaks LSP over stdin and stdout. For each request whose params contain a
+//! `textDocument`, it runs the plugin with [`CrateFilter::CrateContainingFile`] on that
+//! document, in the workspace given by the client when initializing.
+//!
+//! The plugin compiles the files on disk, so unsaved changes in the editor are not seen.
+
+use std::{
+  env, fmt, io,
+  path::{Path, PathBuf},
+  process::ExitCode,
+};
+
+use lsp_server::{Connection, ErrorCode, Message, Request, Response};
+use lsp_types::{InitializeParams, ServerCapabilities, Url};
+use serde_json::Value;
+
+use crate::{CrateFilter, RustcPlugin, RustcPluginArgs, RustcPluginError, cli};
+
+/// A plugin that can answer LSP requests.
+pub trait LspPlugin: RustcPlugin {
+  /// Returns the capabilities of the server, which should advertise each request
+  /// supported by [`LspPlugin::request_args`].
+  fn capabilities(&self) -> ServerCapabilities;
+
+  /// Returns the args to run the plugin with for a request on `file`, or `None` if
+  /// the plugin doesn't support the request's `method`.
+  ///
+  /// The args are not set by the user, so the plugin's configuration overrides them.
+  fn request_args(&self, method: &str, file: &Path, params: &Value)
+  -> Option<Self::Args>;
+
+  /// Converts the outputs of every crate containing the file into the result of the
+  /// request.
+  fn response(
+    &self,
+    method: &str,
+    outputs: Vec<Self::Output>,
+    args: &Self::Args,
+  ) -> Value;
+}
+
+/// The top-level function that should be called in your language server binary.
+///
+/// The binary must be installed next to the plugin's driver, like the CLI binary.
+/// Errors of the connection are printed to stderr, whereas errors of a request are sent
+/// to the client.
+pub fn lsp_main<T: LspPlugin>(plugin: T) -> ExitCode {
+  match try_lsp_main(plugin) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("error: {e}");
+      e.exit_code()
+    }
+  }
+}
+
+/// Fallible version of [`lsp_main`].
+pub fn try_lsp_main<T: LspPlugin>(plugin: T) -> Result<(), RustcPluginError> {
+  let (connection, io_threads) = Connection::stdio();
+  serve(&plugin, &connection)?;
+  drop(connection);
+  io_threads
+    .join()
+    .map_err(|e| RustcPluginError::io("failed to close the LSP connection", e))
+}
+
+fn protocol_error(e: impl fmt::Display) -> RustcPluginError {
+  RustcPluginError::io("LSP connection failed", io::Error::other(e.to_string()))
+}
+
+fn serve<T: LspPlugin>(
+  plugin: &T,
+  connection: &Connection,
+) -> Result<(), RustcPluginError> {
+  let capabilities = serde_json::to_value(plugin.capabilities()).unwrap();
+  let params = connection
+    .initialize(capabilities)
+    .map_err(protocol_error)?;
+  let params =
+    serde_json::from_value::<InitializeParams>(params).map_err(protocol_error)?;
+
+  // Cargo finds the workspace from the current directory.
+  #[allow(deprecated)]
+  let root = params
+    .workspace_folders
+    .and_then(|folders| folders.into_iter().next())
+    .map(|folder| folder.uri)
+    .or(params.root_uri);
+  if let Some(root) = root.and_then(|uri| uri.to_file_path().ok()) {
+    env::set_current_dir(&root).map_err(|e| {
+      RustcPluginError::io(format!("failed to open workspace {}", root.display()), e)
+    })?;
+  }
+
+  for message in &connection.receiver {
+    let Message::Request(request) = message else {
+      continue;
+    };
+    if connection
+      .handle_shutdown(&request)
+      .map_err(protocol_error)?
+    {
+      break;
+    }
+    let response = handle_request(plugin, request);
+    connection
+      .sender
+      .send(Message::Response(response))
+      .map_err(protocol_error)?;
+  }
+
+  Ok(())
+}
+
+fn handle_request<T: LspPlugin>(plugin: &T, request: Request) -> Response {
+  let Request { id, method, params } = request;
+  log::debug!("Handling request {method}");
+
+  let file = params
+    .pointer("/textDocument/uri")
+    .and_then(Value::as_str)
+    .and_then(|uri| Url::parse(uri).ok())
+    .and_then(|uri| uri.to_file_path().ok());
+  let Some(file) = file else {
+    return Response::new_err(
+      id,
+      ErrorCode::InvalidParams as i32,
+      format!("{method} is not a request on a file in the workspace"),
+    );
+  };
+  let Some(args) = plugin.request_args(&method, &file, &params) else {
+    return Response::new_err(
+      id,
+      ErrorCode::MethodNotFound as i32,
+      format!("unsupported request: {method}"),
+    );
+  };
+
+  match run_on_file(plugin, args, file) {
+    Ok((outputs, args)) => Response::new_ok(id, plugin.response(&method, outputs, &args)),
+    Err(e) => Response::new_err(id, ErrorCode::RequestFailed as i32, e.to_string()),
+  }
+}
+
+fn run_on_file<T: LspPlugin>(
+  plugin: &T,
+  args: T::Args,
+  file: PathBuf,
+) -> Result<(Vec<T::Output>, T::Args), RustcPluginError> {
+  let filter = CrateFilter::CrateContainingFile(file);
+  // Stdout carries the LSP messages, so the output of Cargo and the driver goes to stderr.
+  let run = cli::run_cargo(
+    plugin,
+    |_target_dir| RustcPluginArgs {
+      args,
+      filter,
+      explicit_fields: Some(Vec::new()),
+    },
+    io::stderr(),
+  )?;
+  if !run.status.success() {
+    return Err(RustcPluginError::Cargo {
+      code: run.status.code(),
+    });
+  }
+  Ok((run.outputs, run.args))
+}
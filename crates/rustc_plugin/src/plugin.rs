@@ -1,7 +1,13 @@
-use std::{borrow::Cow, path::PathBuf, process::Command};
+use std::{
+  borrow::Cow,
+  path::{Path, PathBuf},
+  process::Command,
+};
 
-use cargo_metadata::camino::Utf8Path;
-use serde::{Serialize, de::DeserializeOwned};
+use cargo_metadata::{Package, camino::Utf8Path};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::RustcPluginError;
 
 /// Specification of a set of crates.
 pub enum CrateFilter {
@@ -11,8 +17,140 @@ pub enum CrateFilter {
   /// Just crates in the workspace.
   OnlyWorkspace,
 
-  /// Only the crate containing a specific file.
+  /// Only the crates whose module tree includes a specific file.
+  ///
+  /// A file may be included by several targets, e.g. a module of both `lib.rs` and
+  /// `main.rs`. Which of them the plugin runs on is decided by
+  /// [`RustcPlugin::select_targets`].
   CrateContainingFile(PathBuf),
+
+  /// Only the packages with the given names, which may be workspace members or dependencies.
+  ///
+  /// Names may contain `*` and `?` wildcards, e.g. `tokio*`.
+  Packages(Vec<String>),
+
+  /// Only the targets of the given kinds in the workspace.
+  TargetKinds(Vec<TargetKind>),
+
+  /// Only the packages for which the predicate returns true.
+  ///
+  /// The predicate is called on the metadata of every package in the dependency graph.
+  Predicate(Box<dyn Fn(&Package) -> bool>),
+}
+
+/// A kind of Cargo target, used by [`CrateFilter::TargetKinds`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TargetKind {
+  /// Library targets, including proc macros.
+  Lib,
+
+  /// Binary targets.
+  Bin,
+
+  /// Example targets.
+  Example,
+
+  /// Integration test targets.
+  Test,
+
+  /// Benchmark targets.
+  Bench,
+}
+
+impl TargetKind {
+  /// Returns the kind of a target from Cargo metadata, if it is one we can select.
+  pub(crate) fn from_cargo_kind(kind: &str) -> Option<Self> {
+    // See https://doc.rust-lang.org/cargo/reference/cargo-targets.html#the-crate-type-field
+    Some(match kind {
+      "lib" | "rlib" | "dylib" | "staticlib" | "cdylib" | "proc-macro" => TargetKind::Lib,
+      "bin" => TargetKind::Bin,
+      "example" => TargetKind::Example,
+      "test" => TargetKind::Test,
+      "bench" => TargetKind::Bench,
+      _ => return None,
+    })
+  }
+}
+
+/// The Cargo subcommand that the CLI uses to compile crates, from
+/// [`RustcPlugin::cargo_subcommand`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CargoSubcommand {
+  /// `cargo check`, which only type-checks crates.
+  #[default]
+  Check,
+
+  /// `cargo check --tests`, which also type-checks test harnesses, i.e. `cfg(test)` code.
+  CheckTests,
+
+  /// `cargo build`, which also generates code for each crate.
+  Build,
+
+  /// `cargo test --no-run`, which builds the crates that `cargo test` would run.
+  TestNoRun,
+}
+
+impl CargoSubcommand {
+  /// Returns the arguments to Cargo for this subcommand.
+  pub(crate) fn args(self) -> &'static [&'static str] {
+    match self {
+      CargoSubcommand::Check => &["check"],
+      CargoSubcommand::CheckTests => &["check", "--tests"],
+      CargoSubcommand::Build => &["build"],
+      CargoSubcommand::TestNoRun => &["test", "--no-run"],
+    }
+  }
+}
+
+/// The format used to pass the plugin's args to the driver, from
+/// [`RustcPlugin::args_format`].
+///
+/// Binary formats are more compact for large args, but are always passed through a file.
+/// Note that they don't support every serde feature, e.g. bincode can't deserialize
+/// `#[serde(untagged)]` enums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ArgsFormat {
+  /// JSON, via `serde_json`.
+  #[default]
+  Json,
+
+  /// [bincode](https://docs.rs/bincode), with the `bincode` feature.
+  #[cfg(feature = "bincode")]
+  Bincode,
+
+  /// [MessagePack](https://msgpack.org), with the `msgpack` feature.
+  #[cfg(feature = "msgpack")]
+  MessagePack,
+}
+
+/// Returns the directory in the target directory where Cargo places the outputs
+/// of a profile.
+pub(crate) fn profile_dir(profile: Option<&str>) -> &str {
+  // See https://doc.rust-lang.org/cargo/reference/profiles.html#custom-profiles
+  match profile {
+    None | Some("dev" | "test") => "debug",
+    Some("release" | "bench") => "release",
+    Some(profile) => profile,
+  }
+}
+
+/// A target that includes the file given to [`CrateFilter::CrateContainingFile`].
+#[derive(Debug, Clone)]
+pub struct FileTarget {
+  /// The package of the target.
+  pub package: Package,
+
+  /// The name of the target, as in Cargo metadata.
+  pub name: String,
+
+  /// The kind of the target.
+  pub kind: TargetKind,
+
+  /// The root source file of the target, e.g. `src/lib.rs`.
+  pub src_path: PathBuf,
+
+  /// True if the target is compiled as a test harness, e.g. the unit tests of a library.
+  pub test: bool,
 }
 
 /// Arguments from your plugin to the rustc_plugin framework.
@@ -22,6 +160,15 @@ pub struct RustcPluginArgs<Args> {
 
   /// Which crates you want to run the plugin on.
   pub filter: CrateFilter,
+
+  /// The top-level fields of the serialized args that the user set explicitly, e.g.
+  /// the arguments whose [`ValueSource`] in Clap is `CommandLine`. Only these fields
+  /// override the plugin's configuration (see [`RustcPlugin::config_name`]), whereas the
+  /// others are defaults that the configuration overrides. If `None`, every field
+  /// overrides the configuration.
+  ///
+  /// [`ValueSource`]: https://docs.rs/clap/latest/clap/parser/enum.ValueSource.html
+  pub explicit_fields: Option<Vec<String>>,
 }
 
 /// Interface between your plugin and the rustc_plugin framework.
@@ -29,6 +176,12 @@ pub trait RustcPlugin: Sized {
   /// Command-line arguments passed by the user.
   type Args: Serialize + DeserializeOwned;
 
+  /// Results produced by a single invocation of the driver.
+  ///
+  /// Each crate the plugin runs on emits one `Output`. The outputs of every crate are
+  /// sent back to the CLI and given to [`RustcPlugin::finalize`].
+  type Output: Serialize + DeserializeOwned = ();
+
   /// Returns the version of your plugin.
   ///
   /// A sensible default is your plugin's Cargo version:
@@ -46,6 +199,77 @@ pub trait RustcPlugin: Sized {
   /// Parses and returns the CLI arguments for the plugin.
   fn args(&self, target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args>;
 
+  /// Returns the name under which the plugin's configuration is found, or `None` if the
+  /// plugin has no configuration, which is the default. A plugin opts in by returning a
+  /// name, typically its [`RustcPlugin::driver_name`].
+  ///
+  /// For a name `name`, the framework merges the following into [`RustcPlugin::Args`],
+  /// where each layer overrides the ones before it:
+  /// 1. The args returned by [`RustcPlugin::args`], as defaults
+  /// 2. `[workspace.metadata.<name>]` in the workspace's `Cargo.toml`
+  /// 3. `<name>.toml` in the workspace root
+  /// 4. `[package.metadata.<name>]` in a package's `Cargo.toml`, only for that package
+  /// 5. The [explicit fields](RustcPluginArgs::explicit_fields) of those args
+  ///
+  /// The layers are merged field by field as JSON. The driver receives the args for the
+  /// package being compiled, while the other methods receive the args without any
+  /// package's configuration. The configuration does not affect
+  /// [`RustcPluginArgs::filter`].
+  fn config_name(&self) -> Option<Cow<'static, str>> {
+    None
+  }
+
+  /// Returns the format used to pass [`RustcPlugin::Args`] to the driver, JSON by default.
+  fn args_format(&self) -> ArgsFormat {
+    ArgsFormat::Json
+  }
+
+  /// Returns true if the framework should cache the output of each crate.
+  ///
+  /// When enabled, a crate whose sources, compiler args, and plugin args are unchanged
+  /// since the last run will not have [`RustcPlugin::run`] called again. Instead, its
+  /// previous [`RustcPlugin::Output`] is given to [`RustcPlugin::finalize`]. Only enable
+  /// this if all the results of your plugin are returned through its output.
+  ///
+  /// Crates that Cargo considers fresh are not recompiled at all. The output cache is
+  /// stored in the plugin's target directory, separately for each toolchain.
+  fn cache_outputs(&self) -> bool {
+    false
+  }
+
+  /// Chooses which targets to run on for [`CrateFilter::CrateContainingFile`].
+  ///
+  /// `targets` contains every target whose module tree includes `file`. By default,
+  /// the plugin runs on all of them.
+  fn select_targets(&self, _file: &Path, targets: Vec<FileTarget>) -> Vec<FileTarget> {
+    targets
+  }
+
+  /// Returns the number of threads that rustc should use when running the plugin,
+  /// i.e. the value of `-Z threads`. If `None`, rustc runs single-threaded.
+  ///
+  /// With multiple threads, queries (including any overridden by the plugin) may run on
+  /// any of rustc's worker threads, so the plugin's state must be thread-safe. For example,
+  /// [`rustc_utils`](https://docs.rs/rustc_utils) uses a `SyncCache` to share MIR bodies.
+  fn compiler_threads(&self, _args: &Self::Args) -> Option<usize> {
+    None
+  }
+
+  /// Returns the Cargo subcommand that compiles crates, `cargo check` by default.
+  ///
+  /// This determines which crates are compiled, e.g. whether `cfg(test)` code is included,
+  /// and the dependency graph that the plugin sees.
+  fn cargo_subcommand(&self, _args: &Self::Args) -> CargoSubcommand {
+    CargoSubcommand::Check
+  }
+
+  /// Returns the Cargo profile to compile crates with, e.g. `release`.
+  ///
+  /// If `None`, Cargo uses the default profile of the subcommand.
+  fn cargo_profile(&self, _args: &Self::Args) -> Option<String> {
+    None
+  }
+
   /// Optionally modify the `cargo` command that launches rustc.
   /// For example, you could pass a `--feature` flag here.
   fn modify_cargo(&self, _cargo: &mut Command, _args: &Self::Args) {}
@@ -55,9 +279,32 @@ pub trait RustcPlugin: Sized {
     self,
     compiler_args: Vec<String>,
     plugin_args: Self::Args,
-  ) -> rustc_interface::interface::Result<()>;
+  ) -> rustc_interface::interface::Result<Self::Output>;
+
+  /// Optionally process the outputs of every crate once Cargo has finished.
+  ///
+  /// This is called in the CLI, not the driver. Outputs are ordered by crate name.
+  /// If compilation failed for some crates, then only the outputs of the crates
+  /// that ran successfully are provided.
+  ///
+  /// An error is returned from [`try_cli_main`](crate::try_cli_main).
+  fn finalize(
+    &self,
+    _outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    Ok(())
+  }
 }
 
 /// The name of the environment variable shared between the CLI and the driver.
 /// Must not conflict with any other env var used by Cargo.
 pub const PLUGIN_ARGS: &str = "PLUGIN_ARGS";
+
+/// The name of the environment variable containing the path to a file with the plugin's
+/// args, used instead of [`PLUGIN_ARGS`] when the args are large or binary.
+pub const PLUGIN_ARGS_FILE: &str = "PLUGIN_ARGS_FILE";
+
+/// The name of the environment variable containing the directory where the driver
+/// writes each crate's [`RustcPlugin::Output`] for the CLI to collect.
+pub const PLUGIN_OUTPUT_DIR: &str = "PLUGIN_OUTPUT_DIR";
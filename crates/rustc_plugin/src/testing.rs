@@ -0,0 +1,210 @@
+//! Utilities for end-to-end tests of plugins, enabled by the `testing` feature.
+//!
+//! A test builds the plugin with [`TestPlugin::build`], creates a [`Workspace`] to run it
+//! on, and checks the [`RunOutput`]:
+//!
+//! ```ignore
+//! let plugin = TestPlugin::build("examples/print-all-items", "print-all-items")?;
+//! let ws = Workspace::from_files([
+//!   ("Cargo.toml", "[package]\nname = \"example\"\nedition = \"2024\""),
+//!   ("src/lib.rs", "pub fn add() {}"),
+//! ])?;
+//! let output = plugin.run(&ws, |cmd| { cmd.arg("-a"); })?;
+//! assert!(output.status.success());
+//! ```
+//!
+//! Every workspace is a fresh temporary directory with its own target directory, so tests
+//! can run in parallel.
+
+use std::{
+  collections::HashMap,
+  env, fs, io,
+  path::{Path, PathBuf},
+  process::{Command, ExitStatus},
+  sync::{
+    LazyLock, Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+};
+
+/// A plugin whose binaries have been built for testing.
+#[derive(Debug, Clone)]
+pub struct TestPlugin {
+  cli: PathBuf,
+  subcommand: String,
+}
+
+impl TestPlugin {
+  /// Builds the plugin package in `dir`, whose CLI binary is `cargo-<subcommand>`.
+  ///
+  /// Each package is built at most once per process, so this is cheap to call from
+  /// every test.
+  pub fn build(dir: impl AsRef<Path>, subcommand: &str) -> io::Result<Self> {
+    static BUILT: LazyLock<Mutex<HashMap<PathBuf, PathBuf>>> =
+      LazyLock::new(Mutex::default);
+
+    let dir = dir.as_ref().canonicalize()?;
+    // The lock is held while building, so concurrent tests wait for a single build.
+    let mut built = BUILT.lock().unwrap_or_else(|e| e.into_inner());
+    let bin_dir = match built.get(&dir) {
+      Some(bin_dir) => bin_dir.clone(),
+      None => {
+        let bin_dir = build_package(&dir)?;
+        built.insert(dir, bin_dir.clone());
+        bin_dir
+      }
+    };
+
+    let mut cli = bin_dir.join(format!("cargo-{subcommand}"));
+    if cfg!(windows) {
+      cli.set_extension("exe");
+    }
+    Ok(TestPlugin {
+      cli,
+      subcommand: subcommand.to_string(),
+    })
+  }
+
+  /// Returns the path of another binary of the plugin package, such as a language
+  /// server.
+  pub fn bin(&self, name: &str) -> PathBuf {
+    let mut path = self.cli.with_file_name(name);
+    if cfg!(windows) {
+      path.set_extension("exe");
+    }
+    path
+  }
+
+  /// Runs the plugin on `workspace`, like `cargo <subcommand>` in its root.
+  ///
+  /// `f` can add arguments or environment variables to the command.
+  pub fn run(
+    &self,
+    workspace: &Workspace,
+    f: impl FnOnce(&mut Command),
+  ) -> io::Result<RunOutput> {
+    let mut cmd = Command::new(&self.cli);
+    // Cargo passes the name of the subcommand as the first argument.
+    cmd
+      .arg(&self.subcommand)
+      .current_dir(&workspace.root)
+      .env("CARGO_TARGET_DIR", workspace.root.join("target"));
+    f(&mut cmd);
+
+    let output = cmd.output()?;
+    Ok(RunOutput {
+      stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+      stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+      status: output.status,
+    })
+  }
+}
+
+/// Builds the binaries of the package in `dir` and returns the directory containing them.
+fn build_package(dir: &Path) -> io::Result<PathBuf> {
+  // Building from the package's directory picks up its `rust-toolchain.toml`.
+  let status = Command::new("cargo")
+    .args(["build", "--bins"])
+    .current_dir(dir)
+    .status()?;
+  if !status.success() {
+    return Err(io::Error::other(format!(
+      "failed to build plugin in {}",
+      dir.display()
+    )));
+  }
+
+  let metadata = cargo_metadata::MetadataCommand::new()
+    .current_dir(dir)
+    .no_deps()
+    .exec()
+    .map_err(io::Error::other)?;
+  Ok(metadata.target_directory.join("debug").into_std_path_buf())
+}
+
+/// The result of running a plugin with [`TestPlugin::run`].
+#[derive(Debug)]
+pub struct RunOutput {
+  pub stdout: String,
+  pub stderr: String,
+  pub status: ExitStatus,
+}
+
+/// A temporary Cargo workspace, which is deleted when dropped.
+#[derive(Debug)]
+pub struct Workspace {
+  root: PathBuf,
+}
+
+impl Workspace {
+  /// Creates a workspace containing the given files, as pairs of relative paths and
+  /// contents, e.g. `("src/lib.rs", "pub fn f() {}")`.
+  pub fn from_files<P: AsRef<Path>, S: AsRef<str>>(
+    files: impl IntoIterator<Item = (P, S)>,
+  ) -> io::Result<Self> {
+    let ws = Workspace::empty()?;
+    for (path, contents) in files {
+      ws.write(path, contents)?;
+    }
+    Ok(ws)
+  }
+
+  /// Creates a workspace with a copy of the files in `dir`, except its target directory.
+  pub fn copy_from(dir: impl AsRef<Path>) -> io::Result<Self> {
+    let ws = Workspace::empty()?;
+    copy_dir(dir.as_ref(), &ws.root)?;
+    Ok(ws)
+  }
+
+  fn empty() -> io::Result<Self> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let root = env::temp_dir().join(format!(
+      "rustc_plugin-test-{}-{}",
+      std::process::id(),
+      COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root)?;
+    Ok(Workspace { root })
+  }
+
+  /// Returns the root directory of the workspace.
+  pub fn path(&self) -> &Path {
+    &self.root
+  }
+
+  /// Writes a file in the workspace, creating its parent directories if needed.
+  pub fn write(
+    &self,
+    path: impl AsRef<Path>,
+    contents: impl AsRef<str>,
+  ) -> io::Result<()> {
+    let path = self.root.join(path);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents.as_ref())
+  }
+}
+
+impl Drop for Workspace {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.root);
+  }
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+  fs::create_dir_all(dst)?;
+  for entry in fs::read_dir(src)? {
+    let entry = entry?;
+    let path = entry.path();
+    if entry.file_type()?.is_dir() {
+      if entry.file_name() != "target" {
+        copy_dir(&path, &dst.join(entry.file_name()))?;
+      }
+    } else {
+      fs::copy(&path, dst.join(entry.file_name()))?;
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,44 @@
+#![feature(rustc_private)]
+
+use anyhow::{Result, ensure};
+use rustc_plugin::testing::{TestPlugin, Workspace};
+
+#[test]
+fn cached_rerun() -> Result<()> {
+  let plugin = TestPlugin::build("tests/plugins", "cache-test")?;
+  let ws = Workspace::from_files([
+    (
+      "Cargo.toml",
+      "[package]\nname = \"cached\"\nversion = \"0.1.0\"\nedition = \"2024\"\n",
+    ),
+    ("src/lib.rs", "pub fn before() {}"),
+  ])?;
+  let run = || {
+    let output = plugin.run(&ws, |_cmd| {})?;
+    ensure!(output.status.success(), "stderr:\n{}", output.stderr);
+    Ok(output.stdout)
+  };
+
+  let output = run()?;
+  assert!(
+    output.contains("Analyzed cached") && output.contains("before"),
+    "output:\n{output}"
+  );
+
+  // The crate is fresh, so its output is replayed without running the driver
+  let output = run()?;
+  assert!(
+    !output.contains("Analyzed") && output.contains("before"),
+    "output:\n{output}"
+  );
+
+  ws.write("src/lib.rs", "pub fn after() {}\npub fn other() {}")?;
+  let output = run()?;
+  assert!(
+    output.contains("Analyzed cached")
+      && output.contains("after, other")
+      && !output.contains("before"),
+    "output:\n{output}"
+  );
+  Ok(())
+}
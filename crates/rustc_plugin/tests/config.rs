@@ -0,0 +1,61 @@
+#![feature(rustc_private)]
+
+use std::process::Command;
+
+use anyhow::{Result, ensure};
+use rustc_plugin::testing::{RunOutput, TestPlugin, Workspace};
+
+fn run_raw(ws: &Workspace, f: impl FnOnce(&mut Command)) -> Result<RunOutput> {
+  let plugin = TestPlugin::build("tests/plugins", "config-test")?;
+  Ok(plugin.run(ws, f)?)
+}
+
+fn run(f: impl FnOnce(&mut Command)) -> Result<String> {
+  let ws = Workspace::copy_from("tests/workspaces/config")?;
+  let output = run_raw(&ws, f)?;
+  ensure!(output.status.success(), "stderr:\n{}", output.stderr);
+  Ok(output.stdout)
+}
+
+#[test]
+fn layers() -> Result<()> {
+  // `a` overrides the workspace's config file with its package metadata, and the
+  // defaults of the CLI don't override either
+  let output = run(|_cmd| {})?;
+  assert!(
+    output.contains("a: uppercase=false prefix=workspace")
+      && output.contains("b: uppercase=true prefix=workspace"),
+    "output:\n{output}"
+  );
+
+  // Args passed on the CLI override every config
+  let output = run(|cmd| {
+    cmd.args(["--uppercase", "--prefix", "cli"]);
+  })?;
+  assert!(
+    output.contains("a: uppercase=true prefix=cli")
+      && output.contains("b: uppercase=true prefix=cli"),
+    "output:\n{output}"
+  );
+  Ok(())
+}
+
+#[test]
+fn invalid() -> Result<()> {
+  let ws = Workspace::from_files([
+    (
+      "Cargo.toml",
+      "[package]\nname = \"invalid\"\nversion = \"0.1.0\"\nedition = \"2024\"\n",
+    ),
+    ("src/lib.rs", ""),
+    ("config-test.toml", "uppercase = \"yes\""),
+  ])?;
+  let output = run_raw(&ws, |_cmd| {})?;
+  let stderr = output.stderr;
+  assert_eq!(output.status.code(), Some(78), "stderr:\n{stderr}");
+  assert!(
+    stderr.contains("invalid plugin configuration in workspace"),
+    "stderr:\n{stderr}"
+  );
+  Ok(())
+}
@@ -0,0 +1,135 @@
+#![feature(rustc_private)]
+
+use std::{path::Path, process::Command};
+
+use anyhow::{Result, ensure};
+use rustc_plugin::testing::{RunOutput, TestPlugin, Workspace};
+
+fn run_raw(dir: &str, f: impl FnOnce(&mut Command)) -> Result<RunOutput> {
+  let plugin = TestPlugin::build("tests/plugins", "filter-test")?;
+  let ws = Workspace::copy_from(Path::new("tests/workspaces").join(dir))?;
+  Ok(plugin.run(&ws, f)?)
+}
+
+fn run(dir: &str, f: impl FnOnce(&mut Command)) -> Result<String> {
+  let output = run_raw(dir, f)?;
+  ensure!(output.status.success(), "stderr:\n{}", output.stderr);
+  Ok(output.stdout)
+}
+
+/// Returns the crates whose items include `item`, once for each time the plugin ran on
+/// them.
+fn crates_with<'a>(output: &'a str, item: &str) -> Vec<&'a str> {
+  output
+    .lines()
+    .filter_map(|line| {
+      let (krate, items) = line.split_once(": ")?;
+      items
+        .split(", ")
+        .any(|other| other == item)
+        .then_some(krate)
+    })
+    .collect()
+}
+
+#[test]
+fn packages() -> Result<()> {
+  let output = run("multi", |cmd| {
+    cmd.args(["--package", "b"]);
+  })?;
+  assert_eq!(crates_with(&output, "add"), ["b"], "output:\n{output}");
+  Ok(())
+}
+
+#[test]
+fn no_matching_packages() -> Result<()> {
+  let output = run_raw("multi", |cmd| {
+    cmd.args(["--package", "does-not-exist"]);
+  })?;
+  let stderr = output.stderr;
+  assert_eq!(output.status.code(), Some(68), "stderr:\n{stderr}");
+  assert!(
+    stderr.contains("error: no packages matched the crate filter"),
+    "stderr:\n{stderr}"
+  );
+  Ok(())
+}
+
+#[test]
+fn large_args() -> Result<()> {
+  // The serialized args are larger than the limit on the size of an environment variable
+  let output = run("multi", |cmd| {
+    for _ in 0 .. 40_000 {
+      cmd.args(["--package", "b"]);
+    }
+  })?;
+  assert_eq!(crates_with(&output, "add"), ["b"], "output:\n{output}");
+  Ok(())
+}
+
+#[test]
+fn file() -> Result<()> {
+  // A module of both the lib and the bin runs on both, and their unit tests
+  let output = run("shared", |cmd| {
+    cmd.args(["--file", "src/util.rs"]);
+  })?;
+  assert_eq!(output.lines().count(), 4, "output:\n{output}");
+  assert_eq!(crates_with(&output, "helper").len(), 4, "output:\n{output}");
+
+  // A module of only the lib runs on the lib and its unit tests
+  let output = run("shared", |cmd| {
+    cmd.args(["--file", "src/lib_only.rs"]);
+  })?;
+  assert_eq!(output.lines().count(), 2, "output:\n{output}");
+  assert_eq!(
+    crates_with(&output, "lib_helper").len(),
+    2,
+    "output:\n{output}"
+  );
+
+  let output = run_raw("shared", |cmd| {
+    cmd.args(["--file", "src/unused.rs"]);
+  })?;
+  let stderr = output.stderr;
+  assert_eq!(output.status.code(), Some(66), "stderr:\n{stderr}");
+  assert!(
+    stderr.contains("is not included by any target"),
+    "stderr:\n{stderr}"
+  );
+  Ok(())
+}
+
+#[test]
+fn target_kinds() -> Result<()> {
+  // `check --tests` already selects the test targets, so the filter must not repeat it
+  let output = run("basic", |cmd| {
+    cmd.args(["--kind", "test", "--subcommand", "check-tests"]);
+  })?;
+  assert!(
+    !crates_with(&output, "integration").is_empty()
+      && crates_with(&output, "add").is_empty(),
+    "output:\n{output}"
+  );
+  Ok(())
+}
+
+#[test]
+fn subcommand() -> Result<()> {
+  let output = run("basic", |cmd| {
+    cmd.args(["--subcommand", "check-tests"]);
+  })?;
+  assert!(
+    !crates_with(&output, "it_works").is_empty(),
+    "output:\n{output}"
+  );
+
+  let output = run("basic", |cmd| {
+    cmd.args(["--subcommand", "build", "--profile", "release"]);
+  })?;
+  assert!(
+    !crates_with(&output, "add").is_empty()
+      && crates_with(&output, "it_works").is_empty(),
+    "output:\n{output}"
+  );
+  Ok(())
+}
@@ -0,0 +1,101 @@
+#![feature(rustc_private)]
+
+use std::{
+  io::{BufReader, Write},
+  process::{ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use anyhow::{Result, bail};
+use lsp_server::{ErrorCode, Message, Notification, Request, Response};
+use rustc_plugin::testing::{TestPlugin, Workspace};
+use serde_json::{Value, json};
+
+struct LspClient {
+  stdin: ChildStdin,
+  stdout: BufReader<ChildStdout>,
+}
+
+impl LspClient {
+  fn notify(&mut self, method: &str, params: Value) -> Result<()> {
+    Message::Notification(Notification::new(method.into(), params))
+      .write(&mut self.stdin)?;
+    Ok(self.stdin.flush()?)
+  }
+
+  fn request(&mut self, id: i32, method: &str, params: Value) -> Result<Response> {
+    Message::Request(Request::new(id.into(), method.into(), params))
+      .write(&mut self.stdin)?;
+    self.stdin.flush()?;
+    loop {
+      match Message::read(&mut self.stdout)? {
+        Some(Message::Response(response)) => return Ok(response),
+        Some(_) => {}
+        None => bail!("the server exited before responding to {method}"),
+      }
+    }
+  }
+}
+
+#[test]
+fn hover() -> Result<()> {
+  let plugin = TestPlugin::build("tests/plugins", "lsp-test")?;
+  let ws = Workspace::copy_from("tests/workspaces/basic")?;
+  let root = ws.path().canonicalize()?;
+  let mut child = Command::new(plugin.bin("lsp-test-server"))
+    .env("CARGO_TARGET_DIR", root.join("target"))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()?;
+  let mut client = LspClient {
+    stdin: child.stdin.take().unwrap(),
+    stdout: BufReader::new(child.stdout.take().unwrap()),
+  };
+
+  let root_uri = format!("file://{}", root.display());
+  let response = client.request(
+    1,
+    "initialize",
+    json!({
+      "capabilities": {},
+      "rootUri": root_uri,
+    }),
+  )?;
+  assert_eq!(
+    response.result.unwrap()["capabilities"]["hoverProvider"],
+    true
+  );
+  client.notify("initialized", json!({}))?;
+
+  // The server runs the plugin on the crates containing the document
+  let params = json!({
+    "textDocument": { "uri": format!("{root_uri}/src/lib.rs") },
+    "position": { "line": 0, "character": 7 },
+  });
+  let response = client.request(2, "textDocument/hover", params.clone())?;
+  assert!(response.error.is_none(), "{response:?}");
+  // The lib and its unit test harness both contain the document
+  let contents = response.result.unwrap()["contents"].clone();
+  let crates = contents
+    .as_str()
+    .unwrap_or_default()
+    .lines()
+    .collect::<Vec<_>>();
+  assert!(
+    crates.len() == 2
+      && crates.iter().all(|krate| krate.starts_with("basic: "))
+      && crates.iter().any(|krate| krate.contains("it_works")),
+    "{contents}"
+  );
+
+  let response = client.request(3, "textDocument/definition", params)?;
+  assert_eq!(
+    response.error.map(|e| e.code),
+    Some(ErrorCode::MethodNotFound as i32)
+  );
+
+  client.request(4, "shutdown", Value::Null)?;
+  client.notify("exit", Value::Null)?;
+  assert!(child.wait()?.success());
+  Ok(())
+}
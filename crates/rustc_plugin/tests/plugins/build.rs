@@ -0,0 +1,5 @@
+#![feature(rustc_private)]
+
+fn main() {
+  rustc_plugin::build_main();
+}
@@ -0,0 +1,58 @@
+//! A plugin that caches its outputs. The driver prints the crates it analyzes, so a
+//! replayed output can be told apart from a fresh one.
+
+use std::borrow::Cow;
+
+use rustc_plugin::{
+  CrateFilter, RustcPlugin, RustcPluginArgs, RustcPluginError, Utf8Path,
+};
+
+use crate::CrateItems;
+
+pub struct CachePlugin;
+
+impl RustcPlugin for CachePlugin {
+  type Args = ();
+  type Output = CrateItems;
+
+  fn version(&self) -> Cow<'static, str> {
+    env!("CARGO_PKG_VERSION").into()
+  }
+
+  fn driver_name(&self) -> Cow<'static, str> {
+    "cache-test-driver".into()
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
+    RustcPluginArgs {
+      args: (),
+      filter: CrateFilter::OnlyWorkspace,
+      explicit_fields: None,
+    }
+  }
+
+  fn cache_outputs(&self) -> bool {
+    true
+  }
+
+  fn run(
+    self,
+    compiler_args: Vec<String>,
+    _plugin_args: Self::Args,
+  ) -> rustc_interface::interface::Result<Self::Output> {
+    let output = crate::run_compiler(&compiler_args)?;
+    println!("Analyzed {}", output.krate);
+    Ok(output)
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    for output in outputs {
+      println!("{output}");
+    }
+    Ok(())
+  }
+}
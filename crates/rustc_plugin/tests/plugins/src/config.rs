@@ -0,0 +1,78 @@
+//! A plugin with a configuration. It prints the args that the driver received for each
+//! crate.
+
+use std::{borrow::Cow, env};
+
+use clap::{CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use rustc_plugin::{
+  CrateFilter, RustcPlugin, RustcPluginArgs, RustcPluginError, Utf8Path,
+};
+use serde::{Deserialize, Serialize};
+
+pub struct ConfigPlugin;
+
+#[derive(Parser, Serialize, Deserialize)]
+pub struct ConfigArgs {
+  #[arg(long)]
+  uppercase: bool,
+
+  #[arg(long, default_value = "item")]
+  prefix: String,
+}
+
+impl RustcPlugin for ConfigPlugin {
+  type Args = ConfigArgs;
+
+  // The crate's name and the args it was compiled with.
+  type Output = (String, ConfigArgs);
+
+  fn version(&self) -> Cow<'static, str> {
+    env!("CARGO_PKG_VERSION").into()
+  }
+
+  fn driver_name(&self) -> Cow<'static, str> {
+    "config-test-driver".into()
+  }
+
+  fn config_name(&self) -> Option<Cow<'static, str>> {
+    Some("config-test".into())
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
+    let matches = ConfigArgs::command().get_matches_from(env::args().skip(1));
+    let args = ConfigArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let explicit_fields = matches
+      .ids()
+      .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+      .map(ToString::to_string)
+      .collect();
+    RustcPluginArgs {
+      args,
+      filter: CrateFilter::OnlyWorkspace,
+      explicit_fields: Some(explicit_fields),
+    }
+  }
+
+  fn run(
+    self,
+    compiler_args: Vec<String>,
+    plugin_args: Self::Args,
+  ) -> rustc_interface::interface::Result<Self::Output> {
+    let output = crate::run_compiler(&compiler_args)?;
+    Ok((output.krate, plugin_args))
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    for (krate, args) in outputs {
+      println!(
+        "{krate}: uppercase={} prefix={}",
+        args.uppercase, args.prefix
+      );
+    }
+    Ok(())
+  }
+}
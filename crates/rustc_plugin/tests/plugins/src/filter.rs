@@ -0,0 +1,107 @@
+//! A plugin whose crate filter, Cargo subcommand and profile are given on the command
+//! line. It prints the items of every crate it ran on.
+
+use std::{borrow::Cow, env, path::PathBuf};
+
+use clap::Parser;
+use rustc_plugin::{
+  CargoSubcommand, CrateFilter, RustcPlugin, RustcPluginArgs, RustcPluginError,
+  TargetKind, Utf8Path,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::CrateItems;
+
+pub struct FilterPlugin;
+
+#[derive(Parser, Serialize, Deserialize)]
+pub struct FilterArgs {
+  #[arg(long)]
+  package: Vec<String>,
+
+  #[arg(long)]
+  file: Option<PathBuf>,
+
+  #[arg(long, value_parser = ["lib", "bin", "example", "test", "bench"])]
+  kind: Vec<String>,
+
+  #[arg(long, value_parser = ["check", "check-tests", "build", "test"])]
+  subcommand: Option<String>,
+
+  #[arg(long)]
+  profile: Option<String>,
+}
+
+impl RustcPlugin for FilterPlugin {
+  type Args = FilterArgs;
+  type Output = CrateItems;
+
+  fn version(&self) -> Cow<'static, str> {
+    env!("CARGO_PKG_VERSION").into()
+  }
+
+  fn driver_name(&self) -> Cow<'static, str> {
+    "filter-test-driver".into()
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
+    let args = FilterArgs::parse_from(env::args().skip(1));
+    let filter = if let Some(file) = &args.file {
+      CrateFilter::CrateContainingFile(file.clone())
+    } else if !args.package.is_empty() {
+      CrateFilter::Packages(args.package.clone())
+    } else if !args.kind.is_empty() {
+      let kinds = args
+        .kind
+        .iter()
+        .map(|kind| match kind.as_str() {
+          "lib" => TargetKind::Lib,
+          "bin" => TargetKind::Bin,
+          "example" => TargetKind::Example,
+          "test" => TargetKind::Test,
+          _ => TargetKind::Bench,
+        })
+        .collect();
+      CrateFilter::TargetKinds(kinds)
+    } else {
+      CrateFilter::AllCrates
+    };
+    RustcPluginArgs {
+      args,
+      filter,
+      explicit_fields: None,
+    }
+  }
+
+  fn cargo_subcommand(&self, args: &Self::Args) -> CargoSubcommand {
+    match args.subcommand.as_deref() {
+      Some("check-tests") => CargoSubcommand::CheckTests,
+      Some("build") => CargoSubcommand::Build,
+      Some("test") => CargoSubcommand::TestNoRun,
+      _ => CargoSubcommand::Check,
+    }
+  }
+
+  fn cargo_profile(&self, args: &Self::Args) -> Option<String> {
+    args.profile.clone()
+  }
+
+  fn run(
+    self,
+    compiler_args: Vec<String>,
+    _plugin_args: Self::Args,
+  ) -> rustc_interface::interface::Result<Self::Output> {
+    crate::run_compiler(&compiler_args)
+  }
+
+  fn finalize(
+    &self,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Result<(), RustcPluginError> {
+    for output in outputs {
+      println!("{output}");
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,77 @@
+//! Small plugins for the end-to-end tests of rustc_plugin, each of which exercises one
+//! part of the framework.
+
+#![feature(rustc_private)]
+
+extern crate rustc_driver;
+extern crate rustc_hir;
+extern crate rustc_interface;
+extern crate rustc_middle;
+
+pub mod cache;
+pub mod config;
+pub mod filter;
+pub mod lsp;
+
+use std::fmt;
+
+use rustc_hir::def_id::LOCAL_CRATE;
+use rustc_middle::ty::TyCtxt;
+use serde::{Deserialize, Serialize};
+
+/// The names of the items in a crate, which the plugins use as their output.
+#[derive(Serialize, Deserialize)]
+pub struct CrateItems {
+  pub krate: String,
+  pub items: Vec<String>,
+}
+
+impl CrateItems {
+  fn collect(tcx: TyCtxt<'_>) -> Self {
+    let items = tcx
+      .hir_free_items()
+      .filter_map(|item| tcx.opt_item_name(item.owner_id.to_def_id()))
+      .map(|name| name.to_string())
+      .collect();
+    CrateItems {
+      krate: tcx.crate_name(LOCAL_CRATE).to_string(),
+      items,
+    }
+  }
+}
+
+// Printed as e.g. `basic: add, sub`.
+impl fmt::Display for CrateItems {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.krate, self.items.join(", "))
+  }
+}
+
+/// Callbacks that collect the items of the crate once it has been analyzed.
+#[derive(Default)]
+struct ItemsCallbacks {
+  items: Option<CrateItems>,
+}
+
+impl rustc_driver::Callbacks for ItemsCallbacks {
+  fn after_analysis(
+    &mut self,
+    _compiler: &rustc_interface::interface::Compiler,
+    tcx: TyCtxt<'_>,
+  ) -> rustc_driver::Compilation {
+    self.items = Some(CrateItems::collect(tcx));
+    rustc_driver::Compilation::Continue
+  }
+}
+
+/// Compiles a crate and returns its items.
+fn run_compiler(
+  compiler_args: &[String],
+) -> rustc_interface::interface::Result<CrateItems> {
+  let mut callbacks = ItemsCallbacks::default();
+  rustc_driver::run_compiler(compiler_args, &mut callbacks);
+  Ok(callbacks.items.unwrap_or_else(|| CrateItems {
+    krate: String::new(),
+    items: Vec::new(),
+  }))
+}
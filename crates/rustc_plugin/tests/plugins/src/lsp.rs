@@ -0,0 +1,79 @@
+//! A plugin whose language server answers hover requests with the items of the crates
+//! containing the hovered file.
+
+use std::{borrow::Cow, path::Path};
+
+use rustc_plugin::{
+  CrateFilter, RustcPlugin, RustcPluginArgs, Utf8Path,
+  lsp_types::{
+    Hover, HoverContents, HoverProviderCapability, MarkedString, ServerCapabilities,
+    request::{HoverRequest, Request},
+  },
+};
+use serde_json::Value;
+
+use crate::CrateItems;
+
+pub struct LspPlugin;
+
+impl RustcPlugin for LspPlugin {
+  type Args = ();
+  type Output = CrateItems;
+
+  fn version(&self) -> Cow<'static, str> {
+    env!("CARGO_PKG_VERSION").into()
+  }
+
+  fn driver_name(&self) -> Cow<'static, str> {
+    "lsp-test-driver".into()
+  }
+
+  fn args(&self, _target_dir: &Utf8Path) -> RustcPluginArgs<Self::Args> {
+    RustcPluginArgs {
+      args: (),
+      filter: CrateFilter::OnlyWorkspace,
+      explicit_fields: None,
+    }
+  }
+
+  fn run(
+    self,
+    compiler_args: Vec<String>,
+    _plugin_args: Self::Args,
+  ) -> rustc_interface::interface::Result<Self::Output> {
+    crate::run_compiler(&compiler_args)
+  }
+}
+
+impl rustc_plugin::LspPlugin for LspPlugin {
+  fn capabilities(&self) -> ServerCapabilities {
+    ServerCapabilities {
+      hover_provider: Some(HoverProviderCapability::Simple(true)),
+      ..Default::default()
+    }
+  }
+
+  fn request_args(
+    &self,
+    method: &str,
+    _file: &Path,
+    _params: &Value,
+  ) -> Option<Self::Args> {
+    (method == HoverRequest::METHOD).then_some(())
+  }
+
+  // Each crate is on its own line.
+  fn response(
+    &self,
+    _method: &str,
+    outputs: Vec<Self::Output>,
+    _args: &Self::Args,
+  ) -> Value {
+    let crates = outputs.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let hover = Hover {
+      contents: HoverContents::Scalar(MarkedString::String(crates.join("\n"))),
+      range: None,
+    };
+    serde_json::to_value(hover).unwrap()
+  }
+}
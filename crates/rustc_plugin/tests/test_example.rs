@@ -1,61 +1,29 @@
-use std::{env, fs, path::Path, process::Command, sync::Once};
+#![feature(rustc_private)]
 
-use anyhow::{Context, Result, ensure};
+use std::{path::Path, process::Command};
 
-static SETUP: Once = Once::new();
+use anyhow::{Result, ensure};
+use rustc_plugin::testing::{TestPlugin, Workspace};
 
-fn run(dir: &str, f: impl FnOnce(&mut Command)) -> Result<String> {
-  let root = env::temp_dir().join("rustc_plugin");
-
-  let heredir = Path::new(".").canonicalize()?;
-
-  SETUP.call_once(|| {
-    let mut cmd = Command::new("cargo");
-    cmd.args([
-      "install",
-      "--path",
-      "examples/print-all-items",
-      "--debug",
-      "--locked",
-      "--root",
-    ]);
-    cmd.arg(&root);
-    cmd.current_dir(&heredir);
-    let status = cmd.status().unwrap();
-    if !status.success() {
-      panic!("installing example failed")
-    }
-  });
-
-  let mut cmd = Command::new("cargo");
-  cmd.arg("print-all-items");
-
-  let path = format!(
-    "{}:{}",
-    root.join("bin").display(),
-    env::var("PATH").unwrap_or_else(|_| "".into())
-  );
-  cmd.env("PATH", path);
-
-  let ws = heredir.join("tests").join(dir);
-  cmd.current_dir(&ws);
-
-  f(&mut cmd);
-
-  let _ = fs::remove_dir_all(ws.join("target"));
+fn print_all_items() -> Result<TestPlugin> {
+  Ok(TestPlugin::build(
+    "examples/print-all-items",
+    "print-all-items",
+  )?)
+}
 
-  let output = cmd.output().context("Process failed")?;
+fn run(dir: &str, f: impl FnOnce(&mut Command)) -> Result<String> {
+  let ws = Workspace::copy_from(Path::new("tests").join(dir))?;
+  let output = print_all_items()?.run(&ws, f)?;
   ensure!(
     output.status.success(),
     "Process exited with non-zero exit code. Stderr:\n{}",
-    String::from_utf8(output.stderr)?
+    output.stderr
   );
 
-  Ok(String::from_utf8(output.stdout)?)
+  Ok(output.stdout)
 }
 
-// TODO: why do these tests need to be run sequentially?
-
 #[test]
 fn basic() -> Result<()> {
   let output = run("workspaces/basic", |_cmd| {})?;
@@ -89,3 +57,64 @@ fn multi() -> Result<()> {
   run("workspaces/multi", |_cmd| {})?;
   Ok(())
 }
+
+#[test]
+fn output() -> Result<()> {
+  let output = run("workspaces/multi", |_cmd| {})?;
+  assert!(
+    output.contains("Found 6 items in total"),
+    "output:\n{output}"
+  );
+  Ok(())
+}
+
+#[test]
+fn plugin_set() -> Result<()> {
+  // Each plugin in the set gets its own args and outputs
+  let plugin = TestPlugin::build("examples/plugin-set", "plugin-set")?;
+  let ws = Workspace::copy_from("tests/workspaces/multi")?;
+  let output = plugin.run(&ws, |cmd| {
+    cmd.args(["--prefix", "ad"]);
+  })?;
+  ensure!(output.status.success(), "stderr:\n{}", output.stderr);
+  assert!(
+    output.stdout.contains("Found 6 items in total")
+      && output
+        .stdout
+        .contains(r#"Functions starting with "ad": add, add"#),
+    "output:\n{}",
+    output.stdout
+  );
+  Ok(())
+}
+
+#[test]
+fn rerun() -> Result<()> {
+  let plugin = print_all_items()?;
+  let ws = Workspace::from_files([
+    (
+      "Cargo.toml",
+      "[package]\nname = \"rerun\"\nversion = \"0.1.0\"\nedition = \"2024\"\n",
+    ),
+    ("src/lib.rs", "pub fn before() {}"),
+  ])?;
+
+  // The plugin runs again even though the crate is fresh
+  for _ in 0 .. 2 {
+    let output = plugin.run(&ws, |_cmd| {})?;
+    assert!(
+      output.stdout.contains(r#"There is an item "before""#),
+      "output:\n{}",
+      output.stdout
+    );
+  }
+
+  ws.write("src/lib.rs", "pub fn after() {}")?;
+  let output = plugin.run(&ws, |_cmd| {})?;
+  assert!(
+    output.stdout.contains(r#"There is an item "after""#),
+    "output:\n{}",
+    output.stdout
+  );
+  Ok(())
+}
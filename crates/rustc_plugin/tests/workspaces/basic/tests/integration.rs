@@ -0,0 +1,4 @@
+#[test]
+fn integration() {
+  assert_eq!(basic::add(1, 1), 2);
+}
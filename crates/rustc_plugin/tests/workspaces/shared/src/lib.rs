@@ -0,0 +1,7 @@
+mod lib_only;
+mod util;
+
+pub fn run() {
+  util::helper();
+  lib_only::lib_helper();
+}
@@ -9,6 +9,15 @@
 //!   (i.e. small) values.
 //! - [`Cache`] should be used for expensive computations that create expensive
 //!   (i.e. large) values.
+//! - [`SyncCache`] should be used instead of [`Cache`] when the cache is shared
+//!   between threads, e.g. by queries running on rustc's worker threads.
+//! - [`DiskCache`] should be used for results about definitions that are expensive
+//!   enough to be worth keeping across compiler runs. It requires the `serde` feature.
+//!
+//! A [`CopyCache`] can be bounded with [`CopyCache::with_capacity`], in which case it
+//! evicts the least recently used values. The other caches return references to their
+//! values, so values are only removed explicitly, e.g. with [`Cache::remove`]. Every
+//! cache records [`CacheStats`], which can be logged with [`crate::timer::cache_stats`].
 //!
 //! Both types of caches implement **recursion breaking**. In general because
 //! caches are supposed to be used as simple `&` (no `mut`) the reference may be
@@ -58,12 +67,91 @@
 //!     means running `compute(k)` should always return the same value
 //!     *independent of the state of it's environment*. Violation of this rule
 //!     can introduces non-determinism in your program.
-use std::{cell::RefCell, hash::Hash, pin::Pin};
+use std::{
+  cell::{Cell, RefCell},
+  collections::BTreeMap,
+  hash::{BuildHasher, Hash},
+  pin::Pin,
+  sync::{
+    Condvar, Mutex, MutexGuard, PoisonError,
+    atomic::{AtomicU64, Ordering},
+  },
+  thread::{self, ThreadId},
+  time::{Duration, Instant},
+};
+
+use rustc_data_structures::fx::{FxBuildHasher, FxHashMap as HashMap};
+
+#[cfg(feature = "serde")]
+pub use self::disk::DiskCache;
+
+/// Statistics about the use of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+  /// Number of lookups that found a value in the cache.
+  pub hits: u64,
+
+  /// Number of lookups that computed a value.
+  pub misses: u64,
+
+  /// Number of values evicted to keep a bounded cache within its capacity.
+  pub evictions: u64,
+
+  /// Number of values removed explicitly, e.g. with [`Cache::remove`] or [`Cache::clear`].
+  pub removals: u64,
+
+  /// Total time spent computing values. For recursive computations, the time of inner
+  /// computations is also included in that of the outer ones.
+  pub compute_time: Duration,
+}
+
+#[derive(Default)]
+struct StatsCounter {
+  hits: AtomicU64,
+  misses: AtomicU64,
+  evictions: AtomicU64,
+  removals: AtomicU64,
+  compute_nanos: AtomicU64,
+}
+
+impl StatsCounter {
+  fn hit(&self) {
+    self.hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn evict(&self) {
+    self.evictions.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn remove(&self, n: usize) {
+    self.removals.fetch_add(n as u64, Ordering::Relaxed);
+  }
+
+  fn compute<T>(&self, compute: impl FnOnce() -> T) -> T {
+    self.misses.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+    let out = compute();
+    let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    self.compute_nanos.fetch_add(nanos, Ordering::Relaxed);
+    out
+  }
 
-use rustc_data_structures::fx::FxHashMap as HashMap;
+  fn get(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      removals: self.removals.load(Ordering::Relaxed),
+      compute_time: Duration::from_nanos(self.compute_nanos.load(Ordering::Relaxed)),
+    }
+  }
+}
 
 /// Cache for non-copyable types.
-pub struct Cache<In, Out>(RefCell<HashMap<In, Option<Pin<Box<Out>>>>>);
+pub struct Cache<In, Out> {
+  map: RefCell<HashMap<In, Option<Pin<Box<Out>>>>>,
+  stats: StatsCounter,
+}
 
 impl<In, Out> Cache<In, Out>
 where
@@ -71,12 +159,33 @@ where
 {
   /// Size of the cache
   pub fn len(&self) -> usize {
-    self.0.borrow().len()
+    self.map.borrow().len()
   }
 
   /// Returns true if the cache contains the key.
   pub fn contains_key(&self, key: &In) -> bool {
-    self.0.borrow().contains_key(key)
+    self.map.borrow().contains_key(key)
+  }
+
+  /// Removes the value for the given key, returning true if it was in the cache.
+  pub fn remove(&mut self, key: &In) -> bool {
+    let removed = self.map.get_mut().remove(key).is_some();
+    if removed {
+      self.stats.remove(1);
+    }
+    removed
+  }
+
+  /// Removes every value from the cache.
+  pub fn clear(&mut self) {
+    let map = self.map.get_mut();
+    self.stats.remove(map.len());
+    map.clear();
+  }
+
+  /// Returns statistics about the use of the cache.
+  pub fn stats(&self) -> CacheStats {
+    self.stats.get()
   }
 
   /// Returns the cached value for the given key, or runs `compute` if
@@ -100,13 +209,18 @@ where
     key: &In,
     compute: impl FnOnce(In) -> Out,
   ) -> Option<&'a Out> {
-    if !self.0.borrow().contains_key(key) {
-      self.0.borrow_mut().insert(key.clone(), None);
-      let out = Box::pin(compute(key.clone()));
-      self.0.borrow_mut().insert(key.clone(), Some(out));
+    let cached = self.map.borrow().get(key).map(Option::is_some);
+    match cached {
+      Some(true) => self.stats.hit(),
+      Some(false) => {}
+      None => {
+        self.map.borrow_mut().insert(key.clone(), None);
+        let out = Box::pin(self.stats.compute(|| compute(key.clone())));
+        self.map.borrow_mut().insert(key.clone(), Some(out));
+      }
     }
 
-    let cache = self.0.borrow();
+    let cache = self.map.borrow();
     // Important here to first `unwrap` the `Option` created by `get`, then
     // propagate the potential option stored in the map.
     let entry = cache.get(key).expect("invariant broken").as_ref()?;
@@ -126,22 +240,255 @@ fn recursion_panic<A>() -> A {
 
 impl<In, Out> Default for Cache<In, Out> {
   fn default() -> Self {
-    Cache(RefCell::new(HashMap::default()))
+    Cache {
+      map: RefCell::new(HashMap::default()),
+      stats: StatsCounter::default(),
+    }
+  }
+}
+
+/// Thread-safe cache for non-copyable types.
+///
+/// Keys are distributed over independently locked shards, so threads only contend
+/// when they access keys in the same shard. While one thread computes the value for
+/// a key, other threads that request the same key wait for it to finish.
+///
+/// Recursion is detected per thread. If two threads each compute a key that requires
+/// the key being computed by the other, then they deadlock.
+pub struct SyncCache<In, Out> {
+  shards: Box<[SyncShard<In, Out>]>,
+  stats: StatsCounter,
+}
+
+struct SyncShard<In, Out> {
+  map: Mutex<HashMap<In, SyncEntry<Out>>>,
+  computed: Condvar,
+}
+
+enum SyncEntry<Out> {
+  InProgress(ThreadId),
+  Done(Pin<Box<Out>>),
+}
+
+const SYNC_CACHE_SHARDS: usize = 32;
+
+impl<In, Out> SyncShard<In, Out> {
+  fn lock(&self) -> MutexGuard<'_, HashMap<In, SyncEntry<Out>>> {
+    // A panic in `compute` never leaves the map in an inconsistent state.
+    self.map.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+impl<In, Out> SyncCache<In, Out>
+where
+  In: Hash + Eq + Clone,
+  Out: Sync,
+{
+  #[allow(clippy::cast_possible_truncation)]
+  fn shard(&self, key: &In) -> &SyncShard<In, Out> {
+    // Truncating the hash is fine for picking a shard
+    let hash = FxBuildHasher.hash_one(key);
+    &self.shards[(hash as usize) % self.shards.len()]
+  }
+
+  /// Size of the cache
+  pub fn len(&self) -> usize {
+    self
+      .shards
+      .iter()
+      .map(|shard| {
+        shard
+          .lock()
+          .values()
+          .filter(|entry| matches!(entry, SyncEntry::Done(_)))
+          .count()
+      })
+      .sum()
+  }
+
+  /// Returns true if the cache contains the key.
+  pub fn contains_key(&self, key: &In) -> bool {
+    matches!(self.shard(key).lock().get(key), Some(SyncEntry::Done(_)))
+  }
+
+  /// Removes the value for the given key, returning true if it was in the cache.
+  pub fn remove(&mut self, key: &In) -> bool {
+    let mut map = self.shard(key).lock();
+    // A value that is being computed is not in the cache yet.
+    if !matches!(map.get(key), Some(SyncEntry::Done(_))) {
+      return false;
+    }
+    map.remove(key);
+    self.stats.remove(1);
+    true
+  }
+
+  /// Removes every value from the cache.
+  pub fn clear(&mut self) {
+    for shard in &mut self.shards {
+      let map = shard.map.get_mut().unwrap_or_else(PoisonError::into_inner);
+      self.stats.remove(map.len());
+      map.clear();
+    }
+  }
+
+  /// Returns statistics about the use of the cache.
+  pub fn stats(&self) -> CacheStats {
+    self.stats.get()
+  }
+
+  /// Returns the cached value for the given key, or runs `compute` if
+  /// the value is not in cache.
+  ///
+  /// # Panics
+  ///
+  /// If this is a recursive invocation for this key.
+  pub fn get(&self, key: &In, compute: impl FnOnce(In) -> Out) -> &Out {
+    self
+      .get_maybe_recursive(key, compute)
+      .unwrap_or_else(recursion_panic)
+  }
+
+  /// Returns the cached value for the given key, or runs `compute` if
+  /// the value is not in cache.
+  ///
+  /// Returns `None` if this is a recursive invocation of `get` for key `key`
+  /// on the current thread.
+  pub fn get_maybe_recursive<'a>(
+    &'a self,
+    key: &In,
+    compute: impl FnOnce(In) -> Out,
+  ) -> Option<&'a Out> {
+    let shard = self.shard(key);
+    let current = thread::current().id();
+
+    let mut map = shard.lock();
+    loop {
+      match map.get(key) {
+        Some(SyncEntry::Done(entry)) => {
+          self.stats.hit();
+          // SAFETY: see `Cache::get_maybe_recursive`.
+          return Some(unsafe { std::mem::transmute::<&'_ Out, &'a Out>(&**entry) });
+        }
+        Some(SyncEntry::InProgress(thread)) if *thread == current => return None,
+        Some(SyncEntry::InProgress(_)) => {
+          map = shard
+            .computed
+            .wait(map)
+            .unwrap_or_else(PoisonError::into_inner);
+        }
+        None => break,
+      }
+    }
+    map.insert(key.clone(), SyncEntry::InProgress(current));
+    drop(map);
+
+    // If `compute` panics, other threads waiting on this key must not wait forever.
+    struct Abandon<'a, In: Hash + Eq, Out> {
+      shard: &'a SyncShard<In, Out>,
+      key: &'a In,
+    }
+    impl<In: Hash + Eq, Out> Drop for Abandon<'_, In, Out> {
+      fn drop(&mut self) {
+        self.shard.lock().remove(self.key);
+        self.shard.computed.notify_all();
+      }
+    }
+    let abandon = Abandon { shard, key };
+    let out = Box::pin(self.stats.compute(|| compute(key.clone())));
+    std::mem::forget(abandon);
+
+    // SAFETY: see `Cache::get_maybe_recursive`.
+    let out_ref = unsafe { std::mem::transmute::<&'_ Out, &'a Out>(&*out) };
+    shard.lock().insert(key.clone(), SyncEntry::Done(out));
+    shard.computed.notify_all();
+    Some(out_ref)
+  }
+}
+
+impl<In, Out> Default for SyncCache<In, Out> {
+  fn default() -> Self {
+    SyncCache {
+      shards: (0 .. SYNC_CACHE_SHARDS)
+        .map(|_| SyncShard {
+          map: Mutex::new(HashMap::default()),
+          computed: Condvar::new(),
+        })
+        .collect(),
+      stats: StatsCounter::default(),
+    }
   }
 }
 
 /// Cache for copyable types.
-pub struct CopyCache<In, Out>(RefCell<HashMap<In, Option<Out>>>);
+///
+/// A cache created with [`CopyCache::with_capacity`] holds at most that many values,
+/// and evicts the least recently used value when it is full.
+pub struct CopyCache<In, Out> {
+  map: RefCell<HashMap<In, CopyEntry<Out>>>,
+  /// For a bounded cache, the keys of computed values ordered by their last use, so
+  /// that the least recently used value is found in logarithmic time.
+  recency: RefCell<BTreeMap<u64, In>>,
+  capacity: Option<usize>,
+  clock: Cell<u64>,
+  stats: StatsCounter,
+}
+
+struct CopyEntry<Out> {
+  /// `None` while the value is being computed.
+  value: Option<Out>,
+  /// The time of the value's last use, under which its key is stored in `recency`.
+  last_used: Option<u64>,
+}
 
 impl<In, Out> CopyCache<In, Out>
 where
   In: Hash + Eq + Clone,
   Out: Copy,
 {
+  /// Creates a cache that holds at most `capacity` values.
+  ///
+  /// # Panics
+  ///
+  /// If `capacity` is zero.
+  pub fn with_capacity(capacity: usize) -> Self {
+    assert!(capacity > 0, "capacity must be positive");
+    CopyCache {
+      capacity: Some(capacity),
+      ..CopyCache::default()
+    }
+  }
+
   /// Size of the cache
   pub fn len(&self) -> usize {
-    self.0.borrow().len()
+    self.map.borrow().len()
+  }
+
+  /// Removes the value for the given key, returning true if it was in the cache.
+  pub fn remove(&self, key: &In) -> bool {
+    let Some(entry) = self.map.borrow_mut().remove(key) else {
+      return false;
+    };
+    if let Some(last_used) = entry.last_used {
+      self.recency.borrow_mut().remove(&last_used);
+    }
+    self.stats.remove(1);
+    true
   }
+
+  /// Removes every value from the cache.
+  pub fn clear(&self) {
+    let mut map = self.map.borrow_mut();
+    self.stats.remove(map.len());
+    map.clear();
+    self.recency.borrow_mut().clear();
+  }
+
+  /// Returns statistics about the use of the cache.
+  pub fn stats(&self) -> CacheStats {
+    self.stats.get()
+  }
+
   /// Returns the cached value for the given key, or runs `compute` if
   /// the value is not in cache.
   ///
@@ -163,19 +510,191 @@ where
     key: &In,
     compute: impl FnOnce(In) -> Out,
   ) -> Option<Out> {
-    if !self.0.borrow().contains_key(key) {
-      self.0.borrow_mut().insert(key.clone(), None);
-      let out = compute(key.clone());
-      self.0.borrow_mut().insert(key.clone(), Some(out));
+    let cached = self.map.borrow().get(key).map(|entry| entry.value);
+    match cached {
+      Some(Some(out)) => {
+        self.stats.hit();
+        self.touch(key);
+        Some(out)
+      }
+      Some(None) => None,
+      None => {
+        let entry = CopyEntry {
+          value: None,
+          last_used: None,
+        };
+        self.map.borrow_mut().insert(key.clone(), entry);
+        let out = self.stats.compute(|| compute(key.clone()));
+        let entry = CopyEntry {
+          value: Some(out),
+          last_used: None,
+        };
+        self.map.borrow_mut().insert(key.clone(), entry);
+        self.touch(key);
+        self.evict_to_capacity();
+        Some(out)
+      }
+    }
+  }
+
+  /// Marks the computed value for `key` as the most recently used one.
+  fn touch(&self, key: &In) {
+    if self.capacity.is_none() {
+      return;
+    }
+    let now = self.clock.get();
+    self.clock.set(now + 1);
+
+    let mut map = self.map.borrow_mut();
+    let entry = map.get_mut(key).expect("invariant broken");
+    let mut recency = self.recency.borrow_mut();
+    if let Some(last_used) = entry.last_used.replace(now) {
+      recency.remove(&last_used);
     }
+    recency.insert(now, key.clone());
+  }
 
-    *self.0.borrow_mut().get(key).expect("invariant broken")
+  fn evict_to_capacity(&self) {
+    let Some(capacity) = self.capacity else {
+      return;
+    };
+    let mut map = self.map.borrow_mut();
+    let mut recency = self.recency.borrow_mut();
+    while map.len() > capacity {
+      // Values that are being computed aren't in `recency`, so they can't be evicted.
+      let Some((_, lru)) = recency.pop_first() else {
+        break;
+      };
+      map.remove(&lru);
+      self.stats.evict();
+    }
   }
 }
 
 impl<In, Out> Default for CopyCache<In, Out> {
   fn default() -> Self {
-    CopyCache(RefCell::new(HashMap::default()))
+    CopyCache {
+      map: RefCell::new(HashMap::default()),
+      recency: RefCell::new(BTreeMap::new()),
+      capacity: None,
+      clock: Cell::new(0),
+      stats: StatsCounter::default(),
+    }
+  }
+}
+
+#[cfg(feature = "serde")]
+mod disk {
+  use std::{
+    fs, io,
+    path::{Path, PathBuf},
+  };
+
+  use rustc_hir::def_id::DefId;
+  use rustc_middle::ty::TyCtxt;
+  use serde::{Serialize, de::DeserializeOwned};
+
+  use super::Cache;
+
+  /// Cache for values that persist on disk across compiler runs.
+  ///
+  /// Each value is computed for a definition, and stored under the [`Svh`] of the
+  /// definition's crate and its [`DefPathHash`]. Since the SVH changes whenever the
+  /// crate does, stale entries are never read. Entries are also namespaced by the
+  /// version of rustc and by a version given to [`DiskCache::new`], so changing the
+  /// toolchain or the format of `Out` invalidates the whole cache.
+  ///
+  /// Within a compiler session, values are memoized in memory like a [`Cache`].
+  /// Failing to read or write an entry is not an error: the value is computed instead,
+  /// and the failure is logged.
+  ///
+  /// [`Svh`]: rustc_data_structures::svh::Svh
+  /// [`DefPathHash`]: rustc_span::def_id::DefPathHash
+  pub struct DiskCache<Out> {
+    dir: PathBuf,
+    memory: Cache<DefId, Out>,
+  }
+
+  impl<Out> DiskCache<Out>
+  where
+    Out: Serialize + DeserializeOwned,
+  {
+    /// Creates a cache stored in `root`.
+    ///
+    /// `version` should change whenever `Out` or its computation changes, e.g. it can be
+    /// the version of the crate using the cache.
+    pub fn new(root: impl AsRef<Path>, version: &str) -> Self {
+      let rustc_version = rustc_interface::util::rustc_version_str().unwrap_or("unknown");
+      let namespace = format!("{rustc_version}-{version}")
+        .chars()
+        .map(|c| {
+          if c.is_ascii_alphanumeric() || c == '.' {
+            c
+          } else {
+            '_'
+          }
+        })
+        .collect::<String>();
+      DiskCache {
+        dir: root.as_ref().join(namespace),
+        memory: Cache::default(),
+      }
+    }
+
+    /// Returns the cached value for `def_id`, or runs `compute` if the value is neither
+    /// in memory nor on disk.
+    ///
+    /// # Panics
+    ///
+    /// If this is a recursive invocation for this key.
+    pub fn get(
+      &self,
+      tcx: TyCtxt<'_>,
+      def_id: DefId,
+      compute: impl FnOnce(DefId) -> Out,
+    ) -> &Out {
+      self.memory.get(&def_id, |def_id| {
+        let path = self.path(tcx, def_id);
+        match read_entry(&path) {
+          Ok(Some(out)) => return out,
+          Ok(None) => {}
+          Err(e) => log::warn!("Failed to read cache entry {}: {e}", path.display()),
+        }
+
+        let out = compute(def_id);
+        if let Err(e) = self.write(&path, &out) {
+          log::warn!("Failed to write cache entry {}: {e}", path.display());
+        }
+        out
+      })
+    }
+
+    fn path(&self, tcx: TyCtxt<'_>, def_id: DefId) -> PathBuf {
+      let svh = tcx.crate_hash(def_id.krate);
+      let def_path_hash = tcx.def_path_hash(def_id);
+      self.dir.join(format!(
+        "{}-{}.json",
+        svh.to_hex(),
+        def_path_hash.0.to_hex()
+      ))
+    }
+
+    fn write(&self, path: &Path, out: &Out) -> io::Result<()> {
+      fs::create_dir_all(&self.dir)?;
+      // Writing to a temporary file first means that concurrent compiler runs never
+      // read a partially written entry.
+      let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+      fs::write(&tmp_path, serde_json::to_vec(out)?)?;
+      fs::rename(tmp_path, path)
+    }
+  }
+
+  fn read_entry<Out: DeserializeOwned>(path: &Path) -> io::Result<Option<Out>> {
+    match fs::read(path) {
+      Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
   }
 }
 
@@ -195,6 +714,49 @@ mod test {
     assert!(std::ptr::eq(x, z));
   }
 
+  #[test]
+  fn test_cache_remove() {
+    let mut cache: Cache<usize, usize> = Cache::default();
+    cache.get(&0, |_| 0);
+    cache.get(&0, |_| 0);
+    cache.get(&1, |_| 1);
+    assert!(cache.remove(&0));
+    assert!(!cache.remove(&0));
+    assert_eq!(*cache.get(&0, |_| 2), 2);
+    cache.clear();
+    assert_eq!(cache.len(), 0);
+
+    let stats = cache.stats();
+    assert_eq!(
+      (stats.hits, stats.misses, stats.evictions, stats.removals),
+      (1, 3, 0, 3)
+    );
+  }
+
+  #[test]
+  fn test_copy_cache_lru() {
+    let cache: CopyCache<usize, usize> = CopyCache::with_capacity(2);
+    cache.get(&0, |_| 0);
+    cache.get(&1, |_| 1);
+    // Using 0 makes 1 the least recently used value
+    cache.get(&0, |_| unreachable!());
+    cache.get(&2, |_| 2);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&0, |_| 10), 0);
+    assert_eq!(cache.get(&1, |_| 11), 11);
+
+    assert!(cache.remove(&1));
+    cache.get(&3, |_| 3);
+    cache.get(&4, |_| 4);
+    assert_eq!(cache.get(&3, |_| 13), 3);
+
+    let stats = cache.stats();
+    assert_eq!(
+      (stats.hits, stats.misses, stats.evictions, stats.removals),
+      (3, 6, 3, 1)
+    );
+  }
+
   #[test]
   fn test_recursion_breaking() {
     struct RecursiveUse(Cache<i32, i32>);
@@ -222,4 +784,93 @@ mod test {
     assert_eq!(cache.get_infinite_recursion(60), 42);
     assert_eq!(cache.get_safe_recursion(5), 15);
   }
+
+  #[test]
+  fn test_sync_cached() {
+    let cache: SyncCache<usize, usize> = SyncCache::default();
+    let x = cache.get(&0, |_| 0);
+    let y = cache.get(&1, |_| 1);
+    let z = cache.get(&0, |_| 2);
+    assert_eq!(*x, 0);
+    assert_eq!(*y, 1);
+    assert!(std::ptr::eq(x, z));
+    assert_eq!(cache.len(), 2);
+    let recursive = cache.get_maybe_recursive(&2, |_| {
+      cache.get_maybe_recursive(&2, |_| 2).copied().unwrap_or(3)
+    });
+    assert_eq!(recursive, Some(&3));
+  }
+
+  #[test]
+  fn test_sync_cached_threads() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let cache: SyncCache<usize, usize> = SyncCache::default();
+    let computed = AtomicUsize::new(0);
+    let values = std::thread::scope(|s| {
+      let handles = (0 .. 8)
+        .map(|_| {
+          s.spawn(|| {
+            let value = cache.get(&0, |_| {
+              computed.fetch_add(1, Ordering::SeqCst);
+              std::thread::sleep(std::time::Duration::from_millis(10));
+              42
+            });
+            std::ptr::from_ref(value).addr()
+          })
+        })
+        .collect::<Vec<_>>();
+      handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>()
+    });
+    assert_eq!(computed.load(Ordering::SeqCst), 1);
+    assert!(values.iter().all(|value| *value == values[0]));
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_disk_cache() {
+    use crate::test_utils::{self, CompileResult};
+
+    let root =
+      std::env::temp_dir().join(format!("rustc_utils-disk-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let input = "fn main() {}";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let main = tcx.hir_body_owners().next().unwrap().to_def_id();
+      let cache = DiskCache::new(&root, "1.0.0");
+      assert_eq!(*cache.get(tcx, main, |_| 1), 1);
+
+      // A new cache reads the value back from disk
+      let cache = DiskCache::new(&root, "1.0.0");
+      assert_eq!(*cache.get(tcx, main, |_| 2), 1);
+
+      // A different version doesn't
+      let cache = DiskCache::new(&root, "1.0.1");
+      assert_eq!(*cache.get(tcx, main, |_| 3), 3);
+    });
+
+    // Changing the crate changes its SVH, which invalidates the entry
+    let input = "fn main() { let _x = 1; }";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let main = tcx.hir_body_owners().next().unwrap().to_def_id();
+      let cache = DiskCache::new(&root, "1.0.0");
+      assert_eq!(*cache.get(tcx, main, |_| 4), 4);
+    });
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn test_sync_cached_panic() {
+    let cache: SyncCache<usize, usize> = SyncCache::default();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+      cache.get(&0, |_| panic!("failed to compute"));
+    }));
+    assert!(result.is_err());
+    assert_eq!(*cache.get(&0, |_| 1), 1);
+  }
 }
@@ -0,0 +1,290 @@
+//! A may-alias analysis for the places of a MIR body, built on borrowck facts.
+//!
+//! Each region of a body may contain loans, i.e. the places borrowed by `&` and `&mut`
+//! expressions. The analysis seeds the region of each loan with the borrowed place, and
+//! the regions of the arguments with the places they point to, e.g. `*x` for
+//! `x: &'a i32`. It then propagates the places along the Polonius `subset_base` facts, so
+//! that each region contains every place a reference of that region may point to.
+//!
+//! A place is resolved to its aliases by replacing each dereferenced reference with the
+//! places its region contains. The analysis is flow-insensitive, except that
+//! [`Aliases::aliases`] ignores loans that cannot have been issued before the queried
+//! location.
+
+use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet};
+use rustc_hir::def_id::DefId;
+use rustc_index::{IndexVec, bit_set::DenseBitSet};
+use rustc_middle::{
+  mir::{BasicBlock, Body, Location, Place, ProjectionElem},
+  ty::{Region, RegionKind, RegionVid, TyCtxt, TyKind},
+};
+
+use super::{borrowck_facts::BodyWithFacts, dataflow::conflicts};
+use crate::PlaceExt;
+
+/// A place a region may point to, along with the location of its loan, if any.
+type Target<'tcx> = (Place<'tcx>, Option<Location>);
+
+/// Returns the region variable of `region` in the body's region inference, if any.
+///
+/// As in [`PlaceExt::interior_pointers`], `'static` is the first region variable. Other
+/// regions, e.g. erased ones, have no variable.
+fn region_vid(region: Region<'_>) -> Option<RegionVid> {
+  match region.kind() {
+    RegionKind::ReVar(vid) => Some(vid),
+    RegionKind::ReStatic => Some(RegionVid::from_usize(0)),
+    _ => None,
+  }
+}
+
+/// The may-alias relation of a body.
+pub struct Aliases<'a, 'tcx> {
+  tcx: TyCtxt<'tcx>,
+  body: &'a Body<'tcx>,
+  points_to: HashMap<RegionVid, HashSet<Target<'tcx>>>,
+  /// The blocks reachable from each block by at least one edge.
+  reachable: IndexVec<BasicBlock, DenseBitSet<BasicBlock>>,
+}
+
+impl<'a, 'tcx> Aliases<'a, 'tcx> {
+  /// Computes the aliases of the body of `def_id`.
+  ///
+  /// The body must have been obtained with
+  /// [`get_body_with_borrowck_facts`](super::borrowck_facts::get_body_with_borrowck_facts),
+  /// which computes the Polonius input facts.
+  pub fn build(
+    tcx: TyCtxt<'tcx>,
+    def_id: DefId,
+    body_with_facts: &'a BodyWithFacts<'tcx>,
+  ) -> Self {
+    let body = &body_with_facts.body;
+    let facts = body_with_facts
+      .input_facts
+      .as_ref()
+      .expect("body is missing Polonius input facts");
+
+    let mut points_to = HashMap::<RegionVid, HashSet<Target<'tcx>>>::default();
+    for arg in body.args_iter() {
+      let place = Place::from_local(arg, tcx);
+      for (region, pointers) in place.interior_pointers(tcx, body, def_id) {
+        let targets = points_to.entry(region).or_default();
+        for (pointer, _) in pointers {
+          targets.insert((pointer.project_deeper(&[ProjectionElem::Deref], tcx), None));
+        }
+      }
+    }
+    for (origin, loan, _) in &facts.loan_issued_at {
+      let borrow = &body_with_facts.borrow_set[*loan];
+      points_to
+        .entry(RegionVid::from(*origin))
+        .or_default()
+        .insert((borrow.borrowed_place(), Some(borrow.reserve_location())));
+    }
+
+    // The subset relation is small, so a naive fixpoint is fast enough.
+    let subsets = facts
+      .subset_base
+      .iter()
+      .map(|(sub, sup, _)| (RegionVid::from(*sub), RegionVid::from(*sup)))
+      .filter(|(sub, sup)| sub != sup)
+      .collect::<HashSet<_>>();
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for (sub, sup) in &subsets {
+        let Some(targets) = points_to.get(sub).cloned() else {
+          continue;
+        };
+        let sup_targets = points_to.entry(*sup).or_default();
+        let len = sup_targets.len();
+        sup_targets.extend(targets);
+        changed |= sup_targets.len() != len;
+      }
+    }
+
+    let blocks = &body.basic_blocks;
+    let reachable = blocks
+      .indices()
+      .map(|block| {
+        let mut reachable = DenseBitSet::new_empty(blocks.len());
+        let mut stack = blocks[block].terminator().successors().collect::<Vec<_>>();
+        while let Some(next) = stack.pop() {
+          if reachable.insert(next) {
+            stack.extend(blocks[next].terminator().successors());
+          }
+        }
+        reachable
+      })
+      .collect();
+
+    Aliases {
+      tcx,
+      body,
+      points_to,
+      reachable,
+    }
+  }
+
+  /// Returns true if the loan issued at `loan` may have been issued before `location`.
+  fn reaches(&self, loan: Location, location: Location) -> bool {
+    (loan.block == location.block && loan.statement_index < location.statement_index)
+      || self.reachable[loan.block].contains(location.block)
+  }
+
+  /// Resolves the dereferences in `place`, where `stack` holds the places being resolved
+  /// to break cycles, e.g. the pointee `*x` of an argument `x` which resolves to itself.
+  fn resolve(
+    &self,
+    place: Place<'tcx>,
+    available: &impl Fn(Option<Location>) -> bool,
+    stack: &mut Vec<Place<'tcx>>,
+  ) -> HashSet<Place<'tcx>> {
+    if stack.contains(&place) {
+      return HashSet::from_iter([place]);
+    }
+    stack.push(place);
+
+    let mut places = HashSet::from_iter([Place::from_local(place.local, self.tcx)]);
+    for elem in place.projection {
+      let mut next = HashSet::default();
+      for base in places {
+        let targets = match (elem, base.ty(self.body, self.tcx).ty.kind()) {
+          (ProjectionElem::Deref, TyKind::Ref(region, ..)) => region_vid(*region)
+            .and_then(|region| self.points_to.get(&region))
+            .into_iter()
+            .flatten()
+            .filter(|(_, loan)| available(*loan))
+            .map(|(target, _)| *target)
+            .collect::<Vec<_>>(),
+          _ => Vec::new(),
+        };
+        // References to unknown places, e.g. raw pointers or boxes, are kept as-is.
+        if targets.is_empty() {
+          next.insert(base.project_deeper(&[elem], self.tcx));
+        }
+        // Reborrows point to places that may themselves contain dereferences.
+        for target in targets {
+          next.extend(self.resolve(target, available, stack));
+        }
+      }
+      places = next;
+    }
+
+    stack.pop();
+    places
+  }
+
+  /// Returns the places that `place` may refer to before `location` is executed.
+  ///
+  /// A place without dereferences only aliases itself.
+  pub fn aliases(&self, place: Place<'tcx>, location: Location) -> HashSet<Place<'tcx>> {
+    let available =
+      |loan: Option<Location>| loan.is_none_or(|loan| self.reaches(loan, location));
+    self.resolve(place, &available, &mut Vec::new())
+  }
+
+  /// Returns true if `place_a` and `place_b` may refer to overlapping memory at any
+  /// location of the body.
+  pub fn conflicts(&self, place_a: Place<'tcx>, place_b: Place<'tcx>) -> bool {
+    let aliases_a = self.resolve(place_a, &|_| true, &mut Vec::new());
+    let aliases_b = self.resolve(place_b, &|_| true, &mut Vec::new());
+    aliases_a
+      .iter()
+      .any(|a| aliases_b.iter().any(|b| conflicts(*a, *b)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use rustc_middle::mir::{Operand, Rvalue, StatementKind};
+
+  use super::*;
+  use crate::{BodyExt, test_utils};
+
+  #[test]
+  fn test_aliases() {
+    let input = r"
+fn foo(a: &mut i32, b: &i32) {
+  let mut x = 1;
+  let mut y = (0, 0);
+  let r = &mut x;
+  let s = &y.1;
+  let t = if *b > 0 { r } else { a };
+  *t = 2;
+  y.0 = *s;
+}";
+    test_utils::compile_body(input, |tcx, body_id, body_with_facts| {
+      let body = &body_with_facts.body;
+      let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
+      let name_map = body.debug_info_name_map();
+      let local = |name: &str| Place::from_local(name_map[name], tcx);
+      let deref = |name: &str| local(name).project_deeper(&[ProjectionElem::Deref], tcx);
+      let (a, b, x, y) = (deref("a"), deref("b"), local("x"), local("y"));
+      let y_1 =
+        y.project_deeper(&[ProjectionElem::Field(1_usize.into(), tcx.types.i32)], tcx);
+
+      let aliases = Aliases::build(tcx, def_id, body_with_facts);
+      let exit = body.all_returns().next().unwrap();
+      assert_eq!(
+        aliases.aliases(deref("t"), exit),
+        HashSet::from_iter([a, x])
+      );
+      assert_eq!(aliases.aliases(deref("s"), exit), HashSet::from_iter([y_1]));
+      assert_eq!(aliases.aliases(b, exit), HashSet::from_iter([b]));
+      assert_eq!(aliases.aliases(x, exit), HashSet::from_iter([x]));
+
+      // Before `r` is assigned, the loan of `x` has not been issued.
+      let borrow_x = body
+        .all_locations()
+        .find(|location| {
+          matches!(
+            body.stmt_at(*location).left().map(|stmt| &stmt.kind),
+            Some(StatementKind::Assign(box (place, _))) if place.local == name_map["r"]
+          )
+        })
+        .unwrap();
+      assert_eq!(
+        aliases.aliases(deref("r"), borrow_x),
+        HashSet::from_iter([deref("r")])
+      );
+
+      assert!(aliases.conflicts(deref("t"), x));
+      assert!(aliases.conflicts(deref("s"), y));
+      assert!(!aliases.conflicts(deref("s"), x));
+      assert!(!aliases.conflicts(deref("r"), a));
+    });
+  }
+
+  #[test]
+  fn test_aliases_static_region() {
+    let input = r"
+struct S { r: &'static i32 }
+fn foo(s: S) -> i32 {
+  *s.r
+}";
+    test_utils::compile_body(input, |tcx, body_id, body_with_facts| {
+      let body = &body_with_facts.body;
+      let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
+      let place = body
+        .all_locations()
+        .find_map(|location| match &body.stmt_at(location).left()?.kind {
+          StatementKind::Assign(box (_, Rvalue::Use(Operand::Copy(place)))) => {
+            Some(*place)
+          }
+          _ => None,
+        })
+        .unwrap();
+
+      // The type of `s.r` has the region `'static` rather than a region variable, so
+      // `*s.r` resolves to itself, up to the region in the type of `s.r`.
+      let aliases = Aliases::build(tcx, def_id, body_with_facts);
+      let exit = body.all_returns().next().unwrap();
+      let aliases = aliases.aliases(place, exit).into_iter().collect::<Vec<_>>();
+      assert_eq!(aliases.len(), 1);
+      assert_eq!(
+        (aliases[0].local, aliases[0].projection.len()),
+        (place.local, place.projection.len())
+      );
+    });
+  }
+}
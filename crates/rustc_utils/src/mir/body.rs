@@ -1,24 +1,32 @@
 //! Utilities for [`Body`].
 
 use std::{
+  fmt::Write as _,
+  fs, io,
   io::Write,
   path::Path,
   process::{Command, Stdio},
 };
 
-use anyhow::{Result, ensure};
+use anyhow::{Result, bail, ensure};
+use either::Either;
 use rustc_data_structures::fx::FxHashMap as HashMap;
 use rustc_hir::{CoroutineDesugaring, CoroutineKind, HirId, def_id::DefId};
 use rustc_middle::{
   mir::{
-    BasicBlock, Body, Local, Location, Place, SourceInfo, TerminatorKind,
+    BasicBlock, Body, Local, Location, Place, SourceInfo, TerminatorKind, UnwindAction,
     VarDebugInfoContents, pretty::MirWriter,
   },
   ty::{Region, Ty, TyCtxt},
 };
 use smallvec::SmallVec;
 
-use super::control_dependencies::ControlDependencies;
+use super::{
+  control_dependencies::{
+    ControlDependencies, ControlDependencyOptions, LocationControlDependencies,
+  },
+  dataflow::{DefUseChains, Liveness, ReachingDefinitions},
+};
 use crate::{PlaceExt, TyExt};
 
 /// Extension trait for [`Body`].
@@ -38,6 +46,12 @@ pub trait BodyExt<'tcx> {
   /// Converts a Body to a debug representation.
   fn to_string(&self, tcx: TyCtxt<'tcx>) -> Result<String>;
 
+  /// Converts a Body to a control-flow graph in the DOT format of Graphviz, with each
+  /// block and location followed by its `annotations`.
+  ///
+  /// The graph can be rendered with [`render_dot`].
+  fn to_dot(&self, annotations: &impl DotAnnotations<'tcx>) -> String;
+
   /// Returns the [`HirId`] corresponding to a MIR [`Location`].
   ///
   /// You **MUST** use the `-Zmaximize-hir-to-mir-mapping` flag for this
@@ -52,6 +66,30 @@ pub trait BodyExt<'tcx> {
   /// for details.
   fn control_dependencies(&self) -> ControlDependencies<BasicBlock>;
 
+  /// Returns the control dependencies of each location, along with the branches they
+  /// come from.
+  ///
+  /// See [`ControlDependencyOptions`] for how paths that don't return are handled.
+  fn location_control_dependencies(
+    &self,
+    options: ControlDependencyOptions,
+  ) -> LocationControlDependencies;
+
+  /// Returns the definitions of places that may reach each location.
+  ///
+  /// See the [`dataflow`][super::dataflow] module documentation for details.
+  fn reaching_definitions(&self, tcx: TyCtxt<'tcx>) -> ReachingDefinitions<'_, 'tcx>;
+
+  /// Returns the places that may be live at each location.
+  ///
+  /// See the [`dataflow`][super::dataflow] module documentation for details.
+  fn liveness(&self, tcx: TyCtxt<'tcx>, def_id: DefId) -> Liveness<'_, 'tcx>;
+
+  /// Returns the definitions that may reach each read of a place, and vice versa.
+  ///
+  /// See the [`dataflow`][super::dataflow] module documentation for details.
+  fn def_use_chains(&self, tcx: TyCtxt<'tcx>) -> DefUseChains;
+
   /// If this body is an async function, then return the type of the context that holds
   /// locals across await calls.
   fn async_context(&self, tcx: TyCtxt<'tcx>, def_id: DefId) -> Option<Ty<'tcx>>;
@@ -121,6 +159,71 @@ impl<'tcx> BodyExt<'tcx> for Body<'tcx> {
     Ok(String::from_utf8(buffer)?)
   }
 
+  fn to_dot(&self, annotations: &impl DotAnnotations<'tcx>) -> String {
+    let mut dot =
+      String::from("digraph mir {\n  node [shape=box, fontname=\"monospace\"];\n");
+    for (block, data) in self.basic_blocks.iter_enumerated() {
+      let mut lines = vec![if data.is_cleanup {
+        format!("{block:?} (cleanup)")
+      } else {
+        format!("{block:?}")
+      }];
+      lines.extend(
+        annotations
+          .block(self, block)
+          .into_iter()
+          .map(|line| format!("// {line}")),
+      );
+      for location in self.locations_in_block(block) {
+        lines.push(match self.stmt_at(location) {
+          Either::Left(statement) => format!("{statement:?}"),
+          Either::Right(terminator) => format!("{:?}", terminator.kind),
+        });
+        lines.extend(
+          annotations
+            .location(self, location)
+            .into_iter()
+            .map(|line| format!("  // {line}")),
+        );
+      }
+      let mut label = String::new();
+      for line in &lines {
+        write!(label, "{}\\l", escape_dot(line)).unwrap();
+      }
+      writeln!(dot, "  {block:?} [label=\"{label}\"];").unwrap();
+
+      let terminator = data.terminator();
+      let edges = match &terminator.kind {
+        TerminatorKind::SwitchInt { targets, .. } => targets
+          .iter()
+          .map(|(value, target)| (target, value.to_string()))
+          .chain([(targets.otherwise(), "otherwise".to_string())])
+          .collect::<Vec<_>>(),
+        _ => terminator
+          .successors()
+          .map(|succ| {
+            let label = if terminator.unwind() == Some(&UnwindAction::Cleanup(succ)) {
+              "unwind"
+            } else {
+              ""
+            };
+            (succ, label.to_string())
+          })
+          .collect(),
+      };
+      for (succ, label) in edges {
+        let style = if label == "unwind" { "dashed" } else { "solid" };
+        writeln!(
+          dot,
+          "  {block:?} -> {succ:?} [label=\"{label}\", style={style}];"
+        )
+        .unwrap();
+      }
+    }
+    dot.push_str("}\n");
+    dot
+  }
+
   fn location_to_hir_id(&self, location: Location) -> HirId {
     let source_info = self.source_info(location);
     self.source_info_to_hir_id(source_info)
@@ -139,6 +242,25 @@ impl<'tcx> BodyExt<'tcx> for Body<'tcx> {
     )
   }
 
+  fn location_control_dependencies(
+    &self,
+    options: ControlDependencyOptions,
+  ) -> LocationControlDependencies {
+    LocationControlDependencies::build(self, options)
+  }
+
+  fn reaching_definitions(&self, tcx: TyCtxt<'tcx>) -> ReachingDefinitions<'_, 'tcx> {
+    ReachingDefinitions::new(tcx, self)
+  }
+
+  fn liveness(&self, tcx: TyCtxt<'tcx>, def_id: DefId) -> Liveness<'_, 'tcx> {
+    Liveness::new(tcx, self, def_id)
+  }
+
+  fn def_use_chains(&self, tcx: TyCtxt<'tcx>) -> DefUseChains {
+    DefUseChains::new(tcx, self)
+  }
+
   fn async_context(&self, tcx: TyCtxt<'tcx>, def_id: DefId) -> Option<Ty<'tcx>> {
     if matches!(
       tcx.coroutine_kind(def_id),
@@ -175,11 +297,106 @@ impl<'tcx> BodyExt<'tcx> for Body<'tcx> {
   }
 }
 
-pub fn run_dot(path: &Path, buf: &[u8]) -> Result<()> {
-  let mut p = Command::new("dot")
-    .args(["-Tpdf", "-o", &path.display().to_string()])
+/// Annotations shown by [`BodyExt::to_dot`], e.g. the results of an analysis.
+///
+/// Each method returns lines of text, which are shown as comments below the
+/// corresponding block header or location.
+pub trait DotAnnotations<'tcx> {
+  /// Returns the annotations of `block`.
+  fn block(&self, _body: &Body<'tcx>, _block: BasicBlock) -> Vec<String> {
+    Vec::new()
+  }
+
+  /// Returns the annotations of the statement or terminator at `location`.
+  fn location(&self, _body: &Body<'tcx>, _location: Location) -> Vec<String> {
+    Vec::new()
+  }
+}
+
+/// No annotations.
+impl DotAnnotations<'_> for () {}
+
+/// The annotations of both elements, in order.
+impl<'tcx, A: DotAnnotations<'tcx>, B: DotAnnotations<'tcx>> DotAnnotations<'tcx>
+  for (A, B)
+{
+  fn block(&self, body: &Body<'tcx>, block: BasicBlock) -> Vec<String> {
+    let mut lines = self.0.block(body, block);
+    lines.extend(self.1.block(body, block));
+    lines
+  }
+
+  fn location(&self, body: &Body<'tcx>, location: Location) -> Vec<String> {
+    let mut lines = self.0.location(body, location);
+    lines.extend(self.1.location(body, location));
+    lines
+  }
+}
+
+/// Annotates each location with the source code of its span.
+pub struct SourceSnippets<'tcx>(pub TyCtxt<'tcx>);
+
+impl<'tcx> DotAnnotations<'tcx> for SourceSnippets<'tcx> {
+  fn location(&self, body: &Body<'tcx>, location: Location) -> Vec<String> {
+    let span = body.source_info(location).span;
+    let Ok(snippet) = self.0.sess.source_map().span_to_snippet(span) else {
+      return Vec::new();
+    };
+    let mut lines = snippet.lines();
+    let first = lines.next().unwrap_or_default().trim();
+    let ellipsis = if lines.next().is_some() { " ..." } else { "" };
+    vec![format!("{first}{ellipsis}")]
+  }
+}
+
+fn escape_dot(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// An output format of Graphviz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DotFormat {
+  Svg,
+  Png,
+  Pdf,
+}
+
+impl DotFormat {
+  /// Returns the name of the format, which is also its file extension.
+  pub fn name(self) -> &'static str {
+    match self {
+      DotFormat::Svg => "svg",
+      DotFormat::Png => "png",
+      DotFormat::Pdf => "pdf",
+    }
+  }
+}
+
+/// Renders a graph in the DOT format to `path` with the Graphviz `dot` binary.
+///
+/// If `dot` is not installed, the graph is written to `path` with a `.dot` extension
+/// instead, and the error explains how to render it.
+pub fn render_dot(path: &Path, buf: &[u8], format: DotFormat) -> Result<()> {
+  let flag = format!("-T{}", format.name());
+  let mut p = match Command::new("dot")
+    .args([flag.as_str(), "-o", &path.display().to_string()])
     .stdin(Stdio::piped())
-    .spawn()?;
+    .spawn()
+  {
+    Ok(p) => p,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      let dot_path = path.with_extension("dot");
+      fs::write(&dot_path, buf)?;
+      bail!(
+        "the Graphviz `dot` binary was not found, so the graph was written to {} instead. \
+         Install Graphviz and run `dot {flag} {} -o {}` to render it.",
+        dot_path.display(),
+        dot_path.display(),
+        path.display()
+      );
+    }
+    Err(e) => return Err(e.into()),
+  };
 
   p.stdin.as_mut().unwrap().write_all(buf)?;
 
@@ -189,9 +406,14 @@ pub fn run_dot(path: &Path, buf: &[u8]) -> Result<()> {
   Ok(())
 }
 
+/// Renders a graph in the DOT format to a PDF at `path`, see [`render_dot`].
+pub fn run_dot(path: &Path, buf: &[u8]) -> Result<()> {
+  render_dot(path, buf, DotFormat::Pdf)
+}
+
 #[cfg(test)]
 mod test {
-  use super::BodyExt;
+  use super::*;
   use crate::test_utils;
 
   #[test]
@@ -211,4 +433,53 @@ fn foobar<'a>(x: &'a i32, y: &'a i32) -> &'a i32 {
       assert_eq!(body.regions_in_return().count(), 1);
     });
   }
+
+  #[test]
+  fn test_to_dot() {
+    let input = r#"
+fn foobar(x: i32) -> i32 {
+  let mut y = "\"quoted\"".len() as i32;
+  if x > 0 {
+    y += 1;
+  }
+  y
+}"#;
+
+    test_utils::compile_body(input, |tcx, body_id, body| {
+      let body = &body.body;
+      let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
+      let annotations = (
+        body.location_control_dependencies(ControlDependencyOptions::default()),
+        (body.liveness(tcx, def_id), SourceSnippets(tcx)),
+      );
+      let dot = body.to_dot(&annotations);
+      assert!(dot.starts_with("digraph mir {"));
+      assert!(dot.contains("bb0 -> bb"));
+      assert!(dot.contains("[label=\"otherwise\", style=solid]"));
+      assert!(dot.contains("// depends on bb"));
+      assert!(dot.contains("// live: "));
+      assert!(dot.contains("// y += 1"));
+      // Quotes in the source are escaped.
+      assert!(dot.contains(r#"\\\"quoted"#));
+
+      let dir =
+        std::env::temp_dir().join(format!("rustc-utils-dot-{}", std::process::id()));
+      std::fs::create_dir_all(&dir).unwrap();
+      let path = dir.join("body.svg");
+      let result = render_dot(&path, dot.as_bytes(), DotFormat::Svg);
+      let dot_missing = Command::new("dot")
+        .arg("-V")
+        .output()
+        .is_err_and(|e| e.kind() == io::ErrorKind::NotFound);
+      if dot_missing {
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("body.dot"), "{err}");
+        assert_eq!(std::fs::read_to_string(dir.join("body.dot")).unwrap(), dot);
+      } else {
+        result.unwrap();
+        assert!(path.exists());
+      }
+      std::fs::remove_dir_all(&dir).unwrap();
+    });
+  }
 }
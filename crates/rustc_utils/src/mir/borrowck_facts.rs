@@ -1,17 +1,33 @@
 //! Polonius integration to extract borrowck facts from rustc.
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::{
+  cell::Cell,
+  ptr,
+  sync::{
+    Arc, Mutex, PoisonError,
+    atomic::{AtomicBool, Ordering},
+  },
+};
 
-use rustc_borrowck::consumers::{BodyWithBorrowckFacts, ConsumerOptions};
+use rustc_borrowck::consumers::{
+  BodyWithBorrowckFacts, BorrowSet, ConsumerOptions, PoloniusInput,
+  PoloniusLocationTable, PoloniusOutput,
+};
 use rustc_data_structures::fx::FxHashSet as HashSet;
 use rustc_hir::def_id::LocalDefId;
+use rustc_index::IndexVec;
+use rustc_interface::Config;
 use rustc_middle::{
-  mir::{Body, StatementKind, TerminatorKind},
+  mir::{Body, Promoted, StatementKind, TerminatorKind},
   ty::TyCtxt,
   util::Providers,
 };
+use rustc_session::Session;
 
-use crate::{BodyExt, block_timer, cache::Cache};
+use crate::{
+  BodyExt, block_timer,
+  cache::{CacheStats, SyncCache},
+};
 
 /// MIR pass to remove instructions not important for Flowistry.
 ///
@@ -55,16 +71,123 @@ pub fn enable_mir_simplification() {
   SIMPLIFY_MIR.store(true, Ordering::SeqCst);
 }
 
-/// You must use this function in [`rustc_driver::Callbacks::config`] to call [`get_body_with_borrowck_facts`].
+/// Sets up `config` so that [`get_body_with_borrowck_facts`] works in its session.
+///
+/// This sets [`override_queries`] as the session's `override_queries`, and stores the
+/// session's bodies in `config` so that they are dropped once the session has ended. Call
+/// it in [`rustc_driver::Callbacks::config`].
+pub fn store_bodies(config: &mut Config) {
+  config.override_queries = Some(override_queries);
+
+  let owner = BodiesOwner(Arc::default());
+  let register_lints = config.register_lints.take();
+  config.register_lints = Some(Box::new(move |sess, store| {
+    if let Some(register_lints) = &register_lints {
+      register_lints(sess, store);
+    }
+    // The session is moved after this hook, so it is only identified once its queries
+    // are overridden, which happens later on this thread.
+    PENDING_BODIES.set(Some(Arc::clone(&owner.0)));
+  }));
+}
+
+/// You must call this function in the `override_queries` of a session set up with
+/// [`store_bodies`] to call [`get_body_with_borrowck_facts`]. [`store_bodies`] already
+/// does so, unless you override `override_queries` afterwards.
 ///
 /// For why we need to do override mir_borrowck, see:
 /// <https://github.com/rust-lang/rust/blob/485ced56b8753ec86936903f2a8c95e9be8996a1/src/test/run-make-fulldeps/obtain-borrowck/driver.rs>
-pub fn override_queries(_session: &rustc_session::Session, local: &mut Providers) {
+pub fn override_queries(session: &Session, local: &mut Providers) {
+  if let Some(bodies) = PENDING_BODIES.take() {
+    let session = ptr::from_ref(session).addr();
+    let mut sessions = MIR_BODIES.lock().unwrap_or_else(PoisonError::into_inner);
+    sessions.retain(|bodies| bodies.session != session);
+    sessions.push(SessionBodies { session, bodies });
+  }
   local.queries.mir_borrowck = mir_borrowck;
 }
 
+/// A MIR body with its borrowck facts.
+///
+/// This is a [`BodyWithBorrowckFacts`] without its region inference context, which
+/// can't be shared between rustc's worker threads.
+pub struct BodyWithFacts<'tcx> {
+  /// A MIR body that contains region identifiers.
+  pub body: Body<'tcx>,
+
+  /// The MIR bodies of promoteds.
+  pub promoted: IndexVec<Promoted, Body<'tcx>>,
+
+  /// The set of borrows occurring in `body` with data about them.
+  pub borrow_set: BorrowSet<'tcx>,
+
+  /// The table that maps Polonius points to locations in the table.
+  pub location_table: Option<PoloniusLocationTable>,
+
+  /// Polonius input facts.
+  pub input_facts: Option<Box<PoloniusInput>>,
+
+  /// Polonius output facts.
+  pub output_facts: Option<Box<PoloniusOutput>>,
+}
+
+impl<'tcx> From<BodyWithBorrowckFacts<'tcx>> for BodyWithFacts<'tcx> {
+  fn from(body_with_facts: BodyWithBorrowckFacts<'tcx>) -> Self {
+    BodyWithFacts {
+      body: body_with_facts.body,
+      promoted: body_with_facts.promoted,
+      borrow_set: body_with_facts.borrow_set,
+      location_table: body_with_facts.location_table,
+      input_facts: body_with_facts.input_facts,
+      output_facts: body_with_facts.output_facts,
+    }
+  }
+}
+
+type BodyCache = SyncCache<LocalDefId, BodyWithFacts<'static>>;
+
+/// Drops the bodies of a session from [`MIR_BODIES`] when the session's config is
+/// dropped, which is after its `tcx`.
+struct BodiesOwner(Arc<BodyCache>);
+
+impl Drop for BodiesOwner {
+  fn drop(&mut self) {
+    let mut sessions = MIR_BODIES.lock().unwrap_or_else(PoisonError::into_inner);
+    sessions.retain(|bodies| !Arc::ptr_eq(&bodies.bodies, &self.0));
+  }
+}
+
+/// The bodies of one compilation session.
+struct SessionBodies {
+  /// The address of the session, which is unique among the sessions that are running.
+  session: usize,
+  bodies: Arc<BodyCache>,
+}
+
 thread_local! {
-  static MIR_BODIES: Cache<LocalDefId, BodyWithBorrowckFacts<'static>> = Cache::default();
+  /// The bodies stored by [`store_bodies`] for the session being created on this thread.
+  static PENDING_BODIES: Cell<Option<Arc<BodyCache>>> = const { Cell::new(None) };
+}
+
+/// The bodies of each running compilation session that was set up with [`store_bodies`].
+///
+/// With `-Z threads`, queries run on rustc's worker threads, so the bodies must be shared
+/// between every thread of a session.
+static MIR_BODIES: Mutex<Vec<SessionBodies>> = Mutex::new(Vec::new());
+
+fn mir_bodies(session: &Session) -> Option<Arc<BodyCache>> {
+  let session = ptr::from_ref(session).addr();
+  let sessions = MIR_BODIES.lock().unwrap_or_else(PoisonError::into_inner);
+  sessions
+    .iter()
+    .find(|bodies| bodies.session == session)
+    .map(|bodies| Arc::clone(&bodies.bodies))
+}
+
+fn expect_mir_bodies(session: &Session) -> Arc<BodyCache> {
+  mir_bodies(session).expect(
+    "no bodies were stored for this session. Are you sure you called borrowck_facts::store_bodies?",
+  )
 }
 
 fn mir_borrowck(
@@ -82,17 +205,19 @@ fn mir_borrowck(
     ConsumerOptions::PoloniusInputFacts,
   );
 
-  for (def_id, mut body_with_facts) in bodies_with_facts.drain() {
-    if SIMPLIFY_MIR.load(Ordering::SeqCst) {
-      simplify_mir(&mut body_with_facts.body);
-    }
+  // Sessions that weren't set up with `store_bodies` are only borrow-checked.
+  if let Some(cache) = mir_bodies(tcx.sess) {
+    for (def_id, body_with_facts) in bodies_with_facts.drain() {
+      let mut body_with_facts = BodyWithFacts::from(body_with_facts);
+      if SIMPLIFY_MIR.load(Ordering::SeqCst) {
+        simplify_mir(&mut body_with_facts.body);
+      }
 
-    // SAFETY: The reader casts the 'static lifetime to 'tcx before using it.
-    let body_with_facts: BodyWithBorrowckFacts<'static> =
-      unsafe { std::mem::transmute(body_with_facts) };
-    MIR_BODIES.with(|cache| {
+      // SAFETY: The reader casts the 'static lifetime to 'tcx before using it.
+      let body_with_facts: BodyWithFacts<'static> =
+        unsafe { std::mem::transmute(body_with_facts) };
       cache.get(&def_id, |_| body_with_facts);
-    });
+    }
   }
 
   let mut providers = Providers::default();
@@ -102,10 +227,9 @@ fn mir_borrowck(
 }
 
 /// Gets the MIR body and [Polonius](https://github.com/rust-lang/polonius)-generated
-/// [borrowck facts](https://doc.rust-lang.org/nightly/nightly-rustc/rustc_borrowck/struct.BodyWithBorrowckFacts.html)
-/// for a given [`LocalDefId`].
+/// borrowck facts for a given [`LocalDefId`].
 ///
-/// For this function to work, you MUST add [`override_queries`] to the
+/// For this function to work, you MUST call [`store_bodies`] on the
 /// [`rustc_interface::Config`](https://doc.rust-lang.org/nightly/nightly-rustc/rustc_interface/interface/struct.Config.html)
 /// inside of your [`rustc_driver::Callbacks`]. For example, see
 /// [example.rs](https://github.com/willcrichton/flowistry/tree/master/crates/flowistry/examples/example.rs).
@@ -115,24 +239,55 @@ fn mir_borrowck(
 pub fn get_body_with_borrowck_facts<'tcx>(
   tcx: TyCtxt<'tcx>,
   def_id: LocalDefId,
-) -> &'tcx BodyWithBorrowckFacts<'tcx> {
-  MIR_BODIES.with(|cache| {
+) -> &'tcx BodyWithFacts<'tcx> {
+  let cache = expect_mir_bodies(tcx.sess);
+  {
     // Note: as of nightly-2025-08-20, get_bodies_with_borrowck_facts also returns the bodies for children
     // of a checked body. We have to handle the case where the parent of the current `def_id` was checked,
     // or else rustc panics about a stolen body. Hence, we check for whether the cache contains the key already.
     if !cache.contains_key(&def_id) {
       // Note: as of nightly-2026-05-01, mir_borrowck will panic if passed a child of an item (eg a closure),
       // so we have to make sure we call for the child's parent instead.
-      let checkable_def_id = if tcx.is_typeck_child(def_id.to_def_id()) { tcx.local_parent(def_id) } else { def_id };
+      let checkable_def_id = if tcx.is_typeck_child(def_id.to_def_id()) {
+        tcx.local_parent(def_id)
+      } else {
+        def_id
+      };
       let _ = tcx.mir_borrowck(checkable_def_id);
     }
 
-    let body = cache.get(&def_id, |_| panic!("mir_borrowck override should have stored body for item: {def_id:?}. Are you sure you registered borrowck_facts::override_queries?"));
+    let body = cache.get(&def_id, |_| panic!("mir_borrowck override should have stored body for item: {def_id:?}. Are you sure you called borrowck_facts::store_bodies?"));
+    // SAFETY: the cache of a session is owned by the session's config, which is dropped
+    // after this session's `tcx`.
     unsafe {
-      std::mem::transmute::<
-        &'_ BodyWithBorrowckFacts<'static>,
-        &'tcx BodyWithBorrowckFacts<'tcx>,
-      >(body)
+      std::mem::transmute::<&'_ BodyWithFacts<'static>, &'tcx BodyWithFacts<'tcx>>(body)
     }
-  })
+  }
+}
+
+/// Returns statistics about the bodies cached in the session of `tcx`, which can be
+/// logged with [`timer::cache_stats`](crate::timer::cache_stats).
+pub fn body_cache_stats(tcx: TyCtxt<'_>) -> CacheStats {
+  mir_bodies(tcx.sess)
+    .map(|bodies| bodies.stats())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils;
+
+  #[test]
+  fn test_bodies_dropped_after_session() {
+    let bodies = Mutex::new(None);
+    test_utils::compile_body("fn foo() {}", |tcx, _, _| {
+      let cache = mir_bodies(tcx.sess).unwrap();
+      assert_eq!(cache.len(), 1);
+      *bodies.lock().unwrap() = Some(Arc::downgrade(&cache));
+    });
+
+    let bodies = bodies.into_inner().unwrap_or_else(PoisonError::into_inner);
+    assert!(bodies.unwrap().upgrade().is_none());
+  }
 }
@@ -0,0 +1,437 @@
+//! A call graph of the functions in the current crate.
+//!
+//! The callee of each [`TerminatorKind::Call`] is resolved with [`Instance::try_resolve`]
+//! in the caller's [`TypingEnv`](rustc_middle::ty::TypingEnv). When the callee can't be
+//! resolved statically, i.e. it is a method of a `dyn Trait` or of a generic type, the
+//! graph conservatively includes every implementation of the method. Calls through
+//! function pointers are resolved when the pointer is a local reified from a known
+//! function in the same body, possibly through copies.
+//!
+//! Closures are called by whatever function they are passed to, which may be outside
+//! the crate, so the graph also links each function to the closures it creates.
+
+use std::fmt::Write;
+
+use either::Either;
+use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet, FxIndexSet};
+use rustc_hir::def_id::DefId;
+use rustc_middle::{
+  mir::{
+    AggregateKind, Body, CastKind, Local, Location, Rvalue, StatementKind, TerminatorKind,
+  },
+  ty::{Instance, InstanceKind, TyCtxt, TyKind, adjustment::PointerCoercion},
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::borrowck_facts::get_body_with_borrowck_facts;
+use crate::{BodyExt, block_timer, source_map::find_bodies::find_bodies};
+
+/// How a caller reaches a callee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CallKind {
+  /// A call whose callee is known statically.
+  Static,
+
+  /// A call of a trait method that may dispatch to this implementation.
+  Dynamic,
+
+  /// A call of a closure, or the creation of a closure that may be called later.
+  Closure,
+
+  /// A call through a function pointer.
+  FnPointer,
+}
+
+/// An edge of a [`CallGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Call {
+  pub caller: DefId,
+  pub callee: DefId,
+  /// The location of the call, or of the closure's creation, in the caller.
+  pub location: Location,
+  pub kind: CallKind,
+}
+
+/// The functions of a crate and the calls between them.
+pub struct CallGraph<'tcx> {
+  tcx: TyCtxt<'tcx>,
+  functions: FxIndexSet<DefId>,
+  calls: HashMap<DefId, Vec<Call>>,
+  callers: HashMap<DefId, Vec<Call>>,
+}
+
+impl<'tcx> CallGraph<'tcx> {
+  /// Builds the call graph of every function and closure body in the current crate.
+  ///
+  /// Bodies are obtained with [`get_body_with_borrowck_facts`], so the same
+  /// requirements apply: the session must be set up with
+  /// [`store_bodies`](super::borrowck_facts::store_bodies).
+  pub fn build(tcx: TyCtxt<'tcx>) -> Self {
+    block_timer!("CallGraph::build");
+    let mut graph = CallGraph {
+      tcx,
+      functions: FxIndexSet::default(),
+      calls: HashMap::default(),
+      callers: HashMap::default(),
+    };
+    for (_, body_id) in find_bodies(tcx) {
+      let def_id = tcx.hir_body_owner_def_id(body_id);
+      let body = &get_body_with_borrowck_facts(tcx, def_id).body;
+      graph.add_body(def_id.to_def_id(), body);
+    }
+    graph
+  }
+
+  fn add_body(&mut self, caller: DefId, body: &Body<'tcx>) {
+    let tcx = self.tcx;
+    self.functions.insert(caller);
+    let mut calls = Vec::new();
+    let mut add = |callee: DefId, location: Location, kind: CallKind| {
+      calls.push(Call {
+        caller,
+        callee,
+        location,
+        kind,
+      });
+    };
+
+    let fn_pointers = fn_pointers(tcx, body);
+    let typing_env = body.typing_env(tcx);
+    for location in body.all_locations() {
+      let func = match body.stmt_at(location) {
+        Either::Left(statement) => {
+          if let StatementKind::Assign(box (
+            _,
+            Rvalue::Aggregate(box AggregateKind::Closure(closure, _), _),
+          )) = &statement.kind
+          {
+            add(*closure, location, CallKind::Closure);
+          }
+          continue;
+        }
+        Either::Right(terminator) => match &terminator.kind {
+          TerminatorKind::Call { func, .. } => func,
+          _ => continue,
+        },
+      };
+
+      match *func.ty(body, tcx).kind() {
+        TyKind::FnDef(def_id, args) => {
+          match Instance::try_resolve(tcx, typing_env, def_id, args) {
+            Ok(Some(instance)) => match instance.def {
+              InstanceKind::Item(item) => {
+                let kind = if tcx.is_closure_like(item) {
+                  CallKind::Closure
+                } else {
+                  CallKind::Static
+                };
+                add(item, location, kind);
+              }
+              InstanceKind::ClosureOnceShim { .. } => {
+                if let TyKind::Closure(closure, _) = args.type_at(0).kind() {
+                  add(*closure, location, CallKind::Closure);
+                }
+              }
+              InstanceKind::Virtual(method, _) => {
+                for item in self.implementations(method) {
+                  add(item, location, CallKind::Dynamic);
+                }
+              }
+              // Calls of intrinsics, shims and drop glue don't run user code.
+              _ => {}
+            },
+            Ok(None) => {
+              for item in self.implementations(def_id) {
+                add(item, location, CallKind::Dynamic);
+              }
+            }
+            Err(_) => {}
+          }
+        }
+        TyKind::FnPtr(..) => {
+          if let Some(local) = func.place().and_then(|place| place.as_local())
+            && let Some(items) = fn_pointers.get(&local)
+          {
+            for item in items {
+              add(*item, location, CallKind::FnPointer);
+            }
+          }
+        }
+        _ => {}
+      }
+    }
+
+    for call in &calls {
+      self.functions.insert(call.callee);
+      self.callers.entry(call.callee).or_default().push(*call);
+    }
+    self.calls.entry(caller).or_default().extend(calls);
+  }
+
+  /// Returns every implementation of the trait method `method`, including its default
+  /// body if any. Methods of the `Fn` traits are skipped, since closures are linked to
+  /// the functions that create them.
+  fn implementations(&self, method: DefId) -> Vec<DefId> {
+    let tcx = self.tcx;
+    let Some(trait_id) = tcx.trait_of_assoc(method) else {
+      return Vec::new();
+    };
+    if tcx.fn_trait_kind_from_def_id(trait_id).is_some() {
+      return Vec::new();
+    }
+    let mut implementations = tcx
+      .all_impls(trait_id)
+      .filter_map(|impl_id| tcx.impl_item_implementor_ids(impl_id).get(&method).copied())
+      .collect::<Vec<_>>();
+    if tcx.defaultness(method).has_value() {
+      implementations.push(method);
+    }
+    implementations
+  }
+
+  /// Returns every function in the graph, i.e. the local bodies and their callees.
+  pub fn functions(&self) -> impl Iterator<Item = DefId> + '_ {
+    self.functions.iter().copied()
+  }
+
+  /// Returns the calls made by `caller`.
+  pub fn callees(&self, caller: DefId) -> impl Iterator<Item = &Call> + '_ {
+    self.calls.get(&caller).into_iter().flatten()
+  }
+
+  /// Returns the calls of `callee`.
+  pub fn callers(&self, callee: DefId) -> impl Iterator<Item = &Call> + '_ {
+    self.callers.get(&callee).into_iter().flatten()
+  }
+
+  /// Returns the functions that may be called, transitively, from `root`, including
+  /// `root` itself.
+  ///
+  /// For example, the functions reachable from `main` are
+  /// `graph.reachable_from(tcx.entry_fn(()).unwrap().0)`.
+  pub fn reachable_from(&self, root: DefId) -> HashSet<DefId> {
+    let mut reachable = HashSet::from_iter([root]);
+    let mut stack = vec![root];
+    while let Some(caller) = stack.pop() {
+      for call in self.callees(caller) {
+        if reachable.insert(call.callee) {
+          stack.push(call.callee);
+        }
+      }
+    }
+    reachable
+  }
+
+  fn calls(&self) -> impl Iterator<Item = &Call> + '_ {
+    self
+      .functions
+      .iter()
+      .flat_map(|caller| self.callees(*caller))
+  }
+
+  /// Renders the graph in the DOT format of Graphviz, with one edge per call site.
+  /// Calls other than static calls are dashed.
+  pub fn to_dot(&self) -> String {
+    let mut dot = String::from("digraph call_graph {\n");
+    for (i, def_id) in self.functions.iter().enumerate() {
+      let name = self.tcx.def_path_str(*def_id);
+      writeln!(dot, "  {i} [label={name:?}];").unwrap();
+    }
+    for call in self.calls() {
+      let from = self.functions.get_index_of(&call.caller).unwrap();
+      let to = self.functions.get_index_of(&call.callee).unwrap();
+      let style = if call.kind == CallKind::Static {
+        "solid"
+      } else {
+        "dashed"
+      };
+      writeln!(dot, "  {from} -> {to} [style={style}];").unwrap();
+    }
+    dot.push_str("}\n");
+    dot
+  }
+
+  /// Returns the graph as JSON, with a list of `nodes` and a list of `edges` between
+  /// the indices of the nodes.
+  #[cfg(feature = "serde")]
+  pub fn to_json(&self) -> serde_json::Value {
+    let nodes = self
+      .functions
+      .iter()
+      .enumerate()
+      .map(|(id, def_id)| serde_json::json!({"id": id, "name": self.tcx.def_path_str(*def_id)}))
+      .collect::<Vec<_>>();
+    let edges = self
+      .calls()
+      .map(|call| {
+        serde_json::json!({
+          "caller": self.functions.get_index_of(&call.caller),
+          "callee": self.functions.get_index_of(&call.callee),
+          "location": format!("{:?}", call.location),
+          "kind": call.kind,
+        })
+      })
+      .collect::<Vec<_>>();
+    serde_json::json!({ "nodes": nodes, "edges": edges })
+  }
+}
+
+/// Returns the functions and closures that each local may point to, when the local is
+/// reified from a function item or copied from such a local.
+fn fn_pointers<'tcx>(
+  tcx: TyCtxt<'tcx>,
+  body: &Body<'tcx>,
+) -> HashMap<Local, HashSet<DefId>> {
+  let mut fn_pointers = HashMap::<_, HashSet<DefId>>::default();
+  let mut copies = Vec::new();
+  for location in body.all_locations() {
+    let Some(statement) = body.stmt_at(location).left() else {
+      continue;
+    };
+    let StatementKind::Assign(box (place, rvalue)) = &statement.kind else {
+      continue;
+    };
+    let Some(local) = place.as_local() else {
+      continue;
+    };
+    match rvalue {
+      Rvalue::Cast(
+        CastKind::PointerCoercion(
+          PointerCoercion::ReifyFnPointer(_) | PointerCoercion::ClosureFnPointer(_),
+          _,
+        ),
+        operand,
+        _,
+      ) => {
+        if let TyKind::FnDef(def_id, _) | TyKind::Closure(def_id, _) =
+          operand.ty(body, tcx).kind()
+        {
+          fn_pointers.entry(local).or_default().insert(*def_id);
+        }
+      }
+      Rvalue::Use(operand) => {
+        if let Some(src) = operand.place().and_then(|place| place.as_local()) {
+          copies.push((local, src));
+        }
+      }
+      _ => {}
+    }
+  }
+
+  let mut changed = true;
+  while changed {
+    changed = false;
+    for (dst, src) in &copies {
+      let Some(items) = fn_pointers.get(src).cloned() else {
+        continue;
+      };
+      let dst_items = fn_pointers.entry(*dst).or_default();
+      let len = dst_items.len();
+      dst_items.extend(items);
+      changed |= dst_items.len() != len;
+    }
+  }
+  fn_pointers
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils::{self, CompileResult};
+
+  #[test]
+  fn test_call_graph() {
+    let input = r"
+trait Shape { fn area(&self) -> u32; }
+struct Square;
+impl Shape for Square { fn area(&self) -> u32 { 4 } }
+struct Circle;
+impl Shape for Circle { fn area(&self) -> u32 { 3 } }
+
+fn total(shapes: &[&dyn Shape]) -> u32 { shapes.iter().map(|s| s.area()).sum() }
+fn generic<S: Shape>(shape: &S) -> u32 { shape.area() }
+fn helper() -> u32 { 1 }
+fn unused() {}
+
+fn main() {
+  let f: fn() -> u32 = helper;
+  f();
+  generic(&Square);
+  total(&[&Circle]);
+}";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let graph = CallGraph::build(tcx);
+      let find = |name: &str| {
+        graph
+          .functions()
+          .find(|def_id| tcx.def_path_str(*def_id) == name)
+          .unwrap_or_else(|| panic!("missing function {name}"))
+      };
+      let main = find("main");
+      let (helper, generic, total) = (find("helper"), find("generic"), find("total"));
+      let square_area = find("<Square as Shape>::area");
+      let circle_area = find("<Circle as Shape>::area");
+
+      let callee_kinds = |caller| {
+        graph
+          .callees(caller)
+          .map(|call| (call.callee, call.kind))
+          .collect::<HashSet<_>>()
+      };
+      let main_calls = callee_kinds(main);
+      assert!(main_calls.contains(&(helper, CallKind::FnPointer)));
+      assert!(main_calls.contains(&(generic, CallKind::Static)));
+      assert!(main_calls.contains(&(total, CallKind::Static)));
+      // The generic call can't be resolved in `generic`, so it falls back to every impl.
+      assert_eq!(
+        callee_kinds(generic),
+        HashSet::from_iter([
+          (square_area, CallKind::Dynamic),
+          (circle_area, CallKind::Dynamic)
+        ])
+      );
+      let closure = callee_kinds(total)
+        .into_iter()
+        .find(|(callee, kind)| tcx.is_closure_like(*callee) && *kind == CallKind::Closure)
+        .expect("missing closure of total")
+        .0;
+      // The closure calls a method of `dyn Shape`, which may dispatch to every impl.
+      assert_eq!(
+        callee_kinds(closure),
+        HashSet::from_iter([
+          (square_area, CallKind::Dynamic),
+          (circle_area, CallKind::Dynamic)
+        ])
+      );
+
+      let reachable = graph.reachable_from(main);
+      for def_id in [helper, generic, total, square_area, circle_area] {
+        assert!(reachable.contains(&def_id));
+      }
+      assert!(!reachable.contains(&find("unused")));
+      assert_eq!(graph.callers(helper).count(), 1);
+      assert_eq!(
+        graph
+          .callers(square_area)
+          .map(|call| call.caller)
+          .collect::<HashSet<_>>(),
+        HashSet::from_iter([generic, closure])
+      );
+
+      let dot = graph.to_dot();
+      assert!(dot.starts_with("digraph call_graph {"));
+      assert!(dot.contains("[label=\"helper\"]"));
+
+      #[cfg(feature = "serde")]
+      {
+        let json = graph.to_json();
+        assert_eq!(
+          json["nodes"].as_array().unwrap().len(),
+          graph.functions().count()
+        );
+      }
+    });
+  }
+}
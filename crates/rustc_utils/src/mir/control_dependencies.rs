@@ -13,11 +13,17 @@ use rustc_data_structures::graph::{
   dominators::Dominators, iterate, vec_graph::VecGraph,
 };
 use rustc_index::{
-  Idx,
+  Idx, IndexVec,
   bit_set::{DenseBitSet, MixedBitSet, SparseBitMatrix},
 };
+use rustc_middle::mir::{
+  BasicBlock, Body, Location, Operand, START_BLOCK, Terminator, TerminatorKind,
+  UnwindAction,
+};
 use smallvec::SmallVec;
 
+use super::body::DotAnnotations;
+
 struct ReversedGraph<'a, G: ControlFlowGraph> {
   graph: &'a G,
   exit: G::Node,
@@ -199,13 +205,286 @@ impl<Node: Idx + Ord> ControlDependencies<Node> {
   }
 }
 
+/// Options for [`LocationControlDependencies`], which decide which paths through a body
+/// count as leaving it.
+///
+/// `Return` (and tail calls) always exit the body. Code that cannot reach an exit has no
+/// control dependencies, and branches between code that reaches an exit and code that
+/// doesn't create none. By default, only returns are exits, like
+/// [`BodyExt::control_dependencies`](crate::BodyExt::control_dependencies).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ControlDependencyOptions {
+  /// Follow [`UnwindAction::Cleanup`] edges, so code can depend on whether a call,
+  /// assertion or drop unwinds. This only matters if `panics_exit` is set, since cleanup
+  /// paths otherwise never reach an exit.
+  pub follow_unwind: bool,
+
+  /// Treat panics as exits, so code after an `assert!`, an overflow check or a call
+  /// depends on it not panicking.
+  ///
+  /// `UnwindResume`, `UnwindTerminate`, `Assert` and calls that never return are exits.
+  /// Any other terminator that may unwind exits directly, unless it has a cleanup path
+  /// and `follow_unwind` is set, in which case the path is followed instead.
+  pub panics_exit: bool,
+
+  /// Treat `Unreachable` as an exit.
+  pub unreachable_exits: bool,
+}
+
+/// The successor of a branching terminator that a control dependency comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Branch {
+  /// The arm of a `SwitchInt` taken when the discriminant has the given value.
+  Value(u128),
+
+  /// The `otherwise` arm of a `SwitchInt`.
+  Otherwise,
+
+  /// The cleanup successor of a terminator that may unwind.
+  Unwind,
+
+  /// Any other successor, e.g. the normal successor of a terminator that may unwind.
+  Target(BasicBlock),
+}
+
+/// A control dependency of a location on a branch of a terminator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ControlDependency {
+  /// The location of the branching terminator.
+  pub location: Location,
+
+  /// The successor of the terminator that leads to the dependent location.
+  pub branch: Branch,
+}
+
+impl ControlDependency {
+  /// Returns the discriminant of the terminator if it is a `SwitchInt`, e.g. the
+  /// condition of an `if`, or the checked condition if it is an `Assert`.
+  pub fn condition<'a, 'tcx>(&self, body: &'a Body<'tcx>) -> Option<&'a Operand<'tcx>> {
+    match &body.basic_blocks[self.location.block].terminator().kind {
+      TerminatorKind::SwitchInt { discr, .. } => Some(discr),
+      TerminatorKind::Assert { cond, .. } => Some(cond),
+      _ => None,
+    }
+  }
+}
+
+/// The control-flow graph of a body, without the edges excluded by the options, where
+/// every exit flows into a virtual exit node.
+///
+/// The imaginary edges of `FalseEdge` are never taken, so they are always excluded.
+struct FilteredCfg {
+  successors: IndexVec<BasicBlock, SmallVec<[BasicBlock; 4]>>,
+  predecessors: IndexVec<BasicBlock, SmallVec<[BasicBlock; 4]>>,
+  exit: BasicBlock,
+}
+
+impl FilteredCfg {
+  fn new(body: &Body<'_>, options: ControlDependencyOptions) -> Self {
+    let exit = BasicBlock::new(body.basic_blocks.len());
+    let mut successors = body
+      .basic_blocks
+      .iter()
+      .map(|data| {
+        let terminator = data.terminator();
+        let mut succs = match terminator.kind {
+          TerminatorKind::FalseEdge { real_target, .. } => {
+            SmallVec::from_elem(real_target, 1)
+          }
+          _ => terminator
+            .successors()
+            .filter(|succ| options.follow_unwind || !is_cleanup_edge(terminator, *succ))
+            .collect(),
+        };
+        let is_exit = match terminator.kind {
+          TerminatorKind::Return | TerminatorKind::TailCall { .. } => true,
+          TerminatorKind::UnwindResume
+          | TerminatorKind::UnwindTerminate(_)
+          | TerminatorKind::Call { target: None, .. }
+          | TerminatorKind::Assert { .. } => options.panics_exit,
+          TerminatorKind::Unreachable => options.unreachable_exits,
+          // Other terminators that may panic leave the body directly, unless they have a
+          // cleanup path that is followed instead.
+          _ => {
+            options.panics_exit
+              && match terminator.unwind() {
+                Some(UnwindAction::Continue | UnwindAction::Terminate(_)) => true,
+                Some(UnwindAction::Cleanup(_)) => !options.follow_unwind,
+                Some(UnwindAction::Unreachable) | None => false,
+              }
+          }
+        };
+        if is_exit {
+          succs.push(exit);
+        }
+        succs
+      })
+      .collect::<IndexVec<BasicBlock, SmallVec<[BasicBlock; 4]>>>();
+    successors.push(SmallVec::new());
+
+    let mut predecessors: IndexVec<BasicBlock, SmallVec<[BasicBlock; 4]>> =
+      IndexVec::from_elem_n(SmallVec::new(), successors.len());
+    for (block, succs) in successors.iter_enumerated() {
+      for succ in succs {
+        predecessors[*succ].push(block);
+      }
+    }
+    FilteredCfg {
+      successors,
+      predecessors,
+      exit,
+    }
+  }
+}
+
+fn is_cleanup_edge(terminator: &Terminator<'_>, succ: BasicBlock) -> bool {
+  terminator.unwind() == Some(&UnwindAction::Cleanup(succ))
+}
+
+impl DirectedGraph for FilteredCfg {
+  type Node = BasicBlock;
+
+  fn num_nodes(&self) -> usize {
+    self.successors.len()
+  }
+}
+
+impl StartNode for FilteredCfg {
+  fn start_node(&self) -> Self::Node {
+    START_BLOCK
+  }
+}
+
+impl Successors for FilteredCfg {
+  fn successors(&self, node: Self::Node) -> impl Iterator<Item = Self::Node> {
+    self.successors[node].iter().copied()
+  }
+}
+
+impl Predecessors for FilteredCfg {
+  fn predecessors(&self, node: Self::Node) -> impl Iterator<Item = Self::Node> {
+    self.predecessors[node].iter().copied()
+  }
+}
+
+/// Represents the control dependencies of each location of a body, along with the
+/// branches they come from.
+///
+/// The statements of a block and its terminator all have the same control dependencies,
+/// which are the terminators of other blocks. Unlike [`ControlDependencies::build_many`],
+/// which unions the dependencies with respect to each exit, all exits are joined into one,
+/// so code can depend on a branch between two exits, e.g. returning or panicking.
+pub struct LocationControlDependencies {
+  dependencies: IndexVec<BasicBlock, Vec<ControlDependency>>,
+}
+
+impl LocationControlDependencies {
+  /// Computes the control dependencies of `body`, by walking up the post-dominator tree
+  /// from each successor of a branch, following Ferrante et al.
+  pub fn build(body: &Body<'_>, options: ControlDependencyOptions) -> Self {
+    let graph = FilteredCfg::new(body, options);
+    let blocks = &body.basic_blocks;
+    let mut dependencies = IndexVec::from_elem_n(Vec::new(), blocks.len());
+
+    let post_dominators = PostDominators::build(&graph, graph.exit);
+    let ipdom = |block| post_dominators.immediate_post_dominator(block);
+    // Blocks that can't reach an exit have no post-dominators.
+    let reaches_exit = |block| ipdom(block).is_some();
+
+    for (block, data) in blocks.iter_enumerated() {
+      let succs = &graph.successors[block];
+      if succs.len() < 2 || !reaches_exit(block) {
+        continue;
+      }
+      let location = body.terminator_loc(block);
+      let stop = ipdom(block);
+      for (succ, branch) in branches(data.terminator(), succs) {
+        if succ == graph.exit || !reaches_exit(succ) {
+          continue;
+        }
+        let dependency = ControlDependency { location, branch };
+        let mut node = Some(succ);
+        while let Some(dependent) = node
+          && node != stop
+          && dependent != graph.exit
+        {
+          let deps: &mut Vec<_> = &mut dependencies[dependent];
+          if !deps.contains(&dependency) {
+            deps.push(dependency);
+          }
+          node = ipdom(dependent);
+        }
+      }
+    }
+
+    LocationControlDependencies { dependencies }
+  }
+
+  /// Returns the branches that `location` is control-dependent on.
+  pub fn dependencies(&self, location: Location) -> &[ControlDependency] {
+    &self.dependencies[location.block]
+  }
+
+  /// Returns the blocks whose locations are control-dependent on the terminator at
+  /// `location`, along with the branch leading to each block.
+  pub fn dependents(
+    &self,
+    location: Location,
+  ) -> impl Iterator<Item = (BasicBlock, Branch)> + '_ {
+    self
+      .dependencies
+      .iter_enumerated()
+      .flat_map(move |(block, deps)| {
+        deps
+          .iter()
+          .filter(move |dep| dep.location == location)
+          .map(move |dep| (block, dep.branch))
+      })
+  }
+}
+
+impl<'tcx> DotAnnotations<'tcx> for LocationControlDependencies {
+  fn block(&self, _body: &Body<'tcx>, block: BasicBlock) -> Vec<String> {
+    self.dependencies[block]
+      .iter()
+      .map(|dep| format!("depends on {:?} ({:?})", dep.location, dep.branch))
+      .collect()
+  }
+}
+
+/// Labels each successor of a terminator with its branch.
+fn branches(
+  terminator: &Terminator<'_>,
+  succs: &[BasicBlock],
+) -> SmallVec<[(BasicBlock, Branch); 4]> {
+  match &terminator.kind {
+    TerminatorKind::SwitchInt { targets, .. } => targets
+      .iter()
+      .map(|(value, target)| (target, Branch::Value(value)))
+      .chain([(targets.otherwise(), Branch::Otherwise)])
+      .collect(),
+    _ => succs
+      .iter()
+      .map(|succ| {
+        let branch = if is_cleanup_edge(terminator, *succ) {
+          Branch::Unwind
+        } else {
+          Branch::Target(*succ)
+        };
+        (*succ, branch)
+      })
+      .collect(),
+  }
+}
+
 #[cfg(test)]
 mod test {
   use log::debug;
   use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet};
-  use rustc_middle::mir::Location;
+  use rustc_middle::mir::{Location, TerminatorKind};
   use test_log::test;
 
+  use super::{Branch, ControlDependencyOptions};
   use crate::{BodyExt, test_utils};
 
   #[test]
@@ -290,4 +569,98 @@ fn main() {
       }
     });
   }
+
+  #[test]
+  fn test_location_control_dependencies() {
+    let input = r"
+fn foo(x: i32) -> i32 {
+  let mut y = 0;
+  if x > 0 { y = 1; }
+  assert!(x != 5);
+  y
+}";
+    test_utils::compile_body(input, move |tcx, _, body_with_facts| {
+      let body = &body_with_facts.body;
+      let y_eq_1 = body
+        .all_locations()
+        .find(|loc| {
+          tcx
+            .sess
+            .source_map()
+            .span_to_snippet(body.source_info(*loc).span)
+            .is_ok_and(|snippet| snippet == "y = 1")
+        })
+        .unwrap();
+      let ret = body.all_returns().next().unwrap();
+
+      let deps = body.location_control_dependencies(ControlDependencyOptions::default());
+      let [if_dep] = deps.dependencies(y_eq_1) else {
+        panic!("{:?}", deps.dependencies(y_eq_1))
+      };
+      // `if x > 0` switches on a boolean, whose false arm is 0.
+      assert_eq!(if_dep.branch, Branch::Otherwise);
+      assert!(if_dep.condition(body).is_some());
+      assert!(
+        deps
+          .dependents(if_dep.location)
+          .any(|(block, _)| block == y_eq_1.block)
+      );
+      // The failing assertion never returns, so the return doesn't depend on it.
+      assert!(deps.dependencies(ret).is_empty());
+
+      let deps = body.location_control_dependencies(ControlDependencyOptions {
+        panics_exit: true,
+        ..Default::default()
+      });
+      let [assert_dep] = deps.dependencies(ret) else {
+        panic!("{:?}", deps.dependencies(ret))
+      };
+      assert_ne!(assert_dep.location, if_dep.location);
+      assert!(assert_dep.condition(body).is_some());
+    });
+  }
+
+  #[test]
+  fn test_panicking_terminators() {
+    let input = r"
+fn foo(v: &[i32], i: usize, x: i32) -> i32 {
+  let y = x + 1;
+  v[i] + y
+}";
+    test_utils::compile_body(input, move |_, _, body_with_facts| {
+      let body = &body_with_facts.body;
+      let asserts = body
+        .basic_blocks
+        .iter_enumerated()
+        .filter(|(_, data)| {
+          matches!(data.terminator().kind, TerminatorKind::Assert { .. })
+        })
+        .map(|(block, _)| body.terminator_loc(block))
+        .collect::<Vec<_>>();
+      // The overflow check of `x + 1`, the bounds check of `v[i]`, and the overflow check
+      // of the addition.
+      let [overflow, bounds, sum] = asserts[..] else {
+        panic!("{asserts:?}")
+      };
+      let ret = body.all_returns().next().unwrap();
+
+      // Failed checks unwind without cleanup, which never reaches a return.
+      let deps = body.location_control_dependencies(ControlDependencyOptions::default());
+      assert!(deps.dependencies(ret).is_empty());
+
+      // Each check depends on the previous one not panicking.
+      let deps = body.location_control_dependencies(ControlDependencyOptions {
+        panics_exit: true,
+        ..Default::default()
+      });
+      for (location, check) in [(bounds, overflow), (sum, bounds), (ret, sum)] {
+        let [dep] = deps.dependencies(location) else {
+          panic!("{:?}", deps.dependencies(location))
+        };
+        assert_eq!(dep.location, check);
+        assert!(dep.condition(body).is_some());
+        assert!(matches!(dep.branch, Branch::Target(_)));
+      }
+    });
+  }
 }
@@ -0,0 +1,580 @@
+//! Reusable dataflow analyses over the places of a MIR body.
+//!
+//! The analyses are computed with [`BodyExt`] methods:
+//! - [`BodyExt::reaching_definitions`] finds the definitions of places that may reach
+//!   each location.
+//! - [`BodyExt::liveness`] finds the places that may be read after each location.
+//! - [`BodyExt::def_use_chains`] links each location to the definitions that reach the
+//!   places it reads.
+//!
+//! A place is *defined* by an assignment to it, including the destination of a call,
+//! and each argument is defined on entry to the body. Writes through a dereference, and
+//! writes of only the discriminant of an enum, may leave the previous value in place, so
+//! they don't override earlier definitions. Places *conflict* if one is a prefix of the
+//! other, e.g. defining `x` also defines `x.0`, and reading `x` also reads `x.0`.
+
+use std::{cell::RefCell, iter};
+
+use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet};
+use rustc_hir::def_id::DefId;
+use rustc_index::{IndexVec, bit_set::MixedBitSet};
+use rustc_middle::{
+  mir::{
+    Body, Local, Location, Place, ProjectionElem, Statement, Terminator, TerminatorEdges,
+    visit::{MutatingUseContext, PlaceContext, Visitor},
+  },
+  ty::TyCtxt,
+};
+use rustc_mir_dataflow::{Analysis, Backward, ResultsCursor, fmt::DebugWithContext};
+#[cfg(feature = "indexical")]
+use {
+  super::location_or_arg::index::{LocationOrArgDomain, LocationOrArgSet},
+  std::sync::Arc,
+};
+
+use super::location_or_arg::LocationOrArg;
+use crate::{BodyExt, PlaceExt, mir::body::DotAnnotations};
+
+/// Returns true if `prefix` is a prefix of `place`, or equal to it.
+pub(crate) fn is_prefix<'tcx>(prefix: Place<'tcx>, place: Place<'tcx>) -> bool {
+  prefix.local == place.local && place.projection.starts_with(prefix.projection)
+}
+
+/// Returns true if one of the places is a prefix of the other.
+pub(crate) fn conflicts<'tcx>(a: Place<'tcx>, b: Place<'tcx>) -> bool {
+  is_prefix(a, b) || is_prefix(b, a)
+}
+
+/// The places read and written at a location.
+pub(crate) struct Accesses<'tcx> {
+  tcx: TyCtxt<'tcx>,
+  pub(crate) reads: Vec<Place<'tcx>>,
+  /// Each written place, and whether the write overrides its previous value.
+  pub(crate) writes: Vec<(Place<'tcx>, bool)>,
+}
+
+impl<'tcx> Accesses<'tcx> {
+  pub(crate) fn at(tcx: TyCtxt<'tcx>, body: &Body<'tcx>, location: Location) -> Self {
+    let mut accesses = Accesses {
+      tcx,
+      reads: Vec::new(),
+      writes: Vec::new(),
+    };
+    let block = &body.basic_blocks[location.block];
+    match block.statements.get(location.statement_index) {
+      Some(statement) => accesses.visit_statement(statement, location),
+      None => accesses.visit_terminator(block.terminator(), location),
+    }
+    accesses
+  }
+}
+
+impl<'tcx> Visitor<'tcx> for Accesses<'tcx> {
+  fn visit_place(
+    &mut self,
+    place: &Place<'tcx>,
+    context: PlaceContext,
+    _location: Location,
+  ) {
+    // Projections read the pointers they dereference and the indices they use.
+    for (base, elem) in place.iter_projections() {
+      match elem {
+        ProjectionElem::Deref => self.reads.push(Place::from_ref(base, self.tcx)),
+        ProjectionElem::Index(local) => {
+          self.reads.push(Place::from_local(local, self.tcx));
+        }
+        _ => {}
+      }
+    }
+
+    match context {
+      PlaceContext::MutatingUse(
+        MutatingUseContext::Store
+        | MutatingUseContext::Call
+        | MutatingUseContext::AsmOutput
+        | MutatingUseContext::Yield,
+      ) => self.writes.push((*place, !place.is_indirect())),
+      PlaceContext::MutatingUse(MutatingUseContext::SetDiscriminant) => {
+        self.writes.push((*place, false));
+      }
+      PlaceContext::NonMutatingUse(_)
+      | PlaceContext::MutatingUse(
+        MutatingUseContext::Borrow
+        | MutatingUseContext::RawBorrow
+        | MutatingUseContext::Drop,
+      ) => self.reads.push(*place),
+      PlaceContext::MutatingUse(
+        MutatingUseContext::Projection | MutatingUseContext::Retag,
+      )
+      | PlaceContext::NonUse(_) => {}
+    }
+  }
+}
+
+rustc_index::newtype_index! {
+  /// A definition of a place, as indexed by [`DefsAnalysis`].
+  #[debug_format = "d{}"]
+  struct DefIndex {}
+}
+
+rustc_index::newtype_index! {
+  /// A place that may be live, as indexed by [`LiveAnalysis`].
+  #[debug_format = "p{}"]
+  struct PlaceIndex {}
+}
+
+impl<C> DebugWithContext<C> for DefIndex {}
+impl<C> DebugWithContext<C> for PlaceIndex {}
+
+/// The reaching definitions analysis, whose states are sets of definitions.
+struct DefsAnalysis<'tcx> {
+  /// Every definition of the body, as pairs of where and what was defined.
+  defs: IndexVec<DefIndex, (LocationOrArg, Place<'tcx>)>,
+  /// The definitions of each local.
+  defs_of_local: IndexVec<Local, Vec<DefIndex>>,
+  /// The definitions at each location, and whether they override earlier definitions.
+  defs_at: HashMap<Location, Vec<(DefIndex, bool)>>,
+}
+
+impl<'tcx> DefsAnalysis<'tcx> {
+  fn new(tcx: TyCtxt<'tcx>, body: &Body<'tcx>) -> Self {
+    let mut defs = IndexVec::<DefIndex, _>::new();
+    let mut defs_of_local = IndexVec::from_elem_n(Vec::new(), body.local_decls.len());
+    let mut add_def = |def: LocationOrArg, place: Place<'tcx>| {
+      let index = defs.push((def, place));
+      defs_of_local[place.local].push(index);
+      index
+    };
+
+    for local in body.args_iter() {
+      add_def(LocationOrArg::Arg(local), Place::from_local(local, tcx));
+    }
+    let mut defs_at = HashMap::default();
+    for location in body.all_locations() {
+      let writes = Accesses::at(tcx, body, location).writes;
+      if !writes.is_empty() {
+        let location_defs = writes
+          .into_iter()
+          .map(|(place, strong)| {
+            (add_def(LocationOrArg::Location(location), place), strong)
+          })
+          .collect();
+        defs_at.insert(location, location_defs);
+      }
+    }
+
+    DefsAnalysis {
+      defs,
+      defs_of_local,
+      defs_at,
+    }
+  }
+
+  fn transfer(&self, state: &mut MixedBitSet<DefIndex>, location: Location) {
+    for &(def, strong) in self.defs_at.get(&location).into_iter().flatten() {
+      let place = self.defs[def].1;
+      if strong {
+        for &other in &self.defs_of_local[place.local] {
+          if is_prefix(place, self.defs[other].1) {
+            state.remove(other);
+          }
+        }
+      }
+      state.insert(def);
+    }
+  }
+}
+
+impl<'tcx> Analysis<'tcx> for DefsAnalysis<'tcx> {
+  type Domain = MixedBitSet<DefIndex>;
+
+  const NAME: &'static str = "reaching_definitions";
+
+  fn bottom_value(&self, _body: &Body<'tcx>) -> Self::Domain {
+    MixedBitSet::new_empty(self.defs.len())
+  }
+
+  fn initialize_start_block(&self, _body: &Body<'tcx>, state: &mut Self::Domain) {
+    for (index, (def, _)) in self.defs.iter_enumerated() {
+      if matches!(def, LocationOrArg::Arg(_)) {
+        state.insert(index);
+      }
+    }
+  }
+
+  fn apply_primary_statement_effect(
+    &self,
+    state: &mut Self::Domain,
+    _statement: &Statement<'tcx>,
+    location: Location,
+  ) {
+    self.transfer(state, location);
+  }
+
+  fn apply_primary_terminator_effect<'mir>(
+    &self,
+    state: &mut Self::Domain,
+    terminator: &'mir Terminator<'tcx>,
+    location: Location,
+  ) -> TerminatorEdges<'mir, 'tcx> {
+    self.transfer(state, location);
+    terminator.edges()
+  }
+}
+
+/// The definitions that may reach each location of a body, computed by
+/// [`BodyExt::reaching_definitions`].
+///
+/// Only the definitions on entry to each block are stored, so each query recomputes the
+/// definitions up to the queried location within its block.
+pub struct ReachingDefinitions<'a, 'tcx> {
+  cursor: RefCell<ResultsCursor<'a, 'tcx, DefsAnalysis<'tcx>>>,
+  #[cfg(feature = "indexical")]
+  domain: Arc<LocationOrArgDomain>,
+}
+
+impl<'a, 'tcx> ReachingDefinitions<'a, 'tcx> {
+  pub(crate) fn new(tcx: TyCtxt<'tcx>, body: &'a Body<'tcx>) -> Self {
+    let cursor = DefsAnalysis::new(tcx, body)
+      .iterate_to_fixpoint(tcx, body, None)
+      .into_results_cursor(body);
+
+    ReachingDefinitions {
+      cursor: RefCell::new(cursor),
+      #[cfg(feature = "indexical")]
+      domain: Arc::new(
+        body
+          .args_iter()
+          .map(LocationOrArg::Arg)
+          .chain(body.all_locations().map(LocationOrArg::Location))
+          .collect(),
+      ),
+    }
+  }
+
+  /// Returns every definition that may reach `location`, along with the defined place.
+  pub(crate) fn defs_at(&self, location: Location) -> Vec<(LocationOrArg, Place<'tcx>)> {
+    let mut cursor = self.cursor.borrow_mut();
+    cursor.seek_before_primary_effect(location);
+    let defs = &cursor.analysis().defs;
+    cursor.get().iter().map(|def| defs[def]).collect()
+  }
+
+  /// Returns the definitions of places conflicting with `place` that may reach
+  /// `location`, before it is executed.
+  pub fn reaching_defs_at(
+    &self,
+    location: Location,
+    place: Place<'tcx>,
+  ) -> HashSet<LocationOrArg> {
+    self
+      .defs_at(location)
+      .into_iter()
+      .filter(|(_, defined)| conflicts(*defined, place))
+      .map(|(def, _)| def)
+      .collect()
+  }
+
+  /// Returns the domain of [`ReachingDefinitions::reaching_defs_at_indexed`], which
+  /// contains every argument and location of the body.
+  #[cfg(feature = "indexical")]
+  pub fn domain(&self) -> &Arc<LocationOrArgDomain> {
+    &self.domain
+  }
+
+  /// Same as [`ReachingDefinitions::reaching_defs_at`], but as an indexed set.
+  #[cfg(feature = "indexical")]
+  pub fn reaching_defs_at_indexed(
+    &self,
+    location: Location,
+    place: Place<'tcx>,
+  ) -> LocationOrArgSet {
+    let mut set = LocationOrArgSet::new(&self.domain);
+    for def in self.reaching_defs_at(location, place) {
+      set.insert(def);
+    }
+    set
+  }
+}
+
+/// The liveness analysis, whose states are sets of places.
+struct LiveAnalysis<'tcx> {
+  /// Every place that may be live.
+  places: IndexVec<PlaceIndex, Place<'tcx>>,
+  /// The places of each local.
+  places_of_local: IndexVec<Local, Vec<PlaceIndex>>,
+  /// The places overwritten at each location, and the places it makes live.
+  effects: HashMap<Location, (Vec<Place<'tcx>>, Vec<PlaceIndex>)>,
+}
+
+impl<'tcx> LiveAnalysis<'tcx> {
+  fn new(tcx: TyCtxt<'tcx>, body: &Body<'tcx>, def_id: DefId) -> Self {
+    let mut places = IndexVec::<PlaceIndex, _>::new();
+    let mut places_of_local = IndexVec::from_elem_n(Vec::new(), body.local_decls.len());
+    let mut indices = HashMap::default();
+    let mut effects = HashMap::default();
+    for location in body.all_locations() {
+      let accesses = Accesses::at(tcx, body, location);
+      let kills = accesses
+        .writes
+        .into_iter()
+        .filter_map(|(place, strong)| strong.then_some(place))
+        .collect::<Vec<_>>();
+      let gens = accesses
+        .reads
+        .into_iter()
+        .flat_map(|place| {
+          iter::once(place).chain(place.interior_places(tcx, body, def_id))
+        })
+        .map(|place| {
+          *indices.entry(place).or_insert_with(|| {
+            let index = places.push(place);
+            places_of_local[place.local].push(index);
+            index
+          })
+        })
+        .collect::<Vec<_>>();
+      if !kills.is_empty() || !gens.is_empty() {
+        effects.insert(location, (kills, gens));
+      }
+    }
+
+    LiveAnalysis {
+      places,
+      places_of_local,
+      effects,
+    }
+  }
+
+  fn transfer(&self, state: &mut MixedBitSet<PlaceIndex>, location: Location) {
+    let Some((kills, gens)) = self.effects.get(&location) else {
+      return;
+    };
+    for killed in kills {
+      for &place in &self.places_of_local[killed.local] {
+        if is_prefix(*killed, self.places[place]) {
+          state.remove(place);
+        }
+      }
+    }
+    for &place in gens {
+      state.insert(place);
+    }
+  }
+}
+
+impl<'tcx> Analysis<'tcx> for LiveAnalysis<'tcx> {
+  type Domain = MixedBitSet<PlaceIndex>;
+  type Direction = Backward;
+
+  const NAME: &'static str = "liveness";
+
+  fn bottom_value(&self, _body: &Body<'tcx>) -> Self::Domain {
+    MixedBitSet::new_empty(self.places.len())
+  }
+
+  fn initialize_start_block(&self, _body: &Body<'tcx>, _state: &mut Self::Domain) {}
+
+  fn apply_primary_statement_effect(
+    &self,
+    state: &mut Self::Domain,
+    _statement: &Statement<'tcx>,
+    location: Location,
+  ) {
+    self.transfer(state, location);
+  }
+
+  fn apply_primary_terminator_effect<'mir>(
+    &self,
+    state: &mut Self::Domain,
+    terminator: &'mir Terminator<'tcx>,
+    location: Location,
+  ) -> TerminatorEdges<'mir, 'tcx> {
+    self.transfer(state, location);
+    terminator.edges()
+  }
+}
+
+/// The places that may be live at each location of a body, computed by
+/// [`BodyExt::liveness`].
+///
+/// Reading a place makes all of its [interior places](PlaceExt::interior_places) live,
+/// so that overwriting one field of a live struct only kills that field. As for
+/// [`ReachingDefinitions`], only the states at block boundaries are stored.
+pub struct Liveness<'a, 'tcx> {
+  cursor: RefCell<ResultsCursor<'a, 'tcx, LiveAnalysis<'tcx>>>,
+}
+
+impl<'a, 'tcx> Liveness<'a, 'tcx> {
+  pub(crate) fn new(tcx: TyCtxt<'tcx>, body: &'a Body<'tcx>, def_id: DefId) -> Self {
+    let cursor = LiveAnalysis::new(tcx, body, def_id)
+      .iterate_to_fixpoint(tcx, body, None)
+      .into_results_cursor(body);
+    Liveness {
+      cursor: RefCell::new(cursor),
+    }
+  }
+
+  /// Returns the places that are live before `location` is executed.
+  pub fn live_at(&self, location: Location) -> HashSet<Place<'tcx>> {
+    // The analysis is backward, so the state before the location in program order is
+    // the state after its effect.
+    let mut cursor = self.cursor.borrow_mut();
+    cursor.seek_after_primary_effect(location);
+    let places = &cursor.analysis().places;
+    cursor.get().iter().map(|place| places[place]).collect()
+  }
+
+  /// Returns true if a place conflicting with `place` is live before `location` is
+  /// executed.
+  pub fn is_live_at(&self, location: Location, place: Place<'tcx>) -> bool {
+    self
+      .live_at(location)
+      .iter()
+      .any(|live| conflicts(*live, place))
+  }
+}
+
+impl<'tcx> DotAnnotations<'tcx> for Liveness<'_, 'tcx> {
+  fn location(&self, _body: &Body<'tcx>, location: Location) -> Vec<String> {
+    let mut live = self
+      .live_at(location)
+      .iter()
+      .map(|place| format!("{place:?}"))
+      .collect::<Vec<_>>();
+    live.sort();
+    vec![format!("live: {}", live.join(", "))]
+  }
+}
+
+/// The definitions used at each location of a body, and vice versa, computed by
+/// [`BodyExt::def_use_chains`].
+pub struct DefUseChains {
+  defs_of_use: HashMap<Location, HashSet<LocationOrArg>>,
+  uses_of_def: HashMap<LocationOrArg, HashSet<Location>>,
+}
+
+impl DefUseChains {
+  pub(crate) fn new<'tcx>(tcx: TyCtxt<'tcx>, body: &Body<'tcx>) -> Self {
+    let reaching_defs = ReachingDefinitions::new(tcx, body);
+    let mut defs_of_use = HashMap::<_, HashSet<_>>::default();
+    let mut uses_of_def = HashMap::<_, HashSet<_>>::default();
+    for location in body.all_locations() {
+      for place in Accesses::at(tcx, body, location).reads {
+        for def in reaching_defs.reaching_defs_at(location, place) {
+          defs_of_use.entry(location).or_default().insert(def);
+          uses_of_def.entry(def).or_default().insert(location);
+        }
+      }
+    }
+    DefUseChains {
+      defs_of_use,
+      uses_of_def,
+    }
+  }
+
+  /// Returns the definitions that may reach the places read at `location`.
+  pub fn defs_of_use(
+    &self,
+    location: Location,
+  ) -> impl Iterator<Item = LocationOrArg> + '_ {
+    self
+      .defs_of_use
+      .get(&location)
+      .into_iter()
+      .flatten()
+      .copied()
+  }
+
+  /// Returns the locations that may read the place defined by `def`.
+  pub fn uses_of_def(&self, def: LocationOrArg) -> impl Iterator<Item = Location> + '_ {
+    self.uses_of_def.get(&def).into_iter().flatten().copied()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use either::Either;
+  use rustc_middle::mir::{Local, StatementKind};
+
+  use super::*;
+  use crate::test_utils;
+
+  fn assignments(body: &Body<'_>, local: Local) -> Vec<Location> {
+    let mut locations = body
+      .all_locations()
+      .filter(|location| match body.stmt_at(*location) {
+        Either::Left(stmt) => {
+          matches!(&stmt.kind, StatementKind::Assign(box (place, _)) if place.local == local)
+        }
+        Either::Right(_) => false,
+      })
+      .collect::<Vec<_>>();
+    locations.sort();
+    locations
+  }
+
+  #[test]
+  fn test_dataflow() {
+    let input = r"
+fn foo(a: i32) {
+  let mut x = a;
+  let y = x;
+  x = 2;
+  let z = x + y;
+}";
+    test_utils::compile_body(input, |tcx, body_id, body_with_facts| {
+      let body = &body_with_facts.body;
+      let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
+      let name_map = body.debug_info_name_map();
+      let place = |name: &str| Place::from_local(name_map[name], tcx);
+      let (a, x, y) = (place("a"), place("x"), place("y"));
+
+      let x_defs = assignments(body, x.local);
+      let [def1, def2] = x_defs[..] else {
+        panic!("expected two definitions of x: {x_defs:?}")
+      };
+      let y_def = assignments(body, y.local)[0];
+
+      // Reaching definitions
+      let reaching = body.reaching_definitions(tcx);
+      assert_eq!(
+        reaching.reaching_defs_at(def1, a),
+        HashSet::from_iter([LocationOrArg::Arg(a.local)])
+      );
+      assert_eq!(
+        reaching.reaching_defs_at(y_def, x),
+        HashSet::from_iter([LocationOrArg::Location(def1)])
+      );
+      assert_eq!(
+        reaching.reaching_defs_at(def2.successor_within_block(), x),
+        HashSet::from_iter([LocationOrArg::Location(def2)])
+      );
+      #[cfg(feature = "indexical")]
+      assert!(
+        reaching
+          .reaching_defs_at_indexed(def1, a)
+          .contains(LocationOrArg::Arg(a.local))
+      );
+
+      // Liveness
+      let liveness = body.liveness(tcx, def_id);
+      assert!(liveness.is_live_at(y_def, x));
+      assert!(!liveness.is_live_at(def2, x));
+      assert!(liveness.is_live_at(def2, y));
+
+      // Def-use chains
+      let chains = body.def_use_chains(tcx);
+      let uses = chains
+        .uses_of_def(LocationOrArg::Location(def1))
+        .collect::<Vec<_>>();
+      assert!(uses.contains(&y_def));
+      assert!(uses.iter().all(|location| *location < def2), "{uses:?}");
+      assert!(
+        chains
+          .defs_of_use(y_def)
+          .any(|def| def == LocationOrArg::Location(def1))
+      );
+    });
+  }
+}
@@ -1,10 +1,14 @@
 //! Utilities for MIR-level data structures.
 
 pub mod adt_def;
+pub mod aliases;
 pub mod body;
 pub mod borrowck_facts;
+pub mod call_graph;
 pub mod control_dependencies;
+pub mod dataflow;
 pub mod location_or_arg;
 pub mod mutability;
 pub mod operand;
 pub mod place;
+pub mod slicing;
@@ -626,7 +626,6 @@ impl<'tcx, Dispatcher: RegionVisitorDispatcher<'tcx>> TypeVisitor<TyCtxt<'tcx>>
 
 #[cfg(test)]
 mod test {
-  use rustc_borrowck::consumers::BodyWithBorrowckFacts;
   use rustc_hir::BodyId;
   use rustc_middle::{
     mir::{Place, PlaceElem},
@@ -635,6 +634,7 @@ mod test {
 
   use crate::{
     BodyExt, PlaceExt,
+    mir::borrowck_facts::BodyWithFacts,
     test_utils::{self, Placer, compare_sets},
   };
 
@@ -752,7 +752,7 @@ fn main() {
     fn callback<'tcx>(
       tcx: TyCtxt<'tcx>,
       body_id: BodyId,
-      body_with_facts: &BodyWithBorrowckFacts<'tcx>,
+      body_with_facts: &BodyWithFacts<'tcx>,
     ) {
       let body = &body_with_facts.body;
       let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
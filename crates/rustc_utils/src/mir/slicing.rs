@@ -0,0 +1,441 @@
+//! Backward and forward program slicing over MIR.
+//!
+//! A location depends on the definitions that may reach the places it reads (see
+//! [`ReachingDefinitions`]), and on the branches it is control-dependent on (see
+//! [`LocationControlDependencies`](super::control_dependencies::LocationControlDependencies)).
+//! Reads and writes through references are resolved with [`Aliases`], so writing `*r`
+//! where `r = &mut x` is a definition of `x`.
+//!
+//! A backward slice contains everything the criterion depends on, transitively, and a
+//! forward slice contains everything that depends on the criterion. Slices are returned
+//! as sets of [`LocationOrArg`] along with their source spans, computed by [`Spanner`].
+
+use anyhow::{Result, bail};
+use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet};
+use rustc_hir::BodyId;
+use rustc_index::bit_set::DenseBitSet;
+use rustc_middle::{
+  mir::{Body, Location, Place},
+  ty::TyCtxt,
+};
+use rustc_span::Span;
+
+use super::{
+  aliases::Aliases,
+  borrowck_facts::BodyWithFacts,
+  control_dependencies::ControlDependencyOptions,
+  dataflow::{Accesses, ReachingDefinitions, conflicts, is_prefix},
+  location_or_arg::LocationOrArg,
+};
+use crate::{
+  BodyExt,
+  source_map::{
+    range::{CharRange, ToSpan},
+    spanner::{EnclosingHirSpans, Spanner},
+  },
+};
+
+/// What to slice on.
+#[derive(Debug, Clone, Copy)]
+pub enum SliceCriterion<'tcx> {
+  /// The value of a place after a location is executed, or on entry to the body. In the
+  /// latter case, the place must be the argument or a part of it.
+  Place(Place<'tcx>, LocationOrArg),
+
+  /// The places in a source range, as found by [`Spanner::span_to_places`].
+  Range(CharRange),
+}
+
+/// The result of slicing a body.
+#[derive(Debug, Clone, Default)]
+pub struct Slice {
+  /// The locations and arguments in the slice.
+  pub locations: HashSet<LocationOrArg>,
+
+  /// The source spans of the slice, sorted and without duplicates.
+  pub spans: Vec<Span>,
+}
+
+impl Slice {
+  /// Converts the spans of the slice to char ranges, skipping spans that can't be
+  /// converted.
+  pub fn ranges(&self, tcx: TyCtxt<'_>) -> Vec<CharRange> {
+    let source_map = tcx.sess.source_map();
+    self
+      .spans
+      .iter()
+      .filter_map(|span| CharRange::from_span(*span, source_map).ok())
+      .collect()
+  }
+}
+
+/// Computes slices of a body.
+pub struct Slicer<'a, 'tcx> {
+  tcx: TyCtxt<'tcx>,
+  body: &'a Body<'tcx>,
+  spanner: Spanner<'tcx>,
+  reaching_defs: ReachingDefinitions<'a, 'tcx>,
+  aliases: Aliases<'a, 'tcx>,
+  /// The locations and arguments that each location depends on.
+  dependencies: HashMap<Location, HashSet<LocationOrArg>>,
+  /// The inverse of `dependencies`.
+  dependents: HashMap<LocationOrArg, HashSet<Location>>,
+}
+
+impl<'a, 'tcx> Slicer<'a, 'tcx> {
+  /// Computes the dependencies between the locations of a body.
+  ///
+  /// The body must have been obtained with
+  /// [`get_body_with_borrowck_facts`](super::borrowck_facts::get_body_with_borrowck_facts).
+  pub fn new(
+    tcx: TyCtxt<'tcx>,
+    body_id: BodyId,
+    body_with_facts: &'a BodyWithFacts<'tcx>,
+  ) -> Self {
+    let body = &body_with_facts.body;
+    let def_id = tcx.hir_body_owner_def_id(body_id).to_def_id();
+    let mut slicer = Slicer {
+      tcx,
+      body,
+      spanner: Spanner::new(tcx, body_id, body),
+      reaching_defs: body.reaching_definitions(tcx),
+      aliases: Aliases::build(tcx, def_id, body_with_facts),
+      dependencies: HashMap::default(),
+      dependents: HashMap::default(),
+    };
+
+    let control_deps =
+      body.location_control_dependencies(ControlDependencyOptions::default());
+    for location in body.all_locations() {
+      let mut deps = HashSet::default();
+      for place in Accesses::at(tcx, body, location).reads {
+        deps.extend(slicer.defs_before(location, place));
+      }
+      deps.extend(
+        control_deps
+          .dependencies(location)
+          .iter()
+          .map(|dep| LocationOrArg::Location(dep.location)),
+      );
+      for dep in &deps {
+        slicer.dependents.entry(*dep).or_default().insert(location);
+      }
+      slicer.dependencies.insert(location, deps);
+    }
+
+    slicer
+  }
+
+  /// Returns the definitions of places that may alias `place` and reach `location`.
+  fn defs_before(
+    &self,
+    location: Location,
+    place: Place<'tcx>,
+  ) -> HashSet<LocationOrArg> {
+    let targets = self.aliases.aliases(place, location);
+    self
+      .reaching_defs
+      .defs_at(location)
+      .into_iter()
+      .filter(|(_, defined)| {
+        self
+          .aliases
+          .aliases(*defined, location)
+          .iter()
+          .any(|defined| targets.iter().any(|target| conflicts(*defined, *target)))
+      })
+      .map(|(def, _)| def)
+      .collect()
+  }
+
+  /// Returns whether `location` may write `place`, and whether it definitely
+  /// overwrites all of it.
+  fn writes(&self, location: Location, place: Place<'tcx>) -> (bool, bool) {
+    let targets = self.aliases.aliases(place, location);
+    let mut overwritten = false;
+    let mut written = false;
+    for (defined, strong) in Accesses::at(self.tcx, self.body, location).writes {
+      for defined in self.aliases.aliases(defined, location) {
+        if targets.iter().any(|target| conflicts(defined, *target)) {
+          written = true;
+          overwritten |=
+            strong && targets.iter().all(|target| is_prefix(defined, *target));
+        }
+      }
+    }
+    (written, overwritten)
+  }
+
+  /// Returns the definitions of `place` that may be visible after `location`.
+  fn defs_after(&self, location: Location, place: Place<'tcx>) -> HashSet<LocationOrArg> {
+    let (written, overwritten) = self.writes(location, place);
+    let mut defs = if overwritten {
+      HashSet::default()
+    } else {
+      self.defs_before(location, place)
+    };
+    if written {
+      defs.insert(LocationOrArg::Location(location));
+    }
+    defs
+  }
+
+  /// Returns the locations after `location` that may read a definition of `place` made
+  /// before `location`, which is still visible if `location` doesn't overwrite it.
+  fn uses_after(&self, location: Location, place: Place<'tcx>) -> HashSet<LocationOrArg> {
+    let blocks = &self.body.basic_blocks;
+    let mut reachable = DenseBitSet::new_empty(blocks.len());
+    let mut stack = blocks[location.block]
+      .terminator()
+      .successors()
+      .collect::<Vec<_>>();
+    while let Some(block) = stack.pop() {
+      if reachable.insert(block) {
+        stack.extend(blocks[block].terminator().successors());
+      }
+    }
+    let is_after = |other: &Location| {
+      reachable.contains(other.block)
+        || (other.block == location.block
+          && other.statement_index > location.statement_index)
+    };
+
+    self
+      .defs_after(location, place)
+      .into_iter()
+      .filter(|def| *def != LocationOrArg::Location(location))
+      .flat_map(|def| self.dependents.get(&def).into_iter().flatten())
+      .filter(|other| is_after(other))
+      .map(|other| LocationOrArg::Location(*other))
+      .collect()
+  }
+
+  /// Returns the places and locations that a criterion refers to.
+  fn criteria(
+    &self,
+    criterion: &SliceCriterion<'tcx>,
+  ) -> Result<Vec<(Place<'tcx>, LocationOrArg)>> {
+    match criterion {
+      SliceCriterion::Place(place, LocationOrArg::Arg(local))
+        if place.local != *local =>
+      {
+        bail!("{place:?} is not a part of the argument {local:?}")
+      }
+      SliceCriterion::Place(place, location) => Ok(vec![(*place, *location)]),
+      SliceCriterion::Range(range) => {
+        let span = range.to_span(self.tcx)?;
+        Ok(
+          self
+            .spanner
+            .span_to_places(span)
+            .into_iter()
+            .flat_map(|spanned| {
+              spanned
+                .locations
+                .iter()
+                .map(move |location| (spanned.place, *location))
+            })
+            .collect(),
+        )
+      }
+    }
+  }
+
+  fn slice(
+    &self,
+    mut locations: HashSet<LocationOrArg>,
+    next: impl Fn(LocationOrArg) -> Vec<LocationOrArg>,
+  ) -> Slice {
+    let mut stack = locations.iter().copied().collect::<Vec<_>>();
+    while let Some(location) = stack.pop() {
+      for next in next(location) {
+        if locations.insert(next) {
+          stack.push(next);
+        }
+      }
+    }
+
+    let mut spans = locations
+      .iter()
+      .flat_map(|location| {
+        self
+          .spanner
+          .location_to_spans(*location, self.body, EnclosingHirSpans::OuterOnly)
+      })
+      .collect::<Vec<_>>();
+    spans.sort_by_key(|span| (span.lo(), span.hi()));
+    spans.dedup();
+
+    Slice { locations, spans }
+  }
+
+  /// Returns the locations and arguments that the criterion may depend on.
+  ///
+  /// Fails if the criterion is a place with an argument that doesn't contain it.
+  pub fn backward_slice(&self, criterion: &SliceCriterion<'tcx>) -> Result<Slice> {
+    let seeds = self
+      .criteria(criterion)?
+      .into_iter()
+      .flat_map(|(place, location)| match location {
+        LocationOrArg::Location(location) => self.defs_after(location, place),
+        LocationOrArg::Arg(local) => HashSet::from_iter([LocationOrArg::Arg(local)]),
+      })
+      .collect();
+    Ok(self.slice(seeds, |location| match location {
+      LocationOrArg::Location(location) => {
+        self.dependencies[&location].iter().copied().collect()
+      }
+      LocationOrArg::Arg(_) => Vec::new(),
+    }))
+  }
+
+  /// Returns the locations that may depend on the criterion.
+  ///
+  /// The slice starts from the criterion's location if it writes the place, and from
+  /// the reads of the place that may execute after it, so reads of the same definition
+  /// before the location are excluded. Fails like [`Slicer::backward_slice`].
+  pub fn forward_slice(&self, criterion: &SliceCriterion<'tcx>) -> Result<Slice> {
+    let seeds = self
+      .criteria(criterion)?
+      .into_iter()
+      .flat_map(|(place, location)| match location {
+        LocationOrArg::Location(loc) => {
+          let mut seeds = self.uses_after(loc, place);
+          if self.writes(loc, place).0 {
+            seeds.insert(location);
+          }
+          seeds
+        }
+        LocationOrArg::Arg(local) => HashSet::from_iter([LocationOrArg::Arg(local)]),
+      })
+      .collect();
+    Ok(self.slice(seeds, |location| {
+      self
+        .dependents
+        .get(&location)
+        .into_iter()
+        .flatten()
+        .map(|location| LocationOrArg::Location(*location))
+        .collect()
+    }))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use either::Either;
+  use rustc_middle::mir::{Local, ProjectionElem, StatementKind};
+
+  use super::*;
+  use crate::test_utils;
+
+  fn assignment(body: &Body<'_>, local: Local, deref: bool) -> LocationOrArg {
+    let location = body
+      .all_locations()
+      .find(|location| match body.stmt_at(*location) {
+        Either::Left(stmt) => matches!(
+          &stmt.kind,
+          StatementKind::Assign(box (place, _))
+            if place.local == local
+              && place.projection.contains(&ProjectionElem::Deref) == deref
+        ),
+        Either::Right(_) => false,
+      })
+      .unwrap();
+    LocationOrArg::Location(location)
+  }
+
+  #[test]
+  fn test_slice() {
+    let src = r"
+fn foo(a: i32, b: i32, c: i32) -> i32 {
+  let mut x = a;
+  let y = b;
+  let r = &mut x;
+  if y > 0 { *r = 1; }
+  let z = y + c;
+  `(x)`
+}";
+    let (input, mut ranges) = test_utils::parse_ranges(src, [("`(", ")`")]).unwrap();
+    let range = ranges.remove("`(").unwrap().remove(0);
+    test_utils::compile_body(input, move |tcx, body_id, body_with_facts| {
+      let body = &body_with_facts.body;
+      let name_map = body.debug_info_name_map();
+      let arg = |name: &str| LocationOrArg::Arg(name_map[name]);
+      let span = range.to_span(tcx).unwrap();
+      let range = CharRange::from_span(span, tcx.sess.source_map()).unwrap();
+
+      let slicer = Slicer::new(tcx, body_id, body_with_facts);
+      let slice = slicer
+        .backward_slice(&SliceCriterion::Range(range))
+        .unwrap();
+      let write_r = assignment(body, name_map["r"], true);
+      let z_def = assignment(body, name_map["z"], false);
+      // `x` is written through `r`, only if `y > 0`.
+      for location in [arg("a"), arg("b"), write_r] {
+        assert!(slice.locations.contains(&location), "{location:?}");
+      }
+      for location in [arg("c"), z_def] {
+        assert!(!slice.locations.contains(&location), "{location:?}");
+      }
+      assert!(!slice.spans.is_empty());
+      assert_eq!(slice.ranges(tcx).len(), slice.spans.len());
+
+      let y = Place::from(name_map["y"]);
+      let y_def = assignment(body, y.local, false);
+      let slice = slicer
+        .forward_slice(&SliceCriterion::Place(y, y_def))
+        .unwrap();
+      for location in [y_def, write_r, z_def] {
+        assert!(slice.locations.contains(&location), "{location:?}");
+      }
+      assert!(
+        !slice
+          .locations
+          .contains(&assignment(body, name_map["r"], false))
+      );
+
+      // `y` is not a part of the argument `a`.
+      assert!(
+        slicer
+          .backward_slice(&SliceCriterion::Place(y, arg("a")))
+          .is_err()
+      );
+    });
+  }
+
+  #[test]
+  fn test_forward_slice_without_write() {
+    let input = r"
+fn foo(a: i32, b: i32) -> (i32, i32, i32) {
+  let x = a;
+  let u = x + 1;
+  let w = b * 2;
+  let v = x + w;
+  let t = w - 1;
+  (u, v, t)
+}";
+    test_utils::compile_body(input, move |tcx, body_id, body_with_facts| {
+      let body = &body_with_facts.body;
+      let name_map = body.debug_info_name_map();
+      let slicer = Slicer::new(tcx, body_id, body_with_facts);
+
+      // `w = b * 2` doesn't write `x`, so the slice only contains the reads of `x`
+      // after it, and neither `w = b * 2`, `u = x + 1` nor `t = w - 1`.
+      let x = Place::from(name_map["x"]);
+      let w_def = assignment(body, name_map["w"], false);
+      let slice = slicer
+        .forward_slice(&SliceCriterion::Place(x, w_def))
+        .unwrap();
+      assert!(
+        slice
+          .locations
+          .contains(&assignment(body, name_map["v"], false))
+      );
+      for name in ["x", "u", "w", "t"] {
+        let def = assignment(body, name_map[name], false);
+        assert!(!slice.locations.contains(&def), "{name}");
+      }
+    });
+  }
+}
@@ -1,17 +1,93 @@
-use std::{fmt, path::PathBuf};
+use std::{cell::Cell, fmt, path::PathBuf};
 
 use rustc_index::Idx;
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Filename(pub PathBuf);
 
+/// An interned [`Filename`].
+///
+/// Indexes are local to the current thread. By default, they are serialized as raw
+/// indexes, which are meaningless to other threads or processes. Within
+/// [`FilenameIndex::with_paths`], they are serialized as paths instead.
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[cfg_attr(feature = "ts-rs", derive(ts_rs::TS))]
 pub struct FilenameIndex {
   private_use_as_methods_instead: usize,
 }
 
+thread_local! {
+  static SERIALIZE_PATHS: Cell<bool> = const { Cell::new(false) };
+}
+
+impl FilenameIndex {
+  /// Runs `f` such that every [`FilenameIndex`] serialized or deserialized on this
+  /// thread is represented by its path rather than its index.
+  ///
+  /// Deserializing a path interns it, so a range serialized by one process can be
+  /// deserialized by another and then resolved, e.g. with
+  /// [`ToSpan`](super::range::ToSpan).
+  pub fn with_paths<T>(f: impl FnOnce() -> T) -> T {
+    struct Reset(bool);
+    impl Drop for Reset {
+      fn drop(&mut self) {
+        SERIALIZE_PATHS.set(self.0);
+      }
+    }
+
+    let _reset = Reset(SERIALIZE_PATHS.replace(true));
+    f()
+  }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+  use std::path::PathBuf;
+
+  use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+
+  use super::{Filename, FilenameIndex, SERIALIZE_PATHS};
+
+  /// The representation of a [`FilenameIndex`] outside of
+  /// [`FilenameIndex::with_paths`].
+  #[derive(Serialize, Deserialize)]
+  #[serde(rename = "FilenameIndex")]
+  struct RawIndex {
+    private_use_as_methods_instead: usize,
+  }
+
+  impl Serialize for FilenameIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      if SERIALIZE_PATHS.get() {
+        let path = self.path().map_err(ser::Error::custom)?;
+        path.serialize(serializer)
+      } else {
+        RawIndex {
+          private_use_as_methods_instead: self.private_use_as_methods_instead,
+        }
+        .serialize(serializer)
+      }
+    }
+  }
+
+  impl<'de> Deserialize<'de> for FilenameIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      if SERIALIZE_PATHS.get() {
+        let path = PathBuf::deserialize(deserializer)?;
+        Ok(Filename::intern(&path))
+      } else {
+        let raw = RawIndex::deserialize(deserializer)?;
+        // An index that isn't interned would panic when used, so reject it up front.
+        let index = FilenameIndex {
+          private_use_as_methods_instead: raw.private_use_as_methods_instead,
+        };
+        index.path().map_err(de::Error::custom)?;
+        Ok(index)
+      }
+    }
+  }
+}
+
 impl fmt::Debug for FilenameIndex {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "f{}", self.private_use_as_methods_instead)
@@ -0,0 +1,194 @@
+//! Conversions between source ranges and the types of the Language Server Protocol,
+//! enabled by the `lsp` feature.
+//!
+//! A [`CharRange`] counts columns in Unicode scalar values, whereas an LSP [`Position`]
+//! counts them in UTF-16 code units. The conversions therefore go through a
+//! [`ByteRange`] and re-encode the columns with [`ColumnEncoding::Utf16`].
+
+use std::env;
+
+use anyhow::{Context, Result};
+use lsp_types::{Location, Position, Range, Url};
+use rustc_span::{SourceFile, source_map::SourceMap};
+
+use super::{
+  filename::{Filename, FilenameIndex},
+  range::{BytePos, ByteRange, CharPos, CharRange, ColumnEncoding},
+};
+
+fn to_lsp_position(pos: CharPos) -> Result<Position> {
+  Ok(Position::new(
+    u32::try_from(pos.line).context("line does not fit in u32")?,
+    u32::try_from(pos.column).context("column does not fit in u32")?,
+  ))
+}
+
+/// Returns the position at the end of `line`, with its column counted in UTF-16 code
+/// units.
+fn line_end(
+  file: &SourceFile,
+  line: usize,
+  filename: FilenameIndex,
+  source_map: &SourceMap,
+) -> Result<CharPos> {
+  let text = file
+    .get_line(line)
+    .with_context(|| format!("line {line} is out of bounds"))?;
+  let end = BytePos(file.line_bounds(line).start.0 as usize + text.len());
+  let range = ByteRange {
+    start: end,
+    end,
+    filename,
+  };
+  Ok(
+    range
+      .as_char_range_with_encoding(source_map, ColumnEncoding::Utf16)
+      .end,
+  )
+}
+
+impl CharRange {
+  /// Converts to an LSP range in the same file.
+  ///
+  /// Fails if a position is past the end of its line.
+  pub fn to_lsp_range(&self, source_map: &SourceMap) -> Result<Range> {
+    let range =
+      ByteRange::from_char_range(self.start, self.end, self.filename, source_map)?
+        .as_char_range_with_encoding(source_map, ColumnEncoding::Utf16);
+    Ok(Range::new(
+      to_lsp_position(range.start)?,
+      to_lsp_position(range.end)?,
+    ))
+  }
+
+  /// Converts from an LSP range in the file `filename`.
+  ///
+  /// As specified by the LSP, a column past the end of a line is clamped to the end of
+  /// the line. Fails if a position is inside a surrogate pair.
+  pub fn from_lsp_range(
+    range: Range,
+    filename: FilenameIndex,
+    source_map: &SourceMap,
+  ) -> Result<CharRange> {
+    let file = filename.find_source_file(source_map)?;
+    let position = |position: Position| -> Result<CharPos> {
+      let line = position.line as usize;
+      let end = line_end(&file, line, filename, source_map)?;
+      Ok(CharPos {
+        line,
+        column: (position.character as usize).min(end.column),
+      })
+    };
+    Ok(
+      ByteRange::from_char_range_with_encoding(
+        position(range.start)?,
+        position(range.end)?,
+        filename,
+        source_map,
+        ColumnEncoding::Utf16,
+      )?
+      .as_char_range(source_map),
+    )
+  }
+
+  /// Converts to an LSP location, whose URI is the absolute path of the range's file.
+  ///
+  /// Relative paths are resolved against the current directory, like rustc does.
+  pub fn to_lsp_location(&self, source_map: &SourceMap) -> Result<Location> {
+    let path = self.filename.path()?;
+    let path = if path.is_absolute() {
+      path
+    } else {
+      env::current_dir()?.join(path)
+    };
+    let uri = Url::from_file_path(&path)
+      .ok()
+      .with_context(|| format!("invalid file path: {}", path.display()))?;
+    Ok(Location::new(uri, self.to_lsp_range(source_map)?))
+  }
+
+  /// Converts from an LSP location, interning the path of its URI.
+  pub fn from_lsp_location(
+    location: &Location,
+    source_map: &SourceMap,
+  ) -> Result<CharRange> {
+    let path = location
+      .uri
+      .to_file_path()
+      .ok()
+      .with_context(|| format!("not a file URI: {}", location.uri))?;
+    let filename = Filename::intern(&path);
+    CharRange::from_lsp_range(location.range, filename, source_map)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils::{self, CompileResult};
+
+  #[test]
+  fn test_lsp_range() {
+    // The crab is one char but two UTF-16 code units.
+    let input = "fn main() {\n  let x = \"🦀\"; let y = 1;\n}";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let source_map = tcx.sess.source_map();
+      let filename = Filename::intern("dummy.rs");
+      let y = CharRange {
+        start: CharPos {
+          line: 1,
+          column: 20,
+        },
+        end: CharPos {
+          line: 1,
+          column: 21,
+        },
+        filename,
+      };
+
+      let range = y.to_lsp_range(source_map).unwrap();
+      assert_eq!(
+        range,
+        Range::new(Position::new(1, 21), Position::new(1, 22))
+      );
+      assert_eq!(
+        CharRange::from_lsp_range(range, filename, source_map).unwrap(),
+        y
+      );
+
+      let location = y.to_lsp_location(source_map).unwrap();
+      assert!(location.uri.path().ends_with("/dummy.rs"), "{location:?}");
+      let parsed = CharRange::from_lsp_location(&location, source_map).unwrap();
+      assert_eq!((parsed.start, parsed.end), (y.start, y.end));
+
+      // Positions inside the crab are rejected, and positions past the end are clamped.
+      let range = |start, end| Range::new(Position::new(1, start), Position::new(1, end));
+      CharRange::from_lsp_range(range(12, 12), filename, source_map).unwrap_err();
+      let end = CharPos {
+        line: 1,
+        column: 25,
+      };
+      assert_eq!(
+        CharRange::from_lsp_range(range(26, 100), filename, source_map).unwrap(),
+        CharRange {
+          start: end,
+          end,
+          filename
+        }
+      );
+
+      // Positions past the end are rejected in the other direction.
+      let past_end = CharPos {
+        line: 1,
+        column: 26,
+      };
+      CharRange {
+        start: past_end,
+        end: past_end,
+        filename,
+      }
+      .to_lsp_range(source_map)
+      .unwrap_err();
+    });
+  }
+}
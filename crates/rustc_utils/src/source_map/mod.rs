@@ -2,6 +2,8 @@
 
 pub mod filename;
 pub mod find_bodies;
+#[cfg(feature = "lsp")]
+pub mod lsp;
 pub mod range;
 pub mod span;
 pub mod spanner;
@@ -15,20 +15,51 @@ use rustc_span::{
   FileName, RemapPathScopeComponents, SourceFile, Span, source_map::SourceMap,
 };
 #[cfg(feature = "serde")]
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "ts-rs")]
 use ts_rs::TS;
 
 use super::filename::{Filename, FilenameIndex};
 use crate::cache::Cache;
 
+/// The unit in which the column of a [`CharPos`] is counted.
+///
+/// Rustc and most of this crate count Unicode scalar values ([`char`]s), whereas VSCode
+/// and the Language Server Protocol count UTF-16 code units. For example, the crab emoji
+/// is 4 bytes, 1 char, and 2 UTF-16 code units wide.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "ts-rs", derive(TS))]
+pub enum ColumnEncoding {
+  /// Columns count UTF-8 bytes.
+  Utf8,
+
+  /// Columns count UTF-16 code units.
+  Utf16,
+
+  /// Columns count Unicode scalar values.
+  #[default]
+  Utf32,
+}
+
+impl ColumnEncoding {
+  /// Returns the width of `c` in this encoding.
+  pub fn char_len(self, c: char) -> usize {
+    match self {
+      ColumnEncoding::Utf8 => c.len_utf8(),
+      ColumnEncoding::Utf16 => c.len_utf16(),
+      ColumnEncoding::Utf32 => 1,
+    }
+  }
+}
+
 struct CharByteMapping {
   byte_to_char: HashMap<BytePos, CharPos>,
   char_to_byte: HashMap<CharPos, BytePos>,
 }
 
 impl CharByteMapping {
-  pub fn build(file: &SourceFile) -> Self {
+  pub fn build(file: &SourceFile, encoding: ColumnEncoding) -> Self {
     let mut byte_to_char = HashMap::default();
     let mut char_to_byte = HashMap::default();
 
@@ -57,11 +88,14 @@ impl CharByteMapping {
       let line_start = line_bounds.start.0 as usize;
       let mut last_column = 0;
       let mut last_offset = 0;
-      for (column, (byte_offset, c)) in line_str.char_indices().enumerate() {
+      for (byte_offset, c) in line_str.char_indices() {
         let bpos = BytePos(line_start + byte_offset);
-        let cpos = CharPos { line, column };
+        let cpos = CharPos {
+          line,
+          column: last_column,
+        };
         check_insert!(bpos, cpos);
-        last_column = column + 1;
+        last_column += encoding.char_len(c);
         last_offset = byte_offset + c.len_utf8();
       }
 
@@ -87,11 +121,14 @@ impl CharByteMapping {
       .unwrap_or_else(|| panic!("Could not find char pos for {pos:?}"))
   }
 
-  pub fn char_to_byte(&self, pos: CharPos) -> BytePos {
-    *self
+  /// Fails if `pos` is past the end of its line, or inside a char that is wider than
+  /// one unit of the encoding.
+  pub fn char_to_byte(&self, pos: CharPos) -> Result<BytePos> {
+    self
       .char_to_byte
       .get(&pos)
-      .unwrap_or_else(|| panic!("Could not find byte pos for {pos:?}"))
+      .copied()
+      .with_context(|| format!("Could not find byte pos for {pos:?}"))
   }
 }
 
@@ -99,7 +136,7 @@ impl CharByteMapping {
 pub struct RangeContext {
   filenames: IndexVec<FilenameIndex, Filename>,
   path_mapping: HashMap<FilenameIndex, Arc<SourceFile>>,
-  char_byte_mapping: Cache<FilenameIndex, CharByteMapping>,
+  char_byte_mapping: Cache<(FilenameIndex, ColumnEncoding), CharByteMapping>,
 }
 
 thread_local! {
@@ -122,6 +159,15 @@ impl Filename {
 }
 
 impl FilenameIndex {
+  /// Returns the path of the interned file.
+  pub fn path(self) -> Result<PathBuf> {
+    CONTEXT.with(|ctx| {
+      let ctx = ctx.borrow();
+      let filename = ctx.filenames.get(self).context("Missing file index!")?;
+      Ok(filename.0.clone())
+    })
+  }
+
   pub fn find_source_file(self, source_map: &SourceMap) -> Result<Arc<SourceFile>> {
     CONTEXT.with(|ctx| {
       let ctx = &mut *ctx.borrow_mut();
@@ -176,12 +222,13 @@ impl FilenameIndex {
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
-#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "ts-rs", derive(TS))]
 pub struct BytePos(pub usize);
 
 /// CharPos is designed to match VSCode's vscode.Position type.
-/// Both line and column are 0-based.
+/// Both line and column are 0-based. The column counts chars, unless the position was
+/// converted with another [`ColumnEncoding`].
 ///
 /// A previous version of CharPos used a global character-based index,
 /// naively thinking this was the same as VSCode's notion of an "offset".
@@ -190,7 +237,7 @@ pub struct BytePos(pub usize);
 /// is to use line-column as a common coordinate system, which is robust
 /// to choice of line endings.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
-#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "ts-rs", derive(TS))]
 pub struct CharPos {
   pub line: usize,
@@ -198,7 +245,7 @@ pub struct CharPos {
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "ts-rs", derive(TS))]
 pub struct ByteRange {
   pub start: BytePos,
@@ -213,7 +260,7 @@ pub struct ByteRange {
 /// character-based (really grapheme-based) indexes. This data structure
 /// along with [`ByteRange`] helps convert between the two representations.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
-#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "ts-rs", derive(TS))]
 pub struct CharRange {
   pub start: CharPos,
@@ -223,13 +270,23 @@ pub struct CharRange {
 
 impl ByteRange {
   pub fn as_char_range(&self, source_map: &SourceMap) -> CharRange {
+    self.as_char_range_with_encoding(source_map, ColumnEncoding::default())
+  }
+
+  /// Converts to a [`CharRange`] whose columns are counted in `encoding`.
+  pub fn as_char_range_with_encoding(
+    &self,
+    source_map: &SourceMap,
+    encoding: ColumnEncoding,
+  ) -> CharRange {
     let file = self.filename.find_source_file(source_map).unwrap();
 
     CONTEXT.with(|ctx| {
       let ctx = ctx.borrow();
-      let mapping: &CharByteMapping = ctx
-        .char_byte_mapping
-        .get(&self.filename, |_| CharByteMapping::build(&file));
+      let mapping: &CharByteMapping =
+        ctx.char_byte_mapping.get(&(self.filename, encoding), |_| {
+          CharByteMapping::build(&file, encoding)
+        });
 
       let char_start = mapping.byte_to_char(self.start);
       let char_end = mapping.byte_to_char(self.end);
@@ -247,16 +304,33 @@ impl ByteRange {
     char_end: CharPos,
     filename: FilenameIndex,
     source_map: &SourceMap,
+  ) -> Result<ByteRange> {
+    Self::from_char_range_with_encoding(
+      char_start,
+      char_end,
+      filename,
+      source_map,
+      ColumnEncoding::default(),
+    )
+  }
+
+  /// Converts from a range whose columns are counted in `encoding`.
+  pub fn from_char_range_with_encoding(
+    char_start: CharPos,
+    char_end: CharPos,
+    filename: FilenameIndex,
+    source_map: &SourceMap,
+    encoding: ColumnEncoding,
   ) -> Result<ByteRange> {
     let file = filename.find_source_file(source_map)?;
 
     CONTEXT.with(|ctx| {
       let ctx = ctx.borrow();
-      let mapping = ctx
-        .char_byte_mapping
-        .get(&filename, |_| CharByteMapping::build(&file));
-      let byte_start = mapping.char_to_byte(char_start);
-      let byte_end = mapping.char_to_byte(char_end);
+      let mapping = ctx.char_byte_mapping.get(&(filename, encoding), |_| {
+        CharByteMapping::build(&file, encoding)
+      });
+      let byte_start = mapping.char_to_byte(char_start)?;
+      let byte_end = mapping.char_to_byte(char_end)?;
       Ok(ByteRange {
         start: byte_start,
         end: byte_end,
@@ -448,4 +522,106 @@ mod test {
       });
     });
   }
+
+  #[test]
+  fn test_column_encoding() {
+    let input = "fn main() {\n  let x = \"🦀é\"; let y = 1;\n}";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let source_map = tcx.sess.source_map();
+      let filename = Filename::intern("dummy.rs");
+      let y_index = input.find('y').unwrap();
+      let byte_range = ByteRange {
+        start: BytePos(y_index),
+        end: BytePos(y_index + 1),
+        filename,
+      };
+
+      let line_start = input.find('\n').unwrap() + 1;
+      for (encoding, column) in [
+        (ColumnEncoding::Utf8, y_index - line_start),
+        (ColumnEncoding::Utf16, 21),
+        (ColumnEncoding::Utf32, 20),
+      ] {
+        let char_range = byte_range.as_char_range_with_encoding(source_map, encoding);
+        assert_eq!(
+          (char_range.start, char_range.end),
+          (CharPos { line: 1, column }, CharPos {
+            line: 1,
+            column: column + 1
+          }),
+          "{encoding:?}"
+        );
+        let parsed = ByteRange::from_char_range_with_encoding(
+          char_range.start,
+          char_range.end,
+          filename,
+          source_map,
+          encoding,
+        )
+        .unwrap();
+        assert_eq!(parsed, byte_range, "{encoding:?}");
+      }
+
+      // The second UTF-16 code unit of the crab isn't a valid position
+      let inside_crab = CharPos {
+        line: 1,
+        column: 12,
+      };
+      ByteRange::from_char_range_with_encoding(
+        inside_crab,
+        inside_crab,
+        filename,
+        source_map,
+        ColumnEncoding::Utf16,
+      )
+      .unwrap_err();
+    });
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_range_serde() {
+    let input = "fn main() { let x = 1; }";
+    test_utils::CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let filename = Filename::intern("dummy.rs");
+      let range = CharRange {
+        start: CharPos {
+          line: 0,
+          column: 16,
+        },
+        end: CharPos {
+          line: 0,
+          column: 17,
+        },
+        filename,
+      };
+
+      // By default, the filename is a raw index
+      let json = serde_json::to_string(&range).unwrap();
+      assert!(!json.contains("dummy.rs"), "{json}");
+      let parsed: CharRange = serde_json::from_str(&json).unwrap();
+      assert_eq!(parsed, range);
+
+      // With paths, the range can be resolved by a thread with its own interner
+      let json = FilenameIndex::with_paths(|| serde_json::to_string(&range).unwrap());
+      assert!(json.contains(r#""filename":"dummy.rs""#), "{json}");
+      let path = std::thread::spawn({
+        let json = json.clone();
+        move || {
+          let parsed: CharRange =
+            FilenameIndex::with_paths(|| serde_json::from_str(&json).unwrap());
+          parsed.filename.path().unwrap()
+        }
+      })
+      .join()
+      .unwrap();
+      assert_eq!(path, PathBuf::from("dummy.rs"));
+
+      let parsed: CharRange =
+        FilenameIndex::with_paths(|| serde_json::from_str(&json).unwrap());
+      assert_eq!(parsed, range);
+      let span = parsed.to_span(tcx).unwrap();
+      assert_eq!(tcx.sess.source_map().span_to_snippet(span).unwrap(), "x");
+    });
+  }
 }
@@ -1,19 +1,23 @@
 //! Running rustc and Flowistry in tests.
 
 use std::{
-  fmt::Debug,
+  cmp::Reverse,
+  env,
+  fmt::{Debug, Write},
   fs,
   hash::Hash,
   io, panic,
-  path::Path,
+  path::{Path, PathBuf},
   process::Command,
-  sync::{Arc, LazyLock},
+  sync::{
+    Arc, LazyLock,
+    atomic::{AtomicUsize, Ordering},
+  },
 };
 
 use anyhow::{Context, Result, anyhow, ensure};
 use log::debug;
 use rustc_abi::{FieldIdx, VariantIdx};
-use rustc_borrowck::consumers::BodyWithBorrowckFacts;
 use rustc_data_structures::fx::{FxHashMap as HashMap, FxHashSet as HashSet};
 use rustc_driver::run_compiler;
 use rustc_hir::BodyId;
@@ -25,7 +29,7 @@ use rustc_span::source_map::FileLoader;
 
 use crate::{
   BodyExt, PlaceExt,
-  mir::borrowck_facts,
+  mir::borrowck_facts::{self, BodyWithFacts},
   source_map::{
     filename::{Filename, FilenameIndex},
     find_bodies::{find_bodies, find_enclosing_bodies},
@@ -52,6 +56,47 @@ impl FileLoader for StringLoader {
   }
 }
 
+/// A [`FileLoader`] that reads source files from memory.
+///
+/// Relative paths are only looked up in memory. Absolute paths that aren't in memory, such
+/// as the files of the standard library, are read from disk.
+pub struct VirtualFileLoader(HashMap<PathBuf, String>);
+
+impl VirtualFileLoader {
+  pub fn new(files: impl IntoIterator<Item = (PathBuf, String)>) -> Self {
+    VirtualFileLoader(files.into_iter().collect())
+  }
+
+  fn get(&self, path: &Path) -> Option<&String> {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    self.0.get(path)
+  }
+}
+
+impl FileLoader for VirtualFileLoader {
+  fn file_exists(&self, path: &Path) -> bool {
+    self.get(path).is_some() || (path.is_absolute() && path.exists())
+  }
+
+  fn read_file(&self, path: &Path) -> io::Result<String> {
+    match self.get(path) {
+      Some(contents) => Ok(contents.clone()),
+      None => fs::read_to_string(path),
+    }
+  }
+
+  fn read_binary_file(&self, path: &Path) -> io::Result<Arc<[u8]>> {
+    match self.get(path) {
+      Some(contents) => Ok(contents.as_bytes().into()),
+      None => Ok(fs::read(path)?.into()),
+    }
+  }
+
+  fn current_directory(&self) -> io::Result<PathBuf> {
+    env::current_dir()
+  }
+}
+
 static SYSROOT: LazyLock<String> = LazyLock::new(|| {
   let rustc_output = Command::new("rustc")
     .args(["--print", "sysroot"])
@@ -81,6 +126,8 @@ thread_local! {
 pub struct CompileBuilder {
   input: String,
   arguments: Vec<String>,
+  files: Vec<(PathBuf, String)>,
+  extern_crates: Vec<(String, String)>,
 }
 
 impl CompileBuilder {
@@ -89,6 +136,8 @@ impl CompileBuilder {
     Self {
       input: input.into(),
       arguments: vec![],
+      files: vec![],
+      extern_crates: vec![],
     }
   }
 
@@ -98,11 +147,63 @@ impl CompileBuilder {
     self
   }
 
+  /// Add a source file, e.g. `foo.rs` for a `mod foo;` in the input.
+  ///
+  /// Paths are relative to the input, which is compiled as [`DUMMY_FILE_NAME`].
+  pub fn with_file(
+    &mut self,
+    path: impl Into<PathBuf>,
+    contents: impl Into<String>,
+  ) -> &mut Self {
+    self.files.push((path.into(), contents.into()));
+    self
+  }
+
+  /// Add a library crate with the given name and source code that the input can
+  /// depend on, e.g. with `use name::Item`.
+  ///
+  /// The crate is compiled before the input with MIR for every function, so that
+  /// analyses can inspect its bodies. Its root file is `<name>.rs`, and it can include
+  /// modules added by [`CompileBuilder::with_file`].
+  pub fn with_extern_crate(
+    &mut self,
+    name: impl Into<String>,
+    input: impl Into<String>,
+  ) -> &mut Self {
+    self.extern_crates.push((name.into(), input.into()));
+    self
+  }
+
   /// Perform the compilation, providing access to it's intermediates state to
   /// the provided closure
   pub fn compile(&self, f: impl for<'tcx> FnOnce(CompileResult<'tcx>) + Send) {
+    let mut files = self.files.clone();
+    files.push((DUMMY_FILE_NAME.into(), self.input.clone()));
+    for (name, input) in &self.extern_crates {
+      files.push((format!("{name}.rs").into(), input.clone()));
+    }
+
+    let out_dir = (!self.extern_crates.is_empty()).then(|| {
+      static COUNTER: AtomicUsize = AtomicUsize::new(0);
+      let dir = env::temp_dir().join(format!(
+        "rustc_utils-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+      ));
+      fs::create_dir_all(&dir).unwrap();
+      dir
+    });
+
+    let mut extern_args = Vec::new();
+    for (name, _) in &self.extern_crates {
+      let out_dir = out_dir.as_ref().unwrap();
+      compile_extern_crate(name, &files, out_dir);
+      let path = out_dir.join(format!("lib{name}.rmeta"));
+      extern_args.extend(["--extern".into(), format!("{name}={}", path.display())]);
+    }
+
     let mut callbacks = TestCallbacks {
-      input: self.input.clone(),
+      files,
       callback: Some(move |tcx: TyCtxt<'_>| f(CompileResult { tcx })),
     };
     let args = [
@@ -121,13 +222,57 @@ impl CompileBuilder {
     ]
     .into_iter()
     .map(str::to_owned)
+    .chain(extern_args)
     .chain(self.arguments.clone())
     .collect::<Box<_>>();
 
-    rustc_driver::catch_fatal_errors(|| {
+    let result = rustc_driver::catch_fatal_errors(|| {
       run_compiler(&args, &mut callbacks);
-    })
-    .unwrap();
+    });
+    if let Some(out_dir) = out_dir {
+      let _ = fs::remove_dir_all(out_dir);
+    }
+    result.unwrap();
+  }
+}
+
+/// Compiles the metadata of an extern crate added by [`CompileBuilder::with_extern_crate`]
+/// into `out_dir`.
+fn compile_extern_crate(name: &str, files: &[(PathBuf, String)], out_dir: &Path) {
+  let args = [
+    "rustc".into(),
+    format!("{name}.rs"),
+    "--crate-name".into(),
+    name.into(),
+    "--crate-type".into(),
+    "lib".into(),
+    "--edition=2024".into(),
+    "--emit=metadata".into(),
+    "-Zalways-encode-mir".into(),
+    "--allow".into(),
+    "warnings".into(),
+    "--out-dir".into(),
+    out_dir.display().to_string(),
+    "--sysroot".into(),
+    SYSROOT.clone(),
+  ];
+
+  let mut callbacks = ExternCrateCallbacks {
+    files: files.to_vec(),
+  };
+  rustc_driver::catch_fatal_errors(|| {
+    run_compiler(&args, &mut callbacks);
+  })
+  .unwrap_or_else(|_| panic!("failed to compile extern crate {name}"));
+}
+
+struct ExternCrateCallbacks {
+  files: Vec<(PathBuf, String)>,
+}
+
+impl rustc_driver::Callbacks for ExternCrateCallbacks {
+  fn config(&mut self, config: &mut rustc_interface::Config) {
+    config.file_loader = Some(Box::new(VirtualFileLoader::new(self.files.clone())));
   }
 }
 
@@ -135,8 +280,7 @@ impl CompileBuilder {
 /// callback is going to use [`CompileResult::as_body`].
 pub fn compile_body(
   input: impl Into<String>,
-  callback: impl for<'tcx> FnOnce(TyCtxt<'tcx>, BodyId, &'tcx BodyWithBorrowckFacts<'tcx>)
-  + Send,
+  callback: impl for<'tcx> FnOnce(TyCtxt<'tcx>, BodyId, &'tcx BodyWithFacts<'tcx>) + Send,
 ) {
   CompileBuilder::new(input).compile(|result| {
     let (body_id, body_with_facts) = result.as_body();
@@ -153,7 +297,7 @@ pub struct CompileResult<'tcx> {
 
 impl<'tcx> CompileResult<'tcx> {
   /// Assume that we compiled only one function and return that function's id and body.
-  pub fn as_body(&self) -> (BodyId, &'tcx BodyWithBorrowckFacts<'tcx>) {
+  pub fn as_body(&self) -> (BodyId, &'tcx BodyWithFacts<'tcx>) {
     let tcx = self.tcx;
     let (_, body_id) = find_bodies(tcx).remove(0);
     let def_id = tcx.hir_body_owner_def_id(body_id);
@@ -166,7 +310,7 @@ impl<'tcx> CompileResult<'tcx> {
   pub fn as_body_with_range(
     &self,
     target: ByteRange,
-  ) -> (BodyId, &'tcx BodyWithBorrowckFacts<'tcx>) {
+  ) -> (BodyId, &'tcx BodyWithFacts<'tcx>) {
     let tcx = self.tcx;
     let body_id = find_enclosing_bodies(tcx, target.to_span(tcx).unwrap())
       .next()
@@ -180,7 +324,7 @@ impl<'tcx> CompileResult<'tcx> {
 }
 
 struct TestCallbacks<Cb> {
-  input: String,
+  files: Vec<(PathBuf, String)>,
   callback: Option<Cb>,
 }
 
@@ -189,8 +333,8 @@ where
   Cb: FnOnce(TyCtxt<'_>),
 {
   fn config(&mut self, config: &mut rustc_interface::Config) {
-    config.override_queries = Some(borrowck_facts::override_queries);
-    config.file_loader = Some(Box::new(StringLoader(self.input.clone())));
+    borrowck_facts::store_bodies(config);
+    config.file_loader = Some(Box::new(VirtualFileLoader::new(self.files.clone())));
   }
 
   fn after_expansion(
@@ -315,6 +459,170 @@ pub fn compare_ranges(
   check(extra, "Actual DID have UNEXPECTED");
 }
 
+/// Renders `ranges` in `prog` with the delimiters `` `[ `` and `` ]` `` used by
+/// [`parse_ranges`], e.g. ``let `[x]` = 1;``.
+///
+/// Unlike [`color_ranges`], the output is plain text and deterministic, so it is suitable
+/// for snapshots.
+pub fn mark_ranges(prog: &str, ranges: &HashSet<ByteRange>) -> String {
+  // At the same position, non-empty ranges close before others open, and nested ranges
+  // open outermost first and close innermost first.
+  let mut markers = ranges
+    .iter()
+    .flat_map(|range| {
+      let (start, end) = (range.start.0, range.end.0);
+      let close_order = if start == end { 2 } else { 0 };
+      [
+        ((start, 1, Reverse(end)), "`["),
+        ((end, close_order, Reverse(start)), "]`"),
+      ]
+    })
+    .collect::<Vec<_>>();
+  markers.sort();
+
+  let mut output = String::with_capacity(prog.len() + markers.len() * 2);
+  let mut last = 0;
+  for ((pos, ..), marker) in markers {
+    output.push_str(&prog[last .. pos]);
+    output.push_str(marker);
+    last = pos;
+  }
+  output.push_str(&prog[last ..]);
+  output
+}
+
+/// The environment variable that makes snapshot assertions overwrite their snapshots
+/// instead of comparing against them.
+pub const UPDATE_SNAPSHOTS: &str = "UPDATE_SNAPSHOTS";
+
+/// Asserts that `actual` matches the snapshot `name`, stored in a `snapshots` directory
+/// next to the source file of the test.
+///
+/// If the environment variable [`UPDATE_SNAPSHOTS`] is set, the snapshot is written
+/// instead. Otherwise, a missing or different snapshot panics with a line diff.
+///
+/// ```ignore
+/// assert_snapshot!("my_test", output);
+/// ```
+#[macro_export]
+macro_rules! assert_snapshot {
+  ($name:expr, $actual:expr) => {
+    $crate::test_utils::check_snapshot(
+      ::std::env!("CARGO_MANIFEST_DIR"),
+      ::std::file!(),
+      $name,
+      ::std::convert::AsRef::<str>::as_ref(&$actual),
+    )
+  };
+}
+
+/// Asserts that the MIR of `body`, printed by
+/// [`BodyExt::to_string`](crate::BodyExt::to_string), matches the snapshot `name`.
+///
+/// See [`assert_snapshot!`] for how snapshots are stored and updated.
+///
+/// ```ignore
+/// assert_mir_snapshot!("my_test", tcx, &body_with_facts.body);
+/// ```
+#[macro_export]
+macro_rules! assert_mir_snapshot {
+  ($name:expr, $tcx:expr, $body:expr) => {
+    $crate::assert_snapshot!($name, $crate::BodyExt::to_string($body, $tcx).unwrap())
+  };
+}
+
+/// Asserts that `ranges` in the source `prog`, rendered by [`mark_ranges`], match the
+/// snapshot `name`.
+///
+/// See [`assert_snapshot!`] for how snapshots are stored and updated.
+///
+/// ```ignore
+/// assert_ranges_snapshot!("my_test", &input, &ranges);
+/// ```
+#[macro_export]
+macro_rules! assert_ranges_snapshot {
+  ($name:expr, $prog:expr, $ranges:expr) => {
+    $crate::assert_snapshot!($name, $crate::test_utils::mark_ranges($prog, $ranges))
+  };
+}
+
+/// Implementation of [`assert_snapshot!`].
+///
+/// `source_file` is the result of `file!()` in the test, which is relative to either
+/// the package in `manifest_dir` or its workspace.
+#[doc(hidden)]
+pub fn check_snapshot(manifest_dir: &str, source_file: &str, name: &str, actual: &str) {
+  let source_file = Path::new(manifest_dir)
+    .ancestors()
+    .map(|dir| dir.join(source_file))
+    .find(|path| path.exists())
+    .unwrap_or_else(|| panic!("could not find the source file {source_file}"));
+  let path = source_file
+    .parent()
+    .unwrap()
+    .join("snapshots")
+    .join(format!("{name}.snap"));
+
+  let actual = format!("{}\n", actual.trim_end());
+  if env::var_os(UPDATE_SNAPSHOTS).is_some() {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, &actual).unwrap();
+    return;
+  }
+
+  let Ok(expected) = fs::read_to_string(&path) else {
+    panic!(
+      "Snapshot {} does not exist, run with {UPDATE_SNAPSHOTS}=1 to create it. \
+       Actual:\n{actual}",
+      path.display()
+    );
+  };
+  assert!(
+    expected == actual,
+    "Snapshot {} does not match, run with {UPDATE_SNAPSHOTS}=1 to update it. \
+     Diff (- expected, + actual):\n{}",
+    path.display(),
+    diff_lines(&expected, &actual)
+  );
+}
+
+/// Returns a line diff between `expected` and `actual`, based on their longest common
+/// subsequence of lines.
+fn diff_lines(expected: &str, actual: &str) -> String {
+  let expected = expected.lines().collect::<Vec<_>>();
+  let actual = actual.lines().collect::<Vec<_>>();
+  let (n, m) = (expected.len(), actual.len());
+
+  // lcs[i][j] is the length of the LCS of expected[i ..] and actual[j ..]
+  let mut lcs = vec![vec![0; m + 1]; n + 1];
+  for i in (0 .. n).rev() {
+    for j in (0 .. m).rev() {
+      lcs[i][j] = if expected[i] == actual[j] {
+        lcs[i + 1][j + 1] + 1
+      } else {
+        lcs[i + 1][j].max(lcs[i][j + 1])
+      };
+    }
+  }
+
+  let mut diff = String::new();
+  let (mut i, mut j) = (0, 0);
+  while i < n || j < m {
+    if i < n && j < m && expected[i] == actual[j] {
+      writeln!(diff, "  {}", expected[i]).unwrap();
+      i += 1;
+      j += 1;
+    } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+      writeln!(diff, "+ {}", actual[j]).unwrap();
+      j += 1;
+    } else {
+      writeln!(diff, "- {}", expected[i]).unwrap();
+      i += 1;
+    }
+  }
+  diff
+}
+
 pub struct Placer<'a, 'tcx> {
   tcx: TyCtxt<'tcx>,
   body: &'a Body<'tcx>,
@@ -434,4 +742,79 @@ mod test {
       ]);
     });
   }
+
+  #[test]
+  fn test_mark_ranges() {
+    let input = "`[`[f]`oo]` `[]`bar `[baz]`";
+    let (prog, ranges) = parse_ranges(input, [("`[", "]`")]).unwrap();
+    let ranges = ranges["`["].iter().copied().collect::<HashSet<_>>();
+    assert_eq!(mark_ranges(&prog, &ranges), input);
+  }
+
+  #[test]
+  fn test_diff_lines() {
+    let diff = diff_lines("a\nb\nc\n", "a\nc\nd\n");
+    assert_eq!(diff, "  a\n- b\n  c\n+ d\n");
+  }
+
+  #[test]
+  fn test_mir_snapshot() {
+    // Other tests enable MIR simplification for the whole process, so it is enabled here
+    // to make the snapshot independent of the order of the tests.
+    borrowck_facts::enable_mir_simplification();
+
+    let input = "fn f(x: i32) -> i32 { if x > 0 { x } else { -x } }";
+    compile_body(input, |tcx, _, body_with_facts| {
+      crate::assert_mir_snapshot!("test_mir_snapshot", tcx, &body_with_facts.body);
+    });
+  }
+
+  #[test]
+  fn test_ranges_snapshot() {
+    let input = "fn f() { let x = 1; }\nfn g() { let closure = || 0; }";
+    CompileBuilder::new(input).compile(|CompileResult { tcx }| {
+      let ranges = find_bodies(tcx)
+        .into_iter()
+        .map(|(span, _)| ByteRange::from_span(span, tcx.sess.source_map()).unwrap())
+        .collect::<HashSet<_>>();
+      crate::assert_ranges_snapshot!("test_ranges_snapshot", input, &ranges);
+    });
+  }
+
+  #[test]
+  fn test_compile_with_file() {
+    let input = "mod foo; pub fn f() -> i32 { foo::g() }";
+    CompileBuilder::new(input)
+      .with_file("foo.rs", "pub fn g() -> i32 { 1 }")
+      .compile(|CompileResult { tcx }| {
+        let names = tcx
+          .hir_body_owners()
+          .map(|def_id| tcx.item_name(def_id.to_def_id()).to_string())
+          .collect::<Vec<_>>();
+        compare_sets(names, ["f".to_string(), "g".to_string()]);
+      });
+  }
+
+  #[test]
+  fn test_compile_with_extern_crate() {
+    let dep = "mod imp; pub trait Tr { fn f(&self) -> i32; } pub struct S;";
+    let imp = "impl crate::Tr for crate::S { fn f(&self) -> i32 { 1 } }";
+    let input = "use dep::Tr; pub fn main() { dep::S.f(); }";
+    CompileBuilder::new(input)
+      .with_file("imp.rs", imp)
+      .with_extern_crate("dep", dep)
+      .compile(|CompileResult { tcx }| {
+        let krate = *tcx
+          .crates(())
+          .iter()
+          .find(|krate| tcx.crate_name(**krate).as_str() == "dep")
+          .unwrap();
+        let tr = tcx.traits(krate)[0];
+        let impls = tcx.all_impls(tr).collect::<Vec<_>>();
+        assert_eq!(impls.len(), 1);
+
+        let method = tcx.associated_item_def_ids(impls[0])[0];
+        assert!(tcx.is_mir_available(method));
+      });
+  }
 }
@@ -1,21 +1,75 @@
 //! A simple timer for profiling.
+//!
+//! Every [`block_timer!`](crate::block_timer) logs how long its block took. After
+//! [`enable_profiling`], the timers also build a profile: nested timers on the same thread
+//! form a stack, and the time of each label is aggregated across calls into
+//! [`TimerStats`]. The profile can then be exported with [`write_chrome_trace`] (for
+//! `chrome://tracing` or Perfetto) or [`write_folded_stacks`] (for `flamegraph.pl` or
+//! `inferno`).
+//!
+//! The trace keeps at most [`set_max_trace_events`] events, after which further calls are
+//! only aggregated into the stats and the folded stacks.
 
-use std::time::Instant;
+use std::{
+  cell::RefCell,
+  fmt::Write as _,
+  io::{self, Write},
+  sync::{
+    LazyLock, Mutex, MutexGuard, PoisonError,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+  },
+  time::{Duration, Instant},
+};
 
-use log::info;
+use log::{info, warn};
+use rustc_data_structures::fx::FxHashMap as HashMap;
+
+use crate::cache::CacheStats;
 
 pub fn elapsed(name: &str, start: Instant) {
   info!("{name} took {:.04}s", start.elapsed().as_secs_f64());
 }
 
+/// Logs the statistics of a cache, e.g. to tune its capacity.
+pub fn cache_stats(name: &str, stats: &CacheStats) {
+  info!(
+    "{name}: {} hits, {} misses, {} evictions, {} removals, {:.04}s computing",
+    stats.hits,
+    stats.misses,
+    stats.evictions,
+    stats.removals,
+    stats.compute_time.as_secs_f64()
+  );
+}
+
 pub struct BlockTimer<'a> {
   pub name: &'a str,
   pub start: Instant,
 }
 
+impl<'a> BlockTimer<'a> {
+  /// Starts a timer, which is added to the profile if profiling is enabled.
+  pub fn new(name: &'a str) -> Self {
+    let start = Instant::now();
+    if PROFILING.load(Ordering::Relaxed) {
+      STACK.with_borrow_mut(|stack| {
+        stack.push(Frame {
+          name: name.to_string(),
+          start,
+          child_time: Duration::ZERO,
+        });
+      });
+    }
+    BlockTimer { name, start }
+  }
+}
+
 impl Drop for BlockTimer<'_> {
   fn drop(&mut self) {
     elapsed(self.name, self.start);
+    if PROFILING.load(Ordering::Relaxed) {
+      record(self);
+    }
   }
 }
 
@@ -24,8 +78,287 @@ impl Drop for BlockTimer<'_> {
 macro_rules! block_timer {
   ($name:expr) => {
     let name = $name;
-    let start = std::time::Instant::now();
-    let _timer = $crate::timer::BlockTimer { name, start };
+    let _timer = $crate::timer::BlockTimer::new(name);
     log::info!("Starting {name}...");
   };
 }
+
+/// The time spent in all calls of a timer with a given label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimerStats {
+  /// Number of times the timer was run.
+  pub calls: u64,
+
+  /// Time from the start to the end of each call.
+  pub total_time: Duration,
+
+  /// Time of each call that was not spent in nested timers.
+  pub self_time: Duration,
+}
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// The time from which trace events are measured.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+static PROFILE: LazyLock<Mutex<Profile>> = LazyLock::new(Mutex::default);
+
+static MAX_TRACE_EVENTS: AtomicUsize = AtomicUsize::new(1 << 20);
+
+#[derive(Default)]
+struct Profile {
+  stats: HashMap<String, TimerStats>,
+  /// Self time of each stack of labels, joined by `;`.
+  folded: HashMap<String, Duration>,
+  events: Vec<TraceEvent>,
+  /// Number of events that didn't fit in `events`.
+  dropped_events: u64,
+}
+
+impl Profile {
+  fn push_event(&mut self, event: TraceEvent, max_events: usize) {
+    if self.events.len() < max_events {
+      self.events.push(event);
+      return;
+    }
+    if self.dropped_events == 0 {
+      warn!("Profile has {max_events} trace events, dropping further events");
+    }
+    self.dropped_events += 1;
+  }
+}
+
+struct TraceEvent {
+  name: String,
+  thread: u64,
+  start: Duration,
+  duration: Duration,
+}
+
+struct Frame {
+  name: String,
+  start: Instant,
+  child_time: Duration,
+}
+
+thread_local! {
+  static STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+  static THREAD: u64 = {
+    static NEXT_THREAD: AtomicU64 = AtomicU64::new(0);
+    NEXT_THREAD.fetch_add(1, Ordering::Relaxed)
+  };
+}
+
+fn profile() -> MutexGuard<'static, Profile> {
+  PROFILE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Starts building a profile from every timer on every thread.
+pub fn enable_profiling() {
+  LazyLock::force(&EPOCH);
+  PROFILING.store(true, Ordering::SeqCst);
+}
+
+/// Sets the maximum number of timer calls kept for [`write_chrome_trace`], which is
+/// 2<sup>20</sup> by default.
+pub fn set_max_trace_events(max: usize) {
+  MAX_TRACE_EVENTS.store(max, Ordering::Relaxed);
+}
+
+/// Discards the profile built so far.
+pub fn reset_profile() {
+  *profile() = Profile::default();
+}
+
+fn record(timer: &BlockTimer) {
+  let end = Instant::now();
+  let total_time = end - timer.start;
+
+  let popped = STACK.with_borrow_mut(|stack| {
+    // Timers started before profiling was enabled aren't on the stack.
+    let top = stack.last()?;
+    if top.start != timer.start || top.name != timer.name {
+      warn!(
+        "Timer {} ended while {} was running, not adding it to the profile",
+        timer.name, top.name
+      );
+      return None;
+    }
+    let frame = stack.pop().unwrap();
+    if let Some(parent) = stack.last_mut() {
+      parent.child_time += total_time;
+    }
+    let path = stack
+      .iter()
+      .chain([&frame])
+      .map(|frame| frame.name.replace(';', ":"))
+      .collect::<Vec<_>>()
+      .join(";");
+    Some((frame, path))
+  });
+  let Some((frame, path)) = popped else {
+    return;
+  };
+
+  let self_time = total_time.saturating_sub(frame.child_time);
+  let thread = THREAD.with(|thread| *thread);
+  let mut profile = profile();
+
+  let stats = profile.stats.entry(frame.name.clone()).or_default();
+  stats.calls += 1;
+  stats.total_time += total_time;
+  stats.self_time += self_time;
+
+  *profile.folded.entry(path).or_default() += self_time;
+
+  let event = TraceEvent {
+    name: frame.name,
+    thread,
+    start: frame.start.saturating_duration_since(*EPOCH),
+    duration: total_time,
+  };
+  profile.push_event(event, MAX_TRACE_EVENTS.load(Ordering::Relaxed));
+}
+
+/// Returns the aggregated stats of each label, sorted by decreasing total time.
+pub fn profile_stats() -> Vec<(String, TimerStats)> {
+  let mut stats = profile()
+    .stats
+    .iter()
+    .map(|(name, stats)| (name.clone(), *stats))
+    .collect::<Vec<_>>();
+  stats.sort_by(|(n1, s1), (n2, s2)| s2.total_time.cmp(&s1.total_time).then(n1.cmp(n2)));
+  stats
+}
+
+/// Logs the aggregated stats of each label, sorted by decreasing total time.
+pub fn log_profile() {
+  for (name, stats) in profile_stats() {
+    info!(
+      "{name}: {} calls, {:.04}s total, {:.04}s self",
+      stats.calls,
+      stats.total_time.as_secs_f64(),
+      stats.self_time.as_secs_f64()
+    );
+  }
+}
+
+/// Writes every timer call as a complete event in the Chrome trace-event JSON format.
+pub fn write_chrome_trace(mut writer: impl Write) -> io::Result<()> {
+  let profile = profile();
+  let mut json = String::from("{\"traceEvents\":[");
+  for (i, event) in profile.events.iter().enumerate() {
+    if i > 0 {
+      json.push(',');
+    }
+    write!(
+      json,
+      r#"{{"name":"{}","ph":"X","ts":{},"dur":{},"pid":0,"tid":{}}}"#,
+      escape_json(&event.name),
+      event.start.as_micros(),
+      event.duration.as_micros(),
+      event.thread
+    )
+    .unwrap();
+  }
+  json.push_str("]}");
+  writer.write_all(json.as_bytes())
+}
+
+/// Writes the self time of each stack of labels in the folded-stack format of
+/// flamegraph tools, with one `outer;inner <microseconds>` line per stack.
+pub fn write_folded_stacks(mut writer: impl Write) -> io::Result<()> {
+  let profile = profile();
+  let mut stacks = profile.folded.iter().collect::<Vec<_>>();
+  stacks.sort();
+  for (path, self_time) in stacks {
+    writeln!(writer, "{path} {}", self_time.as_micros())?;
+  }
+  Ok(())
+}
+
+fn escape_json(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_profile() {
+    enable_profiling();
+    // Other tests may record timers concurrently, so this test uses its own labels.
+    std::thread::spawn(|| {
+      for _ in 0 .. 2 {
+        crate::block_timer!("test_outer");
+        {
+          crate::block_timer!("test_inner");
+          std::thread::sleep(Duration::from_millis(5));
+        }
+      }
+    })
+    .join()
+    .unwrap();
+
+    let stats = profile_stats().into_iter().collect::<HashMap<_, _>>();
+    let outer = stats["test_outer"];
+    let inner = stats["test_inner"];
+    assert_eq!((outer.calls, inner.calls), (2, 2));
+    assert!(inner.total_time >= Duration::from_millis(10));
+    assert!(outer.total_time >= inner.total_time);
+    assert_eq!(outer.self_time + inner.total_time, outer.total_time);
+    assert_eq!(inner.self_time, inner.total_time);
+
+    let mut folded = Vec::new();
+    write_folded_stacks(&mut folded).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(
+      folded
+        .lines()
+        .any(|line| line.starts_with("test_outer;test_inner ")),
+      "{folded}"
+    );
+
+    let mut trace = Vec::new();
+    write_chrome_trace(&mut trace).unwrap();
+    let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+    let names = trace["traceEvents"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .filter(|event| event["name"] == "test_inner")
+      .count();
+    assert_eq!(names, 2);
+  }
+
+  #[test]
+  fn test_max_trace_events() {
+    let event = |name: &str| TraceEvent {
+      name: name.to_string(),
+      thread: 0,
+      start: Duration::ZERO,
+      duration: Duration::ZERO,
+    };
+    let mut profile = Profile::default();
+    for name in ["a", "b", "c"] {
+      profile.push_event(event(name), 2);
+    }
+    let names = profile
+      .events
+      .iter()
+      .map(|event| event.name.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(names, ["a", "b"]);
+    assert_eq!(profile.dropped_events, 1);
+  }
+}
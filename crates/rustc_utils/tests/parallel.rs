@@ -0,0 +1,34 @@
+//! Tests for running analyses with a multi-threaded rustc.
+//!
+//! Rustc only allows one threading mode per process, so these tests are kept
+//! separate from the single-threaded unit tests.
+
+#![feature(rustc_private)]
+
+extern crate rustc_data_structures;
+
+use rustc_data_structures::sync::par_for_each_in;
+use rustc_utils::{
+  mir::borrowck_facts::get_body_with_borrowck_facts, test_utils::CompileBuilder,
+};
+
+#[test]
+fn parallel_bodies() {
+  let input = r"
+fn a(x: &i32) -> i32 { *x }
+fn b() -> i32 { let f = |y: i32| y + 1; f(0) }
+fn c<'a>(x: &'a i32, y: &'a i32) -> &'a i32 { if *x > 0 { x } else { y } }
+";
+  CompileBuilder::new(input)
+    .with_args(["-Zthreads=4".to_string()])
+    .compile(|result| {
+      let tcx = result.tcx;
+      let def_ids = tcx.hir_body_owners().collect::<Vec<_>>();
+      assert_eq!(def_ids.len(), 4);
+      // Each body is borrow-checked on whichever worker thread requests it
+      par_for_each_in(&def_ids, |def_id| {
+        let body = get_body_with_borrowck_facts(tcx, **def_id);
+        assert!(body.input_facts.is_some());
+      });
+    });
+}